@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE messages
+  DROP COLUMN ip,
+  DROP COLUMN user_agent;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE messages
+  ADD COLUMN ip VARCHAR(64),
+  ADD COLUMN user_agent TEXT;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE messages
+  ADD COLUMN views BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+
+UPDATE messages
+  SET message = message_contents.message
+  FROM message_contents
+  WHERE messages.content_hash = message_contents.hash;
+
+ALTER TABLE messages
+  DROP COLUMN content_hash;
+
+DROP TABLE message_contents;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE TABLE message_contents (
+  hash VARCHAR(64) PRIMARY KEY,
+  message TEXT NOT NULL
+);
+
+ALTER TABLE messages
+  ADD COLUMN content_hash VARCHAR(64) REFERENCES message_contents (hash);
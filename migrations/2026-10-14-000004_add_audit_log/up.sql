@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE audit_log (
+  id SERIAL PRIMARY KEY,
+  timestamp BIGINT NOT NULL DEFAULT EXTRACT('epoch' FROM CURRENT_TIMESTAMP),
+  client VARCHAR(64),
+  operation VARCHAR(64) NOT NULL,
+  affected_id INT4,
+  outcome VARCHAR(16) NOT NULL
+);
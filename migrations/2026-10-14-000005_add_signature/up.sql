@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE messages
+  ADD COLUMN signature VARCHAR(64);
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE messages
+  ADD COLUMN visibility VARCHAR(16) NOT NULL DEFAULT 'public';
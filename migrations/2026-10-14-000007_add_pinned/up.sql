@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE messages
+  ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT FALSE;
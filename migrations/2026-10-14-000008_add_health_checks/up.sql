@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+-- one row, rewritten by every deep health check
+CREATE TABLE health_checks (
+  id INT4 PRIMARY KEY,
+  checked_at BIGINT NOT NULL
+);
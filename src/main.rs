@@ -3,6 +3,7 @@
 
 extern crate hyper;
 extern crate futures;
+extern crate tokio_core;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
@@ -13,22 +14,95 @@ extern crate serde_derive;
 #[macro_use]
 extern crate diesel;
 extern crate dotenv;
+extern crate flate2;
+extern crate ipnet;
+extern crate sha2;
+extern crate hmac;
+extern crate unicode_normalization;
+extern crate base64;
+extern crate ammonia;
+extern crate rand;
+extern crate chrono;
+extern crate chrono_tz;
+extern crate prost;
 
 extern crate maud;
 
 mod schema;
 mod services;
 
-use crate::services::MicroService;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+
+use dotenv::dotenv;
+use futures::{Future, Stream};
+use hyper::server::Http;
+use tokio_core::reactor::Core;
+
+use crate::services::config::Config;
+use crate::services::{initialize, replay_write_buffer, watch_for_leaks, MicroService, ServiceState, SingleFlight};
 
 fn main() {
+    // write .env to sysytem path
+    dotenv().ok();
     env_logger::init();
+
+    let config = Config::from_env().expect("Invalid configuration");
+    let state = Arc::new(ServiceState::new(config));
+    let startup_state = state.clone();
+    thread::spawn(move || initialize(&startup_state));
+    if let Some(detector) = state.leak_detector.clone() {
+        thread::spawn(move || watch_for_leaks(&detector));
+    }
+    if state.write_buffer.is_some() {
+        let replay_state = state.clone();
+        thread::spawn(move || replay_write_buffer(&replay_state));
+    }
     let address = "127.0.0.1:8080".parse().unwrap();
-    let server = hyper::server::Http::new()
-        .bind(&address, || Ok(MicroService {}))
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let service_handle = handle.clone();
+    // every connection is served on this thread, so they can share the list queries and inserts in flight
+    let list_flights = Rc::new(SingleFlight::new());
+    let insert_flights = Rc::new(SingleFlight::new());
+    let serve = Http::new()
+        .serve_addr_handle(&address, &handle, move || {
+            Ok(MicroService::new(
+                state.clone(),
+                service_handle.clone(),
+                list_flights.clone(),
+                insert_flights.clone(),
+            ))
+        })
         .unwrap();
 
     info!("Running microservice at {}", address);
-    server.run().unwrap();
+    let connection_handle = handle.clone();
+    core.run(serve.for_each(move |connection| {
+        connection_handle.spawn(connection.map(|_| ()).map_err(log_connection_error));
+        Ok(())
+    })).unwrap();
 }
 
+/// A client going away mid-response is routine and only logged at debug level.
+fn log_connection_error(error: hyper::Error) {
+    match error {
+        hyper::Error::Io(ref io_error) if is_disconnect(io_error.kind()) => {
+            debug!("Client disconnected: {}", io_error)
+        }
+        error => error!("Connection error: {}", error),
+    }
+}
+
+fn is_disconnect(kind: io::ErrorKind) -> bool {
+    match kind {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
@@ -1,8 +1,47 @@
+table! {
+    audit_log (id) {
+        id -> Int4,
+        timestamp -> Int8,
+        client -> Nullable<Varchar>,
+        operation -> Varchar,
+        affected_id -> Nullable<Int4>,
+        outcome -> Varchar,
+    }
+}
+
+table! {
+    health_checks (id) {
+        id -> Int4,
+        checked_at -> Int8,
+    }
+}
+
+table! {
+    message_contents (hash) {
+        hash -> Varchar,
+        message -> Text,
+    }
+}
+
 table! {
     messages (id) {
         id -> Int4,
         username -> Varchar,
         message -> Text,
         timestamp -> Int8,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Text>,
+        views -> Int8,
+        content_hash -> Nullable<Varchar>,
+        signature -> Nullable<Varchar>,
+        visibility -> Varchar,
+        pinned -> Bool,
     }
 }
+
+allow_tables_to_appear_in_same_query!(
+    audit_log,
+    health_checks,
+    message_contents,
+    messages,
+);
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use hyper::{Method, StatusCode};
+
+/// Logs a finished request. Errors (4xx and 5xx) are always logged, everything
+/// else only for a `sample_rate` fraction of requests.
+pub fn log_access(method: &Method, path: &str, status: StatusCode, elapsed: Duration, sample_rate: f64) {
+    if !should_log(status, sample_rate) {
+        return;
+    }
+    info!("{} {} {} {}ms", method, path, u16::from(status), as_millis(elapsed));
+}
+
+fn should_log(status: StatusCode, sample_rate: f64) -> bool {
+    if status.is_client_error() || status.is_server_error() {
+        return true;
+    }
+    sample_rate >= 1.0 || rand::random::<f64>() < sample_rate
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(status: StatusCode, sample_rate: f64) -> usize {
+        (0..1000).filter(|_| should_log(status, sample_rate)).count()
+    }
+
+    #[test]
+    fn always_logs_errors() {
+        assert_eq!(logged(StatusCode::NotFound, 0.0), 1000);
+        assert_eq!(logged(StatusCode::InternalServerError, 0.0), 1000);
+    }
+
+    #[test]
+    fn samples_successes_by_the_rate() {
+        assert_eq!(logged(StatusCode::Ok, 1.0), 1000);
+        assert_eq!(logged(StatusCode::Ok, 0.0), 0);
+        let sampled = logged(StatusCode::Created, 0.1);
+        assert!(sampled > 30 && sampled < 200, "logged {} of 1000", sampled);
+    }
+}
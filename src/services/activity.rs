@@ -0,0 +1,134 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use url::form_urlencoded;
+
+use super::data_source::CountingConnection;
+use super::micro_service::{parse_arg, VISIBILITY_PUBLIC};
+
+/// Default and largest number of `/stats/activity` buckets.
+const DEFAULT_ACTIVITY_BUCKETS: u32 = 24;
+const MAX_ACTIVITY_BUCKETS: u32 = 366;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ActivityInterval {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl ActivityInterval {
+    fn seconds(&self) -> i64 {
+        match *self {
+            ActivityInterval::Minute => 60,
+            ActivityInterval::Hour => 60 * 60,
+            ActivityInterval::Day => 24 * 60 * 60,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            ActivityInterval::Minute => "minute",
+            ActivityInterval::Hour => "hour",
+            ActivityInterval::Day => "day",
+        }
+    }
+}
+
+/// The buckets `/stats/activity` reports, the newest ending at the current one.
+pub struct ActivityQuery {
+    buckets: i64,
+    interval: ActivityInterval,
+}
+
+impl ActivityQuery {
+    /// Number of the oldest bucket reported, the newest being the one `now` falls in.
+    fn first_bucket(&self, now: i64) -> i64 {
+        now / self.interval.seconds() - (self.buckets - 1)
+    }
+}
+
+pub fn parse_activity_query(query: Option<&str>) -> Result<ActivityQuery, String> {
+    let args = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let buckets = parse_arg::<u32>(&args, "buckets")?
+        .map(|buckets| cmp::max(1, cmp::min(buckets, MAX_ACTIVITY_BUCKETS)))
+        .unwrap_or(DEFAULT_ACTIVITY_BUCKETS);
+    let interval = match args.get("interval").map(String::as_str) {
+        Some("minute") => ActivityInterval::Minute,
+        None | Some("hour") => ActivityInterval::Hour,
+        Some("day") => ActivityInterval::Day,
+        Some(other) => return Err(format!("interval must be one of minute|hour|day, got '{}'", other)),
+    };
+    Ok(ActivityQuery {
+        buckets: i64::from(buckets),
+        interval,
+    })
+}
+
+/// Public message counts of the last `buckets` intervals up to `now`, by bucket number
+/// (`timestamp / interval`). Buckets without messages are absent.
+pub fn query_activity(activity_query: &ActivityQuery, now: i64, db_connection: &CountingConnection) -> Option<HashMap<i64, i64>> {
+    use crate::schema::messages;
+    use diesel::dsl::count_star;
+    let seconds = activity_query.interval.seconds();
+    let first_bucket = activity_query.first_bucket(now);
+    match messages::table
+        .filter(messages::visibility.eq(VISIBILITY_PUBLIC))
+        .filter(messages::timestamp.ge(first_bucket * seconds))
+        .group_by(messages::timestamp / seconds)
+        .select((messages::timestamp / seconds, count_star()))
+        .load::<(i64, i64)>(db_connection) {
+        Ok(result) => Some(result.into_iter().collect()),
+        Err(error) => {
+            error!("Error query Db: {}", error);
+            None
+        }
+    }
+}
+
+/// `{"interval": "hour", "buckets": [{"start": .., "count": ..}, ..]}`, oldest bucket
+/// first and empty buckets counted as zero.
+pub fn render_activity_json(activity_query: &ActivityQuery, now: i64, counts: HashMap<i64, i64>) -> serde_json::Value {
+    let seconds = activity_query.interval.seconds();
+    let first_bucket = activity_query.first_bucket(now);
+    let buckets = (first_bucket..first_bucket + activity_query.buckets)
+        .map(|bucket| json!({
+            "start": bucket * seconds,
+            "count": counts.get(&bucket).cloned().unwrap_or(0),
+        }))
+        .collect::<Vec<_>>();
+    json!({
+        "interval": activity_query.interval.as_str(),
+        "buckets": buckets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_empty_buckets_as_zero() {
+        let activity_query = parse_activity_query(Some("buckets=4&interval=hour")).unwrap();
+        let now = 10 * 3600 + 5;
+        let counts = vec![(7, 2), (9, 1)].into_iter().collect::<HashMap<i64, i64>>();
+        let payload = render_activity_json(&activity_query, now, counts);
+        assert_eq!(payload["interval"], "hour");
+        let buckets = payload["buckets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|bucket| (bucket["start"].as_i64().unwrap() / 3600, bucket["count"].as_i64().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(buckets, vec![(7, 2), (8, 0), (9, 1), (10, 0)]);
+    }
+
+    #[test]
+    fn caps_the_buckets_and_refuses_unknown_intervals() {
+        assert_eq!(parse_activity_query(Some("buckets=100000")).unwrap().buckets, i64::from(MAX_ACTIVITY_BUCKETS));
+        assert!(parse_activity_query(Some("interval=week")).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::str;
+
+use hyper::header::{Headers, Host};
+
+/// Parses `ALLOWED_HOSTS`, comma separated host names; `.example.com` also allows every subdomain.
+pub fn parse_allowed_hosts(value: &str) -> Result<Vec<String>, String> {
+    let hosts = value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>();
+    if hosts.is_empty() {
+        return Err(String::from("ALLOWED_HOSTS must name at least one host"));
+    }
+    Ok(hosts)
+}
+
+/// Whether the `Host` header names an allowed host, whatever its port. Requests without
+/// one are refused, HTTP/1.1 requires it.
+pub fn is_allowed_host(headers: &Headers, allowed_hosts: &[String]) -> bool {
+    let hostname = match headers.get::<Host>() {
+        Some(host) => host.hostname().to_ascii_lowercase(),
+        None => return false,
+    };
+    allowed_hosts.iter().any(|allowed| {
+        if allowed.starts_with('.') {
+            hostname == allowed[1..] || hostname.ends_with(allowed.as_str())
+        } else {
+            hostname == *allowed
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(host: &str, allowed_hosts: &str) -> bool {
+        let mut headers = Headers::new();
+        headers.set_raw("Host", host.to_string());
+        is_allowed_host(&headers, &parse_allowed_hosts(allowed_hosts).unwrap())
+    }
+
+    #[test]
+    fn allows_listed_hosts_on_any_port() {
+        assert!(allows("api.example.com", "api.example.com, other.org"));
+        assert!(allows("API.Example.com:8080", "api.example.com"));
+        assert!(allows("example.com", ".example.com"));
+        assert!(allows("a.b.example.com", ".example.com"));
+    }
+
+    #[test]
+    fn refuses_other_hosts() {
+        assert!(!allows("evil.com", "api.example.com"));
+        assert!(!allows("api.example.com.evil.com", "api.example.com"));
+        assert!(!allows("notexample.com", ".example.com"));
+        assert!(!is_allowed_host(&Headers::new(), &[String::from("api.example.com")]));
+    }
+
+    #[test]
+    fn requires_at_least_one_host() {
+        assert!(parse_allowed_hosts(" , ").is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use diesel::prelude::*;
+
+use super::data_source::CountingConnection;
+use super::data_source::models::{AuditEntry, NewAuditEntry};
+use super::routes::Route;
+
+pub const DEFAULT_AUDIT_ENTRIES: i64 = 50;
+pub const MAX_AUDIT_ENTRIES: i64 = 500;
+
+/// Who did something to what, recorded with `AUDIT_LOG=1`. `client` is stored like the
+/// message's own client address, hashed with `HASH_STORED_IP`.
+pub struct AuditContext {
+    pub client: Option<String>,
+    pub operation: Route,
+}
+
+impl AuditContext {
+    /// Records the write's outcome. Called inside the write's transaction for successes,
+    /// so the entry and the change are committed together; failures are recorded after
+    /// the rollback, on their own.
+    pub fn record(&self, affected_id: Option<i32>, succeeded: bool, db_connection: &CountingConnection) -> QueryResult<()> {
+        use crate::schema::audit_log;
+        diesel::insert_into(audit_log::table)
+            .values(&NewAuditEntry {
+                client: self.client.as_ref().map(String::as_str),
+                operation: self.operation.name(),
+                affected_id,
+                outcome: if succeeded { "ok" } else { "error" },
+            })
+            .execute(db_connection)
+            .map(|_| ())
+    }
+
+    /// Records a failed write, outside of its rolled back transaction. A failure to
+    /// record is only logged, the write's own error is what the client gets.
+    pub fn record_failure(&self, db_connection: &CountingConnection) {
+        if let Err(error) = self.record(None, false, db_connection) {
+            error!("Error recording audit entry: {}", error);
+        }
+    }
+}
+
+/// The newest `limit` entries, newest first.
+pub fn recent_audit_entries(limit: i64, db_connection: &CountingConnection) -> QueryResult<Vec<AuditEntry>> {
+    use crate::schema::audit_log;
+    audit_log::table
+        .order(audit_log::id.desc())
+        .limit(limit)
+        .load::<AuditEntry>(db_connection)
+}
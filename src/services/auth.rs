@@ -0,0 +1,22 @@
+use hyper::header::{Authorization, Bearer, Headers};
+
+/// `true` when the request carries `Authorization: Bearer <ADMIN_TOKEN>`.
+/// Without a configured token nobody is an admin.
+pub fn is_admin(headers: &Headers, admin_token: &Option<String>) -> bool {
+    let admin_token = match *admin_token {
+        Some(ref token) => token,
+        None => return false,
+    };
+    match headers.get::<Authorization<Bearer>>() {
+        Some(&Authorization(Bearer { ref token })) => constant_time_eq(token.as_bytes(), admin_token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compares without an early exit so the response time doesn't reveal the matching prefix.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,152 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stops sending requests to a database that keeps failing: after `failure_threshold`
+/// consecutive failed checkouts the circuit opens and requests fail fast for `open_for`,
+/// then one trial request is let through. Its success closes the circuit again, its
+/// failure opens it for another `open_for`.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Circuit {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A trial request is running since then.
+    HalfOpen { since: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            open_for,
+            circuit: Mutex::new(Circuit::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a request may use the database; `Err` carries the seconds until the next
+    /// trial, for `Retry-After`.
+    pub fn allow(&self) -> Result<(), u64> {
+        let mut circuit = self.circuit.lock().unwrap();
+        let now = Instant::now();
+        match *circuit {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } if now < until => Err(seconds_until(until, now)),
+            Circuit::Open { .. } => {
+                info!("Circuit half-open, trying the database again");
+                *circuit = Circuit::HalfOpen { since: now };
+                Ok(())
+            }
+            // a trial that never reported back, e.g. because it timed out, is replaced
+            Circuit::HalfOpen { since } if now.duration_since(since) >= self.open_for => {
+                *circuit = Circuit::HalfOpen { since: now };
+                Ok(())
+            }
+            Circuit::HalfOpen { since } => Err(seconds_until(since + self.open_for, now)),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        match *circuit {
+            Circuit::HalfOpen { .. } => {
+                info!("Circuit closed, the database is reachable again");
+                *circuit = Circuit::Closed { failures: 0 };
+            }
+            Circuit::Closed { .. } => *circuit = Circuit::Closed { failures: 0 },
+            // a request let through before the circuit opened
+            Circuit::Open { .. } => {}
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        let until = Instant::now() + self.open_for;
+        match *circuit {
+            Circuit::Closed { failures } if failures + 1 >= self.failure_threshold => {
+                warn!(
+                    "Circuit opened after {} consecutive database failures, failing fast for {}s",
+                    failures + 1,
+                    self.open_for.as_secs()
+                );
+                *circuit = Circuit::Open { until };
+            }
+            Circuit::Closed { failures } => *circuit = Circuit::Closed { failures: failures + 1 },
+            Circuit::HalfOpen { .. } => {
+                warn!("Circuit trial failed, failing fast for another {}s", self.open_for.as_secs());
+                *circuit = Circuit::Open { until };
+            }
+            Circuit::Open { .. } => {}
+        }
+    }
+}
+
+/// Rounded up, so clients never retry too early.
+fn seconds_until(deadline: Instant, now: Instant) -> u64 {
+    let remaining = deadline.duration_since(now);
+    remaining.as_secs() + if remaining.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const OPEN_FOR: Duration = Duration::from_millis(50);
+
+    fn opened() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(3, OPEN_FOR);
+        for _ in 0..3 {
+            assert_eq!(breaker.allow(), Ok(()));
+            breaker.record_failure();
+        }
+        breaker
+    }
+
+    #[test]
+    fn opens_after_the_threshold_of_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, OPEN_FOR);
+        breaker.record_failure();
+        breaker.record_failure();
+        // a success in between starts the count over
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.allow(), Ok(()));
+        breaker.record_failure();
+        assert_eq!(breaker.allow(), Err(1));
+    }
+
+    #[test]
+    fn lets_one_trial_through_once_open_for_is_over() {
+        let breaker = opened();
+        thread::sleep(OPEN_FOR);
+        assert_eq!(breaker.allow(), Ok(()));
+        // the trial is still running
+        assert_eq!(breaker.allow(), Err(1));
+    }
+
+    #[test]
+    fn closes_when_the_trial_succeeds() {
+        let breaker = opened();
+        thread::sleep(OPEN_FOR);
+        assert_eq!(breaker.allow(), Ok(()));
+        breaker.record_success();
+        assert_eq!(breaker.allow(), Ok(()));
+        assert_eq!(breaker.allow(), Ok(()));
+    }
+
+    #[test]
+    fn opens_again_when_the_trial_fails() {
+        let breaker = opened();
+        thread::sleep(OPEN_FOR);
+        assert_eq!(breaker.allow(), Ok(()));
+        breaker.record_failure();
+        assert_eq!(breaker.allow(), Err(1));
+    }
+}
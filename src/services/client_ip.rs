@@ -0,0 +1,113 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+
+use hyper::header::Headers;
+use ipnet::IpNet;
+
+/// Parses a comma separated list of CIDR ranges; a bare address is taken as a single host.
+pub fn parse_trusted_proxies(value: &str) -> Result<Vec<IpNet>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid trusted proxy range '{}'", entry))
+        })
+        .collect()
+}
+
+/// Resolves the address of the client behind any trusted proxies.
+///
+/// `X-Forwarded-For` is only consulted when the socket peer is itself trusted. The chain is
+/// then walked from the nearest hop outwards and the first untrusted address is the client,
+/// so entries prepended by the client cannot override what our proxies recorded.
+pub fn client_ip(remote_addr: Option<SocketAddr>, headers: &Headers, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let peer = remote_addr?.ip();
+    if !is_trusted(&peer, trusted_proxies) {
+        return Some(peer);
+    }
+
+    let mut client = peer;
+    for hop in forwarded_for(headers).iter().rev() {
+        match hop.parse::<IpAddr>() {
+            Ok(address) => {
+                client = address;
+                if !is_trusted(&address, trusted_proxies) {
+                    break;
+                }
+            }
+            Err(_) => {
+                debug!("Ignoring malformed X-Forwarded-For entry '{}'", hop);
+                break;
+            }
+        }
+    }
+    Some(client)
+}
+
+pub fn is_trusted(address: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|range| range.contains(address))
+}
+
+fn forwarded_for(headers: &Headers) -> Vec<String> {
+    let raw = match headers.get_raw("X-Forwarded-For") {
+        Some(raw) => raw,
+        None => return Vec::new(),
+    };
+    raw.iter()
+        .filter_map(|line| str::from_utf8(line).ok())
+        .flat_map(|line| line.split(','))
+        .map(|hop| hop.trim().to_string())
+        .filter(|hop| !hop.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded(value: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", value.to_string());
+        headers
+    }
+
+    fn peer(address: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(address.parse().unwrap(), 40000))
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn honors_x_forwarded_for_from_a_trusted_proxy() {
+        let trusted = parse_trusted_proxies("10.0.0.0/8, 192.168.1.1").unwrap();
+        assert_eq!(client_ip(peer("10.1.2.3"), &forwarded("203.0.113.7"), &trusted), ip("203.0.113.7"));
+        assert_eq!(client_ip(peer("192.168.1.1"), &forwarded("203.0.113.7"), &trusted), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn ignores_x_forwarded_for_from_an_untrusted_peer() {
+        let trusted = parse_trusted_proxies("10.0.0.0/8").unwrap();
+        assert_eq!(client_ip(peer("198.51.100.9"), &forwarded("203.0.113.7"), &trusted), ip("198.51.100.9"));
+        assert_eq!(client_ip(peer("10.1.2.3"), &forwarded("203.0.113.7"), &[]), ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        let trusted = parse_trusted_proxies("10.0.0.0/8").unwrap();
+        // the client prepended a spoofed address, our proxies appended what they saw
+        let headers = forwarded("1.2.3.4, 203.0.113.7, 10.0.0.2");
+        assert_eq!(client_ip(peer("10.0.0.1"), &headers, &trusted), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn refuses_invalid_proxy_ranges() {
+        assert!(parse_trusted_proxies("10.0.0.0/33").is_err());
+        assert!(parse_trusted_proxies("proxy.local").is_err());
+    }
+}
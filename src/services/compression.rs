@@ -0,0 +1,122 @@
+use std::io::{self, Write};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use futures::future::{Future, ok as futureOk};
+use futures::Stream;
+use hyper::header::{q, AcceptEncoding, ContentEncoding, ContentLength, ContentType, Encoding, Headers};
+use hyper::mime;
+use hyper::server::Response;
+
+/// Bodies smaller than this are sent as is, gzip would barely shrink them.
+const MIN_COMPRESSED_SIZE: u64 = 256;
+
+pub fn accepts_gzip(headers: &Headers) -> bool {
+    match headers.get::<AcceptEncoding>() {
+        Some(&AcceptEncoding(ref encodings)) => encodings
+            .iter()
+            .any(|encoding| encoding.item == Encoding::Gzip && encoding.quality > q(0)),
+        None => false,
+    }
+}
+
+/// Compresses `data` at `level`, 0 (none) through 9 (smallest).
+pub fn gzip(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Gzips a buffered JSON or HTML response; streamed bodies (no `Content-Length`),
+/// small bodies and already encoded ones pass through unchanged.
+pub fn compress_response(response: Response, level: u32) -> Box<dyn Future<Item=Response, Error=hyper::Error>> {
+    let compressible = response.headers().get::<ContentEncoding>().is_none()
+        && is_compressible_type(response.headers())
+        && match response.headers().get::<ContentLength>() {
+            Some(&ContentLength(length)) => length >= MIN_COMPRESSED_SIZE,
+            None => false,
+        };
+    if !compressible {
+        return Box::new(futureOk(response));
+    }
+
+    let status = response.status();
+    let mut headers = response.headers().clone();
+    Box::new(response.body().concat2().map(move |body| match gzip(&body, level) {
+        Ok(compressed) => {
+            headers.set(ContentEncoding(vec![Encoding::Gzip]));
+            headers.set(ContentLength(compressed.len() as u64));
+            headers.set_raw("Vary", "Accept-Encoding");
+            Response::new()
+                .with_status(status)
+                .with_headers(headers)
+                .with_body(compressed)
+        }
+        Err(error) => {
+            error!("Error compressing response: {}", error);
+            Response::new()
+                .with_status(status)
+                .with_headers(headers)
+                .with_body(body)
+        }
+    }))
+}
+
+/// HTML responses are written without a `Content-Type`, which browsers sniff as HTML.
+fn is_compressible_type(headers: &Headers) -> bool {
+    match headers.get::<ContentType>() {
+        Some(&ContentType(ref mime)) => {
+            mime.subtype() == mime::JSON || (mime.type_() == mime::TEXT && mime.subtype() == mime::HTML)
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    fn json_body() -> Vec<u8> {
+        (0..200)
+            .map(|n| format!(r#"{{"id":{},"username":"peter","message":"message {}"}}"#, n, n % 7))
+            .collect::<Vec<_>>()
+            .join(",")
+            .into_bytes()
+    }
+
+    #[test]
+    fn compresses_smaller_at_higher_levels() {
+        let body = json_body();
+        let sizes = [1, 6, 9].iter().map(|&level| gzip(&body, level).unwrap().len()).collect::<Vec<_>>();
+        assert!(sizes[0] >= sizes[1] && sizes[1] >= sizes[2], "sizes {:?}", sizes);
+        assert!(gzip(&body, 0).unwrap().len() > body.len());
+    }
+
+    #[test]
+    fn gzips_large_responses_only() {
+        let mut core = Core::new().unwrap();
+        let body = json_body();
+        let response = Response::new()
+            .with_header(ContentType::json())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body.clone());
+        let compressed = core.run(compress_response(response, 6)).unwrap();
+        assert_eq!(compressed.headers().get::<ContentEncoding>(), Some(&ContentEncoding(vec![Encoding::Gzip])));
+        let compressed_body = core.run(compressed.body().concat2()).unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed_body[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, body);
+
+        let small = Response::new()
+            .with_header(ContentType::json())
+            .with_header(ContentLength(2))
+            .with_body("{}");
+        let small = core.run(compress_response(small, 6)).unwrap();
+        assert!(small.headers().get::<ContentEncoding>().is_none());
+    }
+}
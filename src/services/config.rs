@@ -0,0 +1,535 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hyper::Uri;
+
+use ipnet::IpNet;
+
+use super::allowed_hosts::parse_allowed_hosts;
+use super::client_ip::parse_trusted_proxies;
+use super::json_case::FieldCase;
+use super::maintenance::DEFAULT_MAINTENANCE_MESSAGE;
+use super::moderation::ModerationConfig;
+use super::rate_limit::{parse_rate, Rate, RATE_LIMITED_METHODS};
+use super::routes::{parse_route_timeouts, Route};
+use super::scheduler::parse_read_write_ratio;
+use super::static_files::DEFAULT_ROBOTS_TXT;
+use super::time_display::{TimeDisplay, DEFAULT_DISPLAY_TIME_FORMAT, DEFAULT_DISPLAY_TZ};
+use super::word_stats::{default_stopwords, parse_stopwords};
+
+const DEFAULT_DATABASE_URL: &'static str = "postgresql://postgres@localhost:5432";
+const DEFAULT_MAX_CONCURRENT_PER_IP: usize = 20;
+const DEFAULT_MAX_CONCURRENT_EXPORTS: usize = 2;
+const DEFAULT_QUEUE_WAIT_MS: u64 = 1000;
+const DEFAULT_DB_POOL_SIZE: u32 = 10;
+const DEFAULT_DB_POOL_TIMEOUT_MS: u64 = 3000;
+const DEFAULT_DB_CONNECT_ATTEMPTS: u32 = 5;
+const DEFAULT_DB_CONNECT_RETRY_MS: u64 = 1000;
+const DEFAULT_DB_CONNECT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_READ_WRITE_RATIO: (usize, usize) = (4, 1);
+const DEFAULT_MODERATION_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_GZIP_LEVEL: u32 = 6;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30000;
+const DEFAULT_CONSTANT_TIME_FLOOR_MS: u64 = 100;
+const DEFAULT_WORD_STATS_WINDOW: i64 = 1000;
+const DEFAULT_MIN_SEARCH_LEN: usize = 3;
+const DEFAULT_CB_OPEN_SECS: u64 = 30;
+const DEFAULT_MAX_OFFSET: i64 = 10000;
+const DEFAULT_HEALTH_WRITE_INTERVAL_SECS: u64 = 30;
+const DEFAULT_WRITE_BUFFER_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_ACCESS_LOG_SAMPLE_RATE: f64 = 1.0;
+
+/// How `write_to_db` learns the timestamp of the row it just inserted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InsertReturning {
+    /// `INSERT ... RETURNING timestamp` only, failing clearly when unsupported.
+    Returning,
+    /// A plain `INSERT` followed by a `SELECT` of the new row in one transaction.
+    Select,
+    /// Try `RETURNING` first and fall back to `Select` if the database rejects it.
+    Auto,
+}
+
+/// What `GET /messages?ids=` returns for ids that don't exist.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingIds {
+    Omit,
+    Null,
+}
+
+/// What happens to control characters in usernames and messages (`CONTROL_CHAR_POLICY`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlCharPolicy {
+    Strip,
+    /// 400 naming the first one.
+    Reject,
+}
+
+/// How plaintext requests are answered when TLS is required (`HTTP_TO_HTTPS`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpToHttps {
+    /// A permanent redirect to the `https://` URL.
+    Redirect,
+    /// 426 Upgrade Required.
+    Reject,
+}
+
+/// What `GET /` serves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RootMode {
+    /// The message list.
+    List,
+    /// A welcome page; the list moves to `/messages`.
+    Landing,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub database_url: String,
+    pub db_pool_size: u32,
+    /// How long a request waits for a pooled connection before giving up.
+    pub db_pool_timeout_ms: u64,
+    /// Tries to reach the database at startup before the process gives up, `1` or more.
+    pub db_connect_attempts: u32,
+    /// Pause after the first failed attempt, doubled after each further one.
+    pub db_connect_retry_ms: u64,
+    /// Seconds one connection attempt may take, `0` for no limit.
+    pub db_connect_timeout_secs: u64,
+    /// Weights `(reads, writes)` of connection checkouts while both are waiting.
+    pub read_write_ratio: (usize, usize),
+    pub insert_returning: InsertReturning,
+    pub trusted_proxies: Vec<IpNet>,
+    /// In-flight requests allowed per client address, `0` disables the limit.
+    pub max_concurrent_per_ip: usize,
+    /// Requests served at once across all clients, `0` disables the limit.
+    pub max_concurrent_requests: usize,
+    /// `/export.sql` and `/export.csv` streamed at once, `0` disables the limit.
+    pub max_concurrent_exports: usize,
+    /// Requests allowed to wait for a slot once `max_concurrent_requests` is reached.
+    pub queue_max: usize,
+    pub queue_wait_ms: u64,
+    /// Always report component details on `/health`, not only for `?verbose=true`.
+    pub health_verbose: bool,
+    /// Shortest time between two writes of `/health?deep=true`, which repeats the last result in between.
+    pub health_write_interval: Duration,
+    /// Match `?q=` with `ILIKE` instead of the case-sensitive `LIKE`.
+    pub search_case_insensitive: bool,
+    /// Bearer token granting access to admin-only data, admin features are off without it.
+    pub admin_token: Option<String>,
+    /// Secret new messages are signed with, see `signing`.
+    pub message_signing_key: Option<String>,
+    /// Store the client IP and user agent with each message.
+    pub store_request_meta: bool,
+    /// Store a SHA-256 of the client IP instead of the address itself.
+    pub hash_stored_ip: bool,
+    pub json_field_case: FieldCase,
+    /// Only the most recent messages of each user are kept when set.
+    pub max_messages_per_user: Option<i64>,
+    pub missing_ids: MissingIds,
+    pub root_mode: RootMode,
+    /// Set when TLS is required, see `https_redirect`.
+    pub http_to_https: Option<HttpToHttps>,
+    /// Served as the landing page instead of the built-in one.
+    pub landing_page: Option<String>,
+    /// Check for missing indexes on filtered columns at startup.
+    pub index_advisory: bool,
+    /// Ask an external service before inserting, when `MODERATION_URL` is set.
+    pub moderation: Option<ModerationConfig>,
+    /// Gzip JSON and HTML responses for clients sending `Accept-Encoding: gzip`.
+    pub gzip_responses: bool,
+    /// 0 (fastest) to 9 (smallest).
+    pub gzip_level: u32,
+    /// Let allowlisted tags in messages through on the HTML page, instead of escaping all markup.
+    pub allow_safe_html: bool,
+    /// How long a request may take, `0` disables the timeout.
+    pub request_timeout_ms: u64,
+    /// Per route overrides of `request_timeout_ms`.
+    pub route_timeouts: HashMap<Route, u64>,
+    /// Echo `X-Request-Id`, `X-B3-TraceId` and `traceparent` on responses and log them.
+    pub trace_headers: bool,
+    /// Warn about connections checked out for longer than this.
+    pub pool_leak_threshold_ms: Option<u64>,
+    /// Serve the pool metrics at `/metrics`.
+    pub metrics: bool,
+    /// Answer `/favicon.ico` with the embedded icon instead of 404.
+    pub serve_favicon: bool,
+    /// The `/robots.txt` content, from `ROBOTS_TXT_FILE` when set.
+    pub robots_txt: String,
+    /// Count views of `/messages/<id>`, at the price of a write per view.
+    pub track_views: bool,
+    /// Fraction of successful requests written to the access log, errors are always logged.
+    pub access_log_sample_rate: f64,
+    /// Answer data endpoints with 503 and a maintenance page.
+    pub maintenance_mode: bool,
+    pub maintenance_message: String,
+    /// `Retry-After` seconds sent with the maintenance response.
+    pub maintenance_retry_after: Option<u64>,
+    /// Let concurrent identical list queries share one database query.
+    pub dedupe_get_queries: bool,
+    /// Stream JSON lists as they are read instead of buffering them.
+    pub stream_json_lists: bool,
+    /// Store each distinct message text once in `message_contents`.
+    pub dedup_content_storage: bool,
+    /// Timezone and format of the timestamps in the HTML list.
+    pub time_display: TimeDisplay,
+    /// Answer 400 to requests without a `User-Agent`, health and version checks aside.
+    pub require_user_agent: bool,
+    /// Queries one request may run on its connection before a warning, any number when unset.
+    pub max_queries_per_request: Option<usize>,
+    /// Fail the queries beyond `max_queries_per_request` instead of warning.
+    pub max_queries_strict: bool,
+    /// Record every write in `audit_log`.
+    pub audit_log: bool,
+    /// Host names the `Host` header must match, any host when unset.
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Requests per client address for methods without a limit of their own.
+    pub rate_limit: Option<Rate>,
+    /// Per method limits from `RATE_LIMIT_<METHOD>`, keyed by the method name.
+    pub method_rate_limits: HashMap<String, Rate>,
+    /// Least time a sensitive lookup takes to answer, when `CONSTANT_TIME_LOOKUPS` is on.
+    pub constant_time_floor: Option<Duration>,
+    /// How many of the newest messages `/stats/words` counts.
+    pub word_stats_window: i64,
+    /// Words `/stats/words` leaves out, lowercased.
+    pub stopwords: HashSet<String>,
+    /// Database every insert is copied to, set with `DUAL_WRITE=1`.
+    pub secondary_database_url: Option<String>,
+    /// Let concurrent inserts with the same client and `Idempotency-Key` share one insert.
+    pub coalesce_idempotent_inserts: bool,
+    /// Store usernames and messages in Unicode NFC.
+    pub normalize_unicode: bool,
+    /// Set to strip or refuse control characters, kept without.
+    pub control_char_policy: Option<ControlCharPolicy>,
+    /// Shortest `q` the list and count accept, in characters.
+    pub min_search_len: usize,
+    /// Consecutive failed checkouts that open the circuit breaker, none without.
+    pub cb_failure_threshold: Option<u32>,
+    /// How long an open circuit fails requests fast before a trial.
+    pub cb_open_secs: u64,
+    /// Parse bodies without `Content-Type` as forms instead of refusing them.
+    pub lenient_content_type: bool,
+    /// Secret the timeline's `next_cursor` is signed with.
+    pub cursor_signing_key: Option<String>,
+    /// Refuse `before`/`after` bounds that leave no timestamp instead of answering empty.
+    pub strict_range: bool,
+    /// Deepest `Range` start the list serves, none without.
+    pub max_offset: Option<i64>,
+    /// Move `Range` starts past `max_offset` back to it instead of refusing them.
+    pub cap_offset: bool,
+    /// File inserts are queued in while the database is unreachable, none without.
+    pub write_buffer_path: Option<String>,
+    /// Largest size of the write buffer file; inserts beyond it fail as without a buffer.
+    pub write_buffer_max_bytes: u64,
+}
+
+impl Config {
+    /// Reads the configuration from the process environment (`.env` included).
+    pub fn from_env() -> Result<Config, String> {
+        let database_url = env::var("DATABASE_URL").unwrap_or(String::from(DEFAULT_DATABASE_URL));
+        let insert_returning = match env::var("INSERT_RETURNING") {
+            Ok(ref value) if value == "returning" => InsertReturning::Returning,
+            Ok(ref value) if value == "select" => InsertReturning::Select,
+            Ok(ref value) if value == "auto" => InsertReturning::Auto,
+            Ok(value) => {
+                return Err(format!(
+                    "INSERT_RETURNING must be one of returning|select|auto, got '{}'",
+                    value
+                ));
+            }
+            Err(_) => InsertReturning::Auto,
+        };
+        let json_field_case = match env::var("JSON_FIELD_CASE") {
+            Ok(ref value) if value == "snake" => FieldCase::Snake,
+            Ok(ref value) if value == "camel" => FieldCase::Camel,
+            Ok(value) => {
+                return Err(format!("JSON_FIELD_CASE must be one of snake|camel, got '{}'", value));
+            }
+            Err(_) => FieldCase::Snake,
+        };
+        let max_messages_per_user = env_parse_optional::<i64>("MAX_MESSAGES_PER_USER")?;
+        if let Some(max_messages) = max_messages_per_user {
+            if max_messages < 1 {
+                return Err(format!("MAX_MESSAGES_PER_USER must be at least 1, got {}", max_messages));
+            }
+        }
+        let missing_ids = match env::var("MISSING_IDS") {
+            Ok(ref value) if value == "omit" => MissingIds::Omit,
+            Ok(ref value) if value == "null" => MissingIds::Null,
+            Ok(value) => return Err(format!("MISSING_IDS must be one of omit|null, got '{}'", value)),
+            Err(_) => MissingIds::Null,
+        };
+        let constant_time_floor = if env_flag("CONSTANT_TIME_LOOKUPS") {
+            let floor_ms = env_parse("CONSTANT_TIME_FLOOR_MS", DEFAULT_CONSTANT_TIME_FLOOR_MS)?;
+            Some(Duration::from_millis(floor_ms))
+        } else {
+            None
+        };
+        let word_stats_window = env_parse("WORD_STATS_WINDOW", DEFAULT_WORD_STATS_WINDOW)?;
+        if word_stats_window < 1 {
+            return Err(format!("WORD_STATS_WINDOW must be at least 1, got {}", word_stats_window));
+        }
+        let stopwords = match env::var("STOPWORDS") {
+            Ok(value) => parse_stopwords(&value),
+            Err(_) => default_stopwords(),
+        };
+        let secondary_database_url = if env_flag("DUAL_WRITE") {
+            match env::var("SECONDARY_DATABASE_URL") {
+                Ok(url) if !url.is_empty() => Some(url),
+                _ => return Err(String::from("DUAL_WRITE=1 requires SECONDARY_DATABASE_URL")),
+            }
+        } else {
+            None
+        };
+        let root_mode = match env::var("ROOT_MODE") {
+            Ok(ref value) if value == "list" => RootMode::List,
+            Ok(ref value) if value == "landing" => RootMode::Landing,
+            Ok(value) => return Err(format!("ROOT_MODE must be one of list|landing, got '{}'", value)),
+            Err(_) => RootMode::List,
+        };
+        let http_to_https = match env::var("HTTP_TO_HTTPS") {
+            Ok(ref value) if value == "redirect" => Some(HttpToHttps::Redirect),
+            Ok(ref value) if value == "reject" => Some(HttpToHttps::Reject),
+            Ok(value) => return Err(format!("HTTP_TO_HTTPS must be one of redirect|reject, got '{}'", value)),
+            Err(_) => None,
+        };
+        let control_char_policy = match env::var("CONTROL_CHAR_POLICY") {
+            Ok(ref value) if value == "strip" => Some(ControlCharPolicy::Strip),
+            Ok(ref value) if value == "reject" => Some(ControlCharPolicy::Reject),
+            Ok(value) => return Err(format!("CONTROL_CHAR_POLICY must be one of strip|reject, got '{}'", value)),
+            Err(_) => None,
+        };
+        let landing_page = match env::var("LANDING_PAGE_FILE") {
+            Ok(path) => Some(fs::read_to_string(&path).map_err(|error| format!("Invalid LANDING_PAGE_FILE '{}': {}", path, error))?),
+            Err(_) => None,
+        };
+        let moderation = match env::var("MODERATION_URL") {
+            Ok(url) => {
+                let url = url
+                    .parse::<Uri>()
+                    .map_err(|error| format!("Invalid MODERATION_URL '{}': {}", url, error))?;
+                if url.scheme() != Some("http") {
+                    return Err(format!("MODERATION_URL must be an http:// URL, got '{}'", url));
+                }
+                let fail_open = match env::var("MODERATION_FAILURE_POLICY") {
+                    Ok(ref value) if value == "open" => true,
+                    Ok(ref value) if value == "closed" => false,
+                    Ok(value) => {
+                        return Err(format!("MODERATION_FAILURE_POLICY must be one of open|closed, got '{}'", value));
+                    }
+                    Err(_) => false,
+                };
+                Some(ModerationConfig {
+                    url,
+                    timeout: Duration::from_millis(env_parse("MODERATION_TIMEOUT_MS", DEFAULT_MODERATION_TIMEOUT_MS)?),
+                    fail_open,
+                })
+            }
+            Err(_) => None,
+        };
+        let read_write_ratio = match env::var("READ_WRITE_RATIO") {
+            Ok(value) => parse_read_write_ratio(&value)?,
+            Err(_) => DEFAULT_READ_WRITE_RATIO,
+        };
+        let route_timeouts = match env::var("ROUTE_TIMEOUTS") {
+            Ok(value) => parse_route_timeouts(&value)?,
+            Err(_) => HashMap::new(),
+        };
+        let robots_txt = match env::var("ROBOTS_TXT_FILE") {
+            Ok(path) => fs::read_to_string(&path).map_err(|error| format!("Invalid ROBOTS_TXT_FILE '{}': {}", path, error))?,
+            Err(_) => String::from(DEFAULT_ROBOTS_TXT),
+        };
+        let access_log_sample_rate = env_parse("ACCESS_LOG_SAMPLE_RATE", DEFAULT_ACCESS_LOG_SAMPLE_RATE)?;
+        if !(access_log_sample_rate >= 0.0 && access_log_sample_rate <= 1.0) {
+            return Err(format!("ACCESS_LOG_SAMPLE_RATE must be between 0 and 1, got {}", access_log_sample_rate));
+        }
+        let rate_limit = match env::var("RATE_LIMIT") {
+            Ok(value) => Some(parse_rate("RATE_LIMIT", &value)?),
+            Err(_) => None,
+        };
+        let mut method_rate_limits = HashMap::new();
+        for method in RATE_LIMITED_METHODS.iter() {
+            let name = format!("RATE_LIMIT_{}", method);
+            if let Ok(value) = env::var(&name) {
+                method_rate_limits.insert(method.to_string(), parse_rate(&name, &value)?);
+            }
+        }
+        let db_connect_attempts = env_parse("DB_CONNECT_ATTEMPTS", DEFAULT_DB_CONNECT_ATTEMPTS)?;
+        if db_connect_attempts == 0 {
+            return Err(String::from("DB_CONNECT_ATTEMPTS must be at least 1"));
+        }
+        let gzip_level = env_parse("GZIP_LEVEL", DEFAULT_GZIP_LEVEL)?;
+        if gzip_level > 9 {
+            return Err(format!("GZIP_LEVEL must be between 0 and 9, got {}", gzip_level));
+        }
+        let time_display = TimeDisplay::new(
+            &env::var("DISPLAY_TZ").unwrap_or(String::from(DEFAULT_DISPLAY_TZ)),
+            &env::var("DISPLAY_TIME_FORMAT").unwrap_or(String::from(DEFAULT_DISPLAY_TIME_FORMAT)),
+        )?;
+        let allowed_hosts = match env::var("ALLOWED_HOSTS") {
+            Ok(value) => Some(parse_allowed_hosts(&value)?),
+            Err(_) => None,
+        };
+        let trusted_proxies = match env::var("TRUSTED_PROXIES") {
+            Ok(value) => parse_trusted_proxies(&value)?,
+            Err(_) => Vec::new(),
+        };
+        let db_pool_size = check_pool_size(env_parse("DB_POOL_SIZE", DEFAULT_DB_POOL_SIZE)?)?;
+        Ok(Config {
+            database_url,
+            db_pool_size,
+            db_pool_timeout_ms: env_parse("DB_POOL_TIMEOUT_MS", DEFAULT_DB_POOL_TIMEOUT_MS)?,
+            db_connect_attempts,
+            db_connect_retry_ms: env_parse("DB_CONNECT_RETRY_MS", DEFAULT_DB_CONNECT_RETRY_MS)?,
+            db_connect_timeout_secs: env_parse("DB_CONNECT_TIMEOUT_SECS", DEFAULT_DB_CONNECT_TIMEOUT_SECS)?,
+            read_write_ratio,
+            insert_returning,
+            trusted_proxies,
+            max_concurrent_per_ip: env_parse("MAX_CONCURRENT_PER_IP", DEFAULT_MAX_CONCURRENT_PER_IP)?,
+            max_concurrent_exports: env_parse("MAX_CONCURRENT_EXPORTS", DEFAULT_MAX_CONCURRENT_EXPORTS)?,
+            max_concurrent_requests: env_parse("MAX_CONCURRENT_REQUESTS", 0)?,
+            queue_max: env_parse("QUEUE_MAX", 0)?,
+            queue_wait_ms: env_parse("QUEUE_WAIT_MS", DEFAULT_QUEUE_WAIT_MS)?,
+            health_verbose: env_flag("HEALTH_VERBOSE"),
+            health_write_interval: Duration::from_secs(env_parse("HEALTH_WRITE_INTERVAL_SECS", DEFAULT_HEALTH_WRITE_INTERVAL_SECS)?),
+            search_case_insensitive: env_flag("SEARCH_CASE_INSENSITIVE"),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            message_signing_key: env::var("MESSAGE_SIGNING_KEY").ok().filter(|key| !key.is_empty()),
+            store_request_meta: env_flag("STORE_REQUEST_META"),
+            hash_stored_ip: env_flag("HASH_STORED_IP"),
+            json_field_case,
+            max_messages_per_user,
+            missing_ids,
+            root_mode,
+            http_to_https,
+            landing_page,
+            index_advisory: env_flag("INDEX_ADVISORY"),
+            moderation,
+            gzip_responses: env_flag("GZIP_RESPONSES"),
+            gzip_level,
+            allow_safe_html: env_flag("ALLOW_SAFE_HTML"),
+            request_timeout_ms: env_parse("REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT_MS)?,
+            route_timeouts,
+            trace_headers: env_flag("TRACE_HEADERS"),
+            pool_leak_threshold_ms: env_parse_optional("POOL_LEAK_THRESHOLD_MS")?.filter(|&threshold_ms| threshold_ms > 0),
+            metrics: env_flag("METRICS"),
+            serve_favicon: env_flag_or("SERVE_FAVICON", true),
+            robots_txt,
+            track_views: env_flag("TRACK_VIEWS"),
+            access_log_sample_rate,
+            maintenance_mode: env_flag("MAINTENANCE_MODE"),
+            maintenance_message: env::var("MAINTENANCE_MESSAGE")
+                .ok()
+                .filter(|message| !message.is_empty())
+                .unwrap_or_else(|| String::from(DEFAULT_MAINTENANCE_MESSAGE)),
+            maintenance_retry_after: env_parse_optional("MAINTENANCE_RETRY_AFTER")?,
+            dedupe_get_queries: env_flag("DEDUPE_GET_QUERIES"),
+            stream_json_lists: env_flag("STREAM_JSON_LISTS"),
+            dedup_content_storage: env_flag("DEDUP_CONTENT_STORAGE"),
+            time_display,
+            require_user_agent: env_flag("REQUIRE_USER_AGENT"),
+            max_queries_per_request: env_parse_optional("MAX_QUERIES_PER_REQUEST")?,
+            max_queries_strict: env_flag("MAX_QUERIES_STRICT"),
+            audit_log: env_flag("AUDIT_LOG"),
+            allowed_hosts,
+            rate_limit,
+            method_rate_limits,
+            constant_time_floor,
+            word_stats_window,
+            stopwords,
+            secondary_database_url,
+            coalesce_idempotent_inserts: env_flag("COALESCE_IDEMPOTENT_INSERTS"),
+            normalize_unicode: env_flag("NORMALIZE_UNICODE"),
+            control_char_policy,
+            min_search_len: env_parse("MIN_SEARCH_LEN", DEFAULT_MIN_SEARCH_LEN)?,
+            cb_failure_threshold: env_parse_optional("CB_FAILURE_THRESHOLD")?.filter(|&threshold| threshold > 0),
+            cb_open_secs: env_parse("CB_OPEN_SECS", DEFAULT_CB_OPEN_SECS)?,
+            lenient_content_type: env_flag("LENIENT_CONTENT_TYPE"),
+            cursor_signing_key: env::var("CURSOR_SIGNING_KEY").ok().filter(|key| !key.is_empty()),
+            strict_range: env_flag("STRICT_RANGE"),
+            max_offset: Some(env_parse("MAX_OFFSET", DEFAULT_MAX_OFFSET)?).filter(|&max_offset| max_offset > 0),
+            cap_offset: env_flag("CAP_OFFSET"),
+            write_buffer_path: env::var("WRITE_BUFFER_PATH").ok().filter(|path| !path.is_empty()),
+            write_buffer_max_bytes: env_parse("WRITE_BUFFER_MAX_BYTES", DEFAULT_WRITE_BUFFER_MAX_BYTES)?,
+        })
+    }
+
+    /// The timeout of `route`, `None` when it may take as long as it needs.
+    pub fn timeout_for(&self, route: Route) -> Option<Duration> {
+        let timeout_ms = self.route_timeouts.get(&route).cloned().unwrap_or(self.request_timeout_ms);
+        if timeout_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(timeout_ms))
+        }
+    }
+}
+
+/// r2d2 panics on an empty pool.
+fn check_pool_size(db_pool_size: u32) -> Result<u32, String> {
+    if db_pool_size < 1 {
+        return Err(format!("DB_POOL_SIZE must be at least 1, got {}", db_pool_size));
+    }
+    Ok(db_pool_size)
+}
+
+fn env_parse_optional<T>(name: &str) -> Result<Option<T>, String>
+    where T: FromStr,
+          T::Err: fmt::Display {
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|error| format!("Invalid {} '{}': {}", name, value, error)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// `1` or `true` enables a flag, anything else leaves it off.
+fn env_flag(name: &str) -> bool {
+    env_flag_or(name, false)
+}
+
+/// Like `env_flag`, for flags that are on unless set otherwise.
+fn env_flag_or(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Ok(value) => value == "1" || value.eq_ignore_ascii_case("true"),
+        Err(_) => default,
+    }
+}
+
+fn env_parse<T>(name: &str, default: T) -> Result<T, String>
+    where T: FromStr,
+          T::Err: fmt::Display {
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<T>()
+            .map_err(|error| format!("Invalid {} '{}': {}", name, value, error)),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_an_empty_pool() {
+        assert_eq!(check_pool_size(0), Err(String::from("DB_POOL_SIZE must be at least 1, got 0")));
+        assert_eq!(check_pool_size(1), Ok(1));
+    }
+
+    #[test]
+    fn overrides_the_default_timeout_per_route() {
+        let mut config = Config::from_env().unwrap();
+        config.request_timeout_ms = 30000;
+        config.route_timeouts.insert(Route::Health, 500);
+        config.route_timeouts.insert(Route::ExportSql, 0);
+        assert_eq!(config.timeout_for(Route::Health), Some(Duration::from_millis(500)));
+        assert_eq!(config.timeout_for(Route::Messages), Some(Duration::from_millis(30000)));
+        assert_eq!(config.timeout_for(Route::ExportSql), None);
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Text};
+use sha2::{Digest, Sha256};
+
+use super::data_source::CountingConnection;
+use super::data_source::models::{Message, NewMessage, NewMessageContent};
+
+const MESSAGE_TEXT: &str = "COALESCE((SELECT message_contents.message FROM message_contents \
+                            WHERE message_contents.hash = messages.content_hash), messages.message)";
+
+/// Emoji and pictograph blocks as a Postgres regex bracket expression: emoticons, symbols
+/// and pictographs, transport, flags' regional indicators, dingbats and misc symbols.
+const EMOJI_CLASS: &str = "[\\U0001F000-\\U0001FAFF\\u2600-\\u27BF\\u2B00-\\u2BFF]";
+
+/// The text of a message wherever it is stored, for filters on `messages` queries.
+pub fn message_text() -> SqlLiteral<Text> {
+    sql::<Text>(MESSAGE_TEXT)
+}
+
+/// Whether the text of a message contains an emoji, or doesn't with `present` false.
+pub fn message_has_emoji(present: bool) -> SqlLiteral<Bool> {
+    let operator = if present { "~" } else { "!~" };
+    sql::<Bool>(&format!("{} {} '{}'", MESSAGE_TEXT, operator, EMOJI_CLASS))
+}
+
+/// Moves the text of `new_message` into `message_contents`, where identical texts share
+/// one row, and leaves the message referencing it by hash.
+pub fn store_content(new_message: &mut NewMessage, db_connection: &CountingConnection) -> QueryResult<()> {
+    use crate::schema::message_contents;
+    let hash = content_hash(&new_message.message);
+    diesel::insert_into(message_contents::table)
+        .values(&NewMessageContent {
+            hash: &hash,
+            message: &new_message.message,
+        })
+        .on_conflict_do_nothing()
+        .execute(db_connection)?;
+    new_message.message.clear();
+    new_message.content_hash = Some(hash);
+    Ok(())
+}
+
+/// Fills in the text of the messages stored by reference, in one query.
+pub fn resolve_contents(mut messages: Vec<Message>, db_connection: &CountingConnection) -> QueryResult<Vec<Message>> {
+    use crate::schema::message_contents;
+    let hashes = messages
+        .iter()
+        .filter_map(|message| message.content_hash.clone())
+        .collect::<Vec<_>>();
+    if hashes.is_empty() {
+        return Ok(messages);
+    }
+    let texts = message_contents::table
+        .filter(message_contents::hash.eq_any(hashes))
+        .load::<(String, String)>(db_connection)?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    for message in &mut messages {
+        if let Some(text) = message.content_hash.as_ref().and_then(|hash| texts.get(hash)) {
+            message.message = text.clone();
+        }
+    }
+    Ok(messages)
+}
+
+pub fn resolve_content(message: Message, db_connection: &CountingConnection) -> QueryResult<Message> {
+    let mut messages = resolve_contents(vec![message], db_connection)?;
+    Ok(messages.remove(0))
+}
+
+fn content_hash(message: &str) -> String {
+    Sha256::digest(message.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_identical_texts_alike() {
+        assert_eq!(content_hash("+1"), content_hash("+1"));
+        assert_ne!(content_hash("+1"), content_hash("+2"));
+        assert_eq!(content_hash(""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+}
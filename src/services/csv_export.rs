@@ -0,0 +1,27 @@
+use super::data_source::models::Message;
+
+/// The first line of `/export.csv`.
+pub const CSV_EXPORT_HEADER: &str = "id,username,message,timestamp,ip,user_agent,views\r\n";
+
+/// One RFC 4180 record per message, ending in CRLF.
+pub fn csv_record(message: &Message) -> String {
+    format!(
+        "{},{},{},{},{},{},{}\r\n",
+        message.id,
+        quote_field(&message.username),
+        quote_field(&message.message),
+        message.timestamp,
+        quote_field(message.ip.as_ref().map(String::as_str).unwrap_or("")),
+        quote_field(message.user_agent.as_ref().map(String::as_str).unwrap_or("")),
+        message.views,
+    )
+}
+
+/// Quotes fields containing a separator, quote or line break, doubling the quotes.
+fn quote_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
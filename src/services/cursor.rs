@@ -0,0 +1,68 @@
+use serde_json::Value;
+
+use super::auth::constant_time_eq;
+use super::signing::hmac_sha256;
+
+/// Length of the HMAC-SHA256 appended to signed cursors.
+const MAC_LEN: usize = 32;
+
+/// Packs a query state into an opaque, URL safe cursor. With `key` an HMAC of the state
+/// is appended, so a client can't change the state without the cursor being refused.
+pub fn encode_cursor(state: &Value, key: Option<&str>) -> String {
+    let mut bytes = state.to_string().into_bytes();
+    if let Some(key) = key {
+        let mac = hmac_sha256(key, &bytes);
+        bytes.extend_from_slice(&mac);
+    }
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// The state packed by `encode_cursor`, checking its HMAC with `key`.
+pub fn decode_cursor(cursor: &str, key: Option<&str>) -> Result<Value, String> {
+    let invalid = || String::from("Invalid 'cursor'");
+    let mut bytes = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+    if let Some(key) = key {
+        if bytes.len() < MAC_LEN {
+            return Err(invalid());
+        }
+        let mac = bytes.split_off(bytes.len() - MAC_LEN);
+        if !constant_time_eq(&mac, &hmac_sha256(key, &bytes)) {
+            return Err(invalid());
+        }
+    }
+    serde_json::from_slice::<Value>(&bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "cursor signing key";
+
+    fn state() -> Value {
+        json!({"before": 1_600_000_000, "id": 42, "username": "peter"})
+    }
+
+    #[test]
+    fn round_trips_the_state() {
+        assert_eq!(decode_cursor(&encode_cursor(&state(), None), None), Ok(state()));
+        assert_eq!(decode_cursor(&encode_cursor(&state(), Some(KEY)), Some(KEY)), Ok(state()));
+    }
+
+    #[test]
+    fn rejects_a_tampered_signed_cursor() {
+        let bytes = base64::decode_config(&encode_cursor(&state(), Some(KEY)), base64::URL_SAFE_NO_PAD).unwrap();
+        let (packed, mac) = bytes.split_at(bytes.len() - MAC_LEN);
+        let mut tampered = String::from_utf8(packed.to_vec()).unwrap().replace("peter", "admin").into_bytes();
+        tampered.extend_from_slice(mac);
+        let tampered = base64::encode_config(&tampered, base64::URL_SAFE_NO_PAD);
+        assert!(decode_cursor(&tampered, Some(KEY)).is_err());
+    }
+
+    #[test]
+    fn rejects_cursors_signed_with_another_key_or_not_at_all() {
+        assert!(decode_cursor(&encode_cursor(&state(), Some("other key")), Some(KEY)).is_err());
+        assert!(decode_cursor(&encode_cursor(&state(), None), Some(KEY)).is_err());
+        assert!(decode_cursor("not a cursor!", None).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::cell::{Cell, RefCell};
+
+use diesel::connection::{AnsiTransactionManager, Connection, SimpleConnection};
+use diesel::deserialize::{Queryable, QueryableByName};
+use diesel::pg::{Pg, PgConnection};
+use diesel::query_builder::{AsQuery, QueryFragment, QueryId};
+use diesel::result::{ConnectionResult, Error, QueryResult};
+use diesel::sql_types::HasSqlType;
+
+/// A `PgConnection` counting the queries of the request holding it, for `MAX_QUERIES_PER_REQUEST`.
+///
+/// Diesel runs every statement through one of the methods below, transactions
+/// included, so each of them counts one query.
+pub struct CountingConnection {
+    inner: PgConnection,
+    queries: Cell<usize>,
+    budget: RefCell<Option<QueryBudget>>,
+}
+
+/// The query limit of one request.
+pub struct QueryBudget {
+    pub max_queries: usize,
+    /// Fail the queries beyond the limit instead of only warning.
+    pub strict: bool,
+    /// What checked the connection out, e.g. the route.
+    pub context: String,
+}
+
+impl CountingConnection {
+    /// Starts counting for a new request; without a budget nothing is checked.
+    pub fn start_request(&self, budget: Option<QueryBudget>) {
+        self.queries.set(0);
+        *self.budget.borrow_mut() = budget;
+    }
+
+    pub fn finish_request(&self) {
+        if let Some(budget) = self.budget.borrow_mut().take() {
+            debug!("{} ran {} queries", budget.context, self.queries.get());
+        }
+    }
+
+    fn count_query(&self) -> QueryResult<()> {
+        let queries = self.queries.get() + 1;
+        self.queries.set(queries);
+        let budget = self.budget.borrow();
+        let budget = match *budget {
+            Some(ref budget) if queries > budget.max_queries => budget,
+            _ => return Ok(()),
+        };
+        if budget.strict {
+            return Err(Error::QueryBuilderError(
+                format!("{} exceeded MAX_QUERIES_PER_REQUEST={}", budget.context, budget.max_queries).into(),
+            ));
+        }
+        // once per request, at the first query over the limit
+        if queries == budget.max_queries + 1 {
+            warn!(
+                "{} ran more than MAX_QUERIES_PER_REQUEST={} queries, possible N+1 query pattern",
+                budget.context, budget.max_queries
+            );
+        }
+        Ok(())
+    }
+}
+
+impl SimpleConnection for CountingConnection {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        self.count_query()?;
+        self.inner.batch_execute(query)
+    }
+}
+
+impl Connection for CountingConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        Ok(CountingConnection {
+            inner: PgConnection::establish(database_url)?,
+            queries: Cell::new(0),
+            budget: RefCell::new(None),
+        })
+    }
+
+    fn execute(&self, query: &str) -> QueryResult<usize> {
+        self.count_query()?;
+        self.inner.execute(query)
+    }
+
+    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+        where T: AsQuery,
+              T::Query: QueryFragment<Pg> + QueryId,
+              Pg: HasSqlType<T::SqlType>,
+              U: Queryable<T::SqlType, Pg> {
+        self.count_query()?;
+        self.inner.query_by_index(source)
+    }
+
+    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+        where T: QueryFragment<Pg> + QueryId,
+              U: QueryableByName<Pg> {
+        self.count_query()?;
+        self.inner.query_by_name(source)
+    }
+
+    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+        where T: QueryFragment<Pg> + QueryId {
+        self.count_query()?;
+        self.inner.execute_returning_count(source)
+    }
+
+    fn transaction_manager(&self) -> &AnsiTransactionManager {
+        self.inner.transaction_manager()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// A connection to `DATABASE_URL`; the tests needing one run with `cargo test -- --ignored`.
+    fn connection() -> CountingConnection {
+        let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| String::from("postgresql://postgres@localhost:5432"));
+        CountingConnection::establish(&database_url).unwrap()
+    }
+
+    fn budget(max_queries: usize, strict: bool) -> Option<QueryBudget> {
+        Some(QueryBudget {
+            max_queries,
+            strict,
+            context: String::from("GET /messages"),
+        })
+    }
+
+    #[test]
+    #[ignore]
+    fn only_warns_past_the_limit_by_default() {
+        let connection = connection();
+        connection.start_request(budget(2, false));
+        for _ in 0..4 {
+            connection.execute("SELECT 1").unwrap();
+        }
+        assert_eq!(connection.queries.get(), 4);
+        connection.finish_request();
+    }
+
+    #[test]
+    #[ignore]
+    fn fails_the_queries_past_the_limit_in_strict_mode() {
+        let connection = connection();
+        connection.start_request(budget(2, true));
+        assert!(connection.execute("SELECT 1").is_ok());
+        assert!(connection.batch_execute("SELECT 1").is_ok());
+        match connection.execute("SELECT 1") {
+            Err(Error::QueryBuilderError(error)) => assert_eq!(error.to_string(), "GET /messages exceeded MAX_QUERIES_PER_REQUEST=2"),
+            other => panic!("expected the third query to fail, got {:?}", other),
+        }
+        // the next request starts over
+        connection.start_request(budget(2, true));
+        assert!(connection.execute("SELECT 1").is_ok());
+    }
+}
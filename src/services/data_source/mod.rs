@@ -1,2 +1,81 @@
+use std::time::Duration;
 
-pub mod models;
\ No newline at end of file
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+
+use super::error::ServiceError;
+
+mod counting;
+pub mod models;
+
+pub use self::counting::{CountingConnection, QueryBudget};
+
+pub type DbPool = Pool<ConnectionManager<CountingConnection>>;
+pub type DbConnection = PooledConnection<ConnectionManager<CountingConnection>>;
+
+/// Creates the pool without connecting, so the service starts even while the database is down.
+/// Connection attempts give up after `connect_timeout_secs`, see `with_connect_timeout`.
+pub fn build_pool(database_url: &str, max_size: u32, checkout_timeout: Duration, connect_timeout_secs: u64) -> DbPool {
+    Pool::builder()
+        .max_size(max_size)
+        .connection_timeout(checkout_timeout)
+        .build_unchecked(ConnectionManager::new(with_connect_timeout(database_url, connect_timeout_secs)))
+}
+
+/// Adds libpq's `connect_timeout` to the connection string unless it sets one itself,
+/// so an unreachable host fails after that many seconds instead of the OS's TCP timeout.
+/// Both URLs and `key=value` strings are understood; `0` waits indefinitely.
+fn with_connect_timeout(database_url: &str, connect_timeout_secs: u64) -> String {
+    if database_url.contains("connect_timeout=") {
+        return String::from(database_url);
+    }
+    let separator = if !database_url.contains("://") {
+        " "
+    } else if database_url.contains('?') {
+        "&"
+    } else {
+        "?"
+    };
+    format!("{}{}connect_timeout={}", database_url, separator, connect_timeout_secs)
+}
+
+/// Checks a connection out of the pool.
+///
+/// r2d2 reports both cases as a timeout, so the pool state tells them apart: every
+/// connection open and busy means the pool is exhausted, anything else means new
+/// connections could not be established.
+pub fn checkout(pool: &DbPool) -> Result<DbConnection, ServiceError> {
+    pool.get().map_err(|error| {
+        let state = pool.state();
+        if state.connections >= pool.max_size() && state.idle_connections == 0 {
+            warn!("Connection pool exhausted: {}", error);
+            ServiceError::PoolExhausted
+        } else {
+            error!("Error connection to database {}", error);
+            ServiceError::DbUnavailable(error.to_string())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn reports_an_unreachable_database_as_unavailable() {
+        // nothing listens on port 1
+        let pool = build_pool("postgresql://postgres@127.0.0.1:1/messages", 1, Duration::from_millis(200), 1);
+        assert_eq!(checkout(&pool).err().and_then(|error| error.code()), Some("db_unavailable"));
+    }
+
+    /// Needs the database of `DATABASE_URL`, run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn reports_a_pool_whose_connections_are_all_busy_as_exhausted() {
+        let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| String::from("postgresql://postgres@localhost:5432"));
+        let pool = build_pool(&database_url, 1, Duration::from_millis(200), 1);
+        let _busy = checkout(&pool).unwrap();
+        assert_eq!(checkout(&pool).err().and_then(|error| error.code()), Some("pool_exhausted"));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::schema::messages;
+use crate::schema::{audit_log, message_contents, messages};
 
 #[derive(Queryable, Serialize, Debug)]
 pub struct Message {
@@ -6,12 +6,94 @@ pub struct Message {
     pub username: String,
     pub message: String,
     pub timestamp: i64,
+    /// Only recorded with `STORE_REQUEST_META=1` and only shown to admins.
+    #[serde(skip_serializing)]
+    pub ip: Option<String>,
+    #[serde(skip_serializing)]
+    pub user_agent: Option<String>,
+    /// Counted with `TRACK_VIEWS=1`, shown by the single message endpoint only.
+    #[serde(skip_serializing)]
+    pub views: i64,
+    /// Set when the text lives in `message_contents`, `message` is empty then
+    /// until it is resolved.
+    #[serde(skip_serializing)]
+    pub content_hash: Option<String>,
+    /// HMAC of the message with `MESSAGE_SIGNING_KEY`, for messages stored while it was set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// `public`, or `private` for messages only admins read.
+    #[serde(skip_serializing)]
+    pub visibility: String,
+    /// Listed before all other messages, set by admins.
+    #[serde(skip_serializing_if = "is_false")]
+    pub pinned: bool,
 }
 
+fn is_false(value: &bool) -> bool {
+    !*value
+}
 
-#[derive(Insertable, Debug)]
+
+/// Also what `WRITE_BUFFER_PATH` queues, as JSON.
+#[derive(Insertable, Serialize, Deserialize, Debug)]
 #[table_name = "messages"]
 pub struct NewMessage {
     pub username: String,
     pub message: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub content_hash: Option<String>,
+    pub visibility: String,
+}
+
+/// A row as committed to the primary, copied to the secondary with `DUAL_WRITE=1`.
+/// The text is stored inline, the secondary's `message_contents` stays empty.
+#[derive(Insertable, Debug)]
+#[table_name = "messages"]
+pub struct ReplicatedMessage {
+    pub id: i32,
+    pub username: String,
+    pub message: String,
+    pub timestamp: i64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub signature: Option<String>,
+    pub visibility: String,
+}
+
+/// A message text stored once for every message referencing it by `hash`.
+#[derive(Insertable, Debug)]
+#[table_name = "message_contents"]
+pub struct NewMessageContent<'a> {
+    pub hash: &'a str,
+    pub message: &'a str,
+}
+
+/// A row of `audit_log`, one per write with `AUDIT_LOG=1`.
+#[derive(Queryable, Serialize, Debug)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub timestamp: i64,
+    pub client: Option<String>,
+    /// The route name, e.g. `insert`.
+    pub operation: String,
+    pub affected_id: Option<i32>,
+    /// `ok` or `error`.
+    pub outcome: String,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "audit_log"]
+pub struct NewAuditEntry<'a> {
+    pub client: Option<&'a str>,
+    pub operation: &'a str,
+    pub affected_id: Option<i32>,
+    pub outcome: &'a str,
+}
+
+/// Client details stored alongside a message for auditing.
+#[derive(Debug, Default)]
+pub struct RequestMeta {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
 }
\ No newline at end of file
@@ -0,0 +1,76 @@
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use diesel::result::QueryResult;
+
+use super::data_source::{build_pool, checkout, CountingConnection, DbPool};
+
+/// A write queued for the secondary, with the name it is logged under.
+type Replication = (&'static str, Box<dyn FnOnce(&CountingConnection) -> QueryResult<()> + Send>);
+
+/// The database written alongside the primary with `DUAL_WRITE=1`, e.g. while migrating
+/// to it. It is never read from, and failing to write to it never fails a request.
+///
+/// The writes run one after another on a thread of their own, so a slow or unreachable
+/// secondary holds up neither the reactor nor the primary's connections.
+pub struct SecondaryStore {
+    // `Sender` is not `Sync`, the store is shared by every request
+    sender: Mutex<Sender<Replication>>,
+}
+
+impl SecondaryStore {
+    pub fn new(database_url: &str, max_size: u32, checkout_timeout: Duration, connect_timeout_secs: u64) -> Self {
+        let pool = build_pool(database_url, max_size, checkout_timeout, connect_timeout_secs);
+        let (sender, receiver) = mpsc::channel::<Replication>();
+        thread::Builder::new()
+            .name(String::from("dual-write"))
+            .spawn(move || {
+                for (operation, write) in receiver {
+                    run(&pool, operation, write);
+                }
+            })
+            .expect("Error starting the dual write thread");
+        SecondaryStore {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Queues `write` for the secondary once it already succeeded on the primary.
+    /// It runs after the writes queued before it; failures are logged.
+    pub fn replicate<F>(&self, operation: &'static str, write: F)
+        where F: FnOnce(&CountingConnection) -> QueryResult<()> + Send + 'static {
+        let queued = self.sender.lock().unwrap().send((operation, Box::new(write)));
+        if queued.is_err() {
+            warn!("Dual write of {} dropped, the secondary database writer stopped", operation);
+        }
+    }
+}
+
+fn run(pool: &DbPool, operation: &str, write: Box<dyn FnOnce(&CountingConnection) -> QueryResult<()> + Send>) {
+    let result = checkout(pool)
+        .map_err(|error| error.to_string())
+        .and_then(|connection| write(&connection).map_err(|error| error.to_string()));
+    if let Err(error) = result {
+        warn!("Dual write of {} to the secondary database failed: {}", operation, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn queues_writes_without_waiting_for_an_unreachable_secondary() {
+        let checkout_timeout = Duration::from_secs(2);
+        let secondary = SecondaryStore::new("postgresql://postgres@localhost:1", 1, checkout_timeout, 1);
+        let started = Instant::now();
+        for _ in 0..3 {
+            secondary.replicate("insert", |_| Ok(()));
+        }
+        assert!(started.elapsed() < checkout_timeout);
+    }
+}
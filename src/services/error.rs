@@ -0,0 +1,128 @@
+use std::fmt;
+
+use hyper::StatusCode;
+
+/// Exports take a while, retrying sooner is unlikely to find a free slot.
+const EXPORTS_BUSY_RETRY_AFTER_SECS: u64 = 10;
+
+/// Errors surfaced by the request handlers, each mapping to an HTTP status.
+#[derive(Debug)]
+pub enum ServiceError {
+    Hyper(hyper::Error),
+    BadRequest(String),
+    /// A body whose size differs from its `Content-Length` header.
+    InvalidContentLength(String),
+    PayloadTooLarge(String),
+    /// A request body without a `Content-Type` header.
+    MissingContentType,
+    UnsupportedMediaType(String),
+    Internal(String),
+    /// Every pooled connection is in use.
+    PoolExhausted,
+    /// No connection to the database could be established.
+    DbUnavailable(String),
+    /// Startup hasn't finished yet.
+    Starting,
+    /// The moderation service refused the message, with its reason.
+    ModerationRejected(String),
+    /// The moderation service could not be asked and moderation fails closed.
+    ModerationUnavailable,
+    /// The route's timeout ran out before a response was ready.
+    Timeout,
+    /// The client used up its rate limit, with the seconds until it may retry.
+    RateLimited(u64),
+    /// The database kept failing and is left alone for a while, with the seconds until it is tried again.
+    CircuitOpen(u64),
+    /// The response body could not be serialized, with the cause.
+    Serialization(String),
+    /// `MAX_CONCURRENT_EXPORTS` exports are running already.
+    ExportsBusy,
+}
+
+impl ServiceError {
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            ServiceError::Hyper(_) => StatusCode::InternalServerError,
+            ServiceError::BadRequest(_)
+            | ServiceError::InvalidContentLength(_)
+            | ServiceError::MissingContentType => StatusCode::BadRequest,
+            ServiceError::PayloadTooLarge(_) => StatusCode::PayloadTooLarge,
+            ServiceError::UnsupportedMediaType(_) => StatusCode::UnsupportedMediaType,
+            ServiceError::Internal(_) | ServiceError::Serialization(_) => StatusCode::InternalServerError,
+            ServiceError::PoolExhausted
+            | ServiceError::DbUnavailable(_)
+            | ServiceError::Starting
+            | ServiceError::ModerationUnavailable
+            | ServiceError::Timeout
+            | ServiceError::CircuitOpen(_)
+            | ServiceError::ExportsBusy => StatusCode::ServiceUnavailable,
+            ServiceError::ModerationRejected(_) => StatusCode::UnprocessableEntity,
+            ServiceError::RateLimited(_) => StatusCode::TooManyRequests,
+        }
+    }
+
+    /// Machine readable error code, for errors clients are expected to tell apart.
+    pub fn code(&self) -> Option<&'static str> {
+        match *self {
+            ServiceError::InvalidContentLength(_) => Some("invalid_content_length"),
+            ServiceError::MissingContentType => Some("missing_content_type"),
+            ServiceError::PoolExhausted => Some("pool_exhausted"),
+            ServiceError::DbUnavailable(_) => Some("db_unavailable"),
+            ServiceError::Starting => Some("starting"),
+            ServiceError::ModerationRejected(_) => Some("moderation_rejected"),
+            ServiceError::ModerationUnavailable => Some("moderation_unavailable"),
+            ServiceError::Timeout => Some("timeout"),
+            ServiceError::RateLimited(_) => Some("rate_limited"),
+            ServiceError::CircuitOpen(_) => Some("circuit_open"),
+            ServiceError::Serialization(_) => Some("serialization_error"),
+            ServiceError::ExportsBusy => Some("exports_busy"),
+            _ => None,
+        }
+    }
+
+    /// Seconds a client should wait before retrying, if retrying is expected to help.
+    pub fn retry_after(&self) -> Option<u64> {
+        match *self {
+            ServiceError::PoolExhausted | ServiceError::Starting => Some(1),
+            ServiceError::ExportsBusy => Some(EXPORTS_BUSY_RETRY_AFTER_SECS),
+            ServiceError::RateLimited(seconds) | ServiceError::CircuitOpen(seconds) => Some(seconds),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServiceError::Hyper(ref error) => write!(f, "{}", error),
+            ServiceError::BadRequest(ref message)
+            | ServiceError::InvalidContentLength(ref message)
+            | ServiceError::PayloadTooLarge(ref message)
+            | ServiceError::UnsupportedMediaType(ref message)
+            | ServiceError::Internal(ref message)
+            | ServiceError::ModerationRejected(ref message) => write!(f, "{}", message),
+            ServiceError::MissingContentType => write!(f, "Content-Type header required for a request body"),
+            ServiceError::PoolExhausted => write!(f, "database connection pool exhausted"),
+            ServiceError::DbUnavailable(_) => write!(f, "database unavailable"),
+            ServiceError::Starting => write!(f, "service is starting"),
+            ServiceError::ModerationUnavailable => write!(f, "moderation service unavailable"),
+            ServiceError::Timeout => write!(f, "request timed out"),
+            ServiceError::RateLimited(_) => write!(f, "rate limit exceeded"),
+            ServiceError::CircuitOpen(_) => write!(f, "database unavailable, not retrying yet"),
+            ServiceError::Serialization(_) => write!(f, "response could not be serialized"),
+            ServiceError::ExportsBusy => write!(f, "too many exports running, retry later"),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ServiceError {
+    fn from(error: serde_json::Error) -> Self {
+        ServiceError::Serialization(error.to_string())
+    }
+}
+
+impl From<hyper::Error> for ServiceError {
+    fn from(error: hyper::Error) -> Self {
+        ServiceError::Hyper(error)
+    }
+}
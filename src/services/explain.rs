@@ -0,0 +1,34 @@
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+use diesel::sql_types::Text;
+
+use super::data_source::CountingConnection;
+
+/// `EXPLAIN ANALYZE` of any query, each row one line of the plan.
+struct ExplainAnalyze<Q>(Q);
+
+impl<Q> QueryId for ExplainAnalyze<Q> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q> Query for ExplainAnalyze<Q> {
+    type SqlType = Text;
+}
+
+impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for ExplainAnalyze<Q> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql("EXPLAIN ANALYZE ");
+        self.0.walk_ast(out.reborrow())
+    }
+}
+
+impl<Q> RunQueryDsl<CountingConnection> for ExplainAnalyze<Q> {}
+
+/// Runs `query` under `EXPLAIN ANALYZE` and returns the plan with its timings. The query
+/// really executes, writes included, so this is for reads only.
+pub fn explain_analyze<Q: QueryFragment<Pg>>(query: Q, db_connection: &CountingConnection) -> QueryResult<String> {
+    let lines = ExplainAnalyze(query).load::<String>(db_connection)?;
+    Ok(lines.join("\n"))
+}
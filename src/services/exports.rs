@@ -0,0 +1,189 @@
+use diesel::prelude::*;
+use hyper::header::ContentType;
+use hyper::server::Response;
+use tokio_core::reactor::Handle;
+
+use super::content_dedup::resolve_contents;
+use super::csv_export::{csv_record, CSV_EXPORT_HEADER};
+use super::data_source::models::Message;
+use super::limits::ExportPermit;
+use super::scheduler::ScheduledConnection;
+use super::sql_export::{insert_statement, SQL_EXPORT_FOOTER, SQL_EXPORT_HEADER};
+use super::streaming::stream_body;
+
+/// Rows per chunk of `/export.sql`.
+pub const EXPORT_BATCH_SIZE: i64 = 500;
+
+/// Rows per chunk of `/export.csv`.
+const CSV_EXPORT_BATCH_SIZE: i64 = 1000;
+
+enum ExportStage {
+    Header,
+    /// Rows with ids above `after_id` are still to be sent.
+    Rows { after_id: i32 },
+    Footer,
+    Done,
+}
+
+/// Streams every message as an `INSERT` statement, `EXPORT_BATCH_SIZE` rows per
+/// chunk in id order, holding `connection` until the dump is done.
+pub fn make_sql_export_response(connection: ScheduledConnection, permit: ExportPermit, handle: &Handle) -> Response {
+    use crate::schema::messages;
+    let mut stage = ExportStage::Header;
+    let body = stream_body(handle, move || {
+        // the slot is freed with the body, once the export is sent or the client is gone
+        let _permit = &permit;
+        let (chunk, next) = match stage {
+            ExportStage::Header => (String::from(SQL_EXPORT_HEADER), ExportStage::Rows { after_id: 0 }),
+            ExportStage::Rows { after_id } => {
+                let batch = messages::table
+                    .filter(messages::id.gt(after_id))
+                    .order(messages::id.asc())
+                    .limit(EXPORT_BATCH_SIZE)
+                    .load::<Message>(&*connection)
+                    .and_then(|batch| resolve_contents(batch, &*connection))
+                    .map_err(|error| error.to_string())?;
+                let next = match batch.last() {
+                    Some(last) if batch.len() as i64 == EXPORT_BATCH_SIZE => ExportStage::Rows { after_id: last.id },
+                    _ => ExportStage::Footer,
+                };
+                (batch.iter().map(insert_statement).collect::<String>(), next)
+            }
+            ExportStage::Footer => (String::from(SQL_EXPORT_FOOTER), ExportStage::Done),
+            ExportStage::Done => return Ok(None),
+        };
+        stage = next;
+        Ok(Some(chunk.into_bytes()))
+    }, None);
+    let mut response = Response::new()
+        .with_header(ContentType("application/sql; charset=utf-8".parse().unwrap()))
+        .with_body(body);
+    response.headers_mut().set_raw("Content-Disposition", "attachment; filename=\"messages.sql\"");
+    response
+}
+
+/// Streams every message as a CSV record, `CSV_EXPORT_BATCH_SIZE` rows per chunk in id
+/// order. Each batch is only read once the client took the previous chunk, so memory
+/// stays at one batch whatever the table size.
+pub fn make_csv_export_response(connection: ScheduledConnection, permit: ExportPermit, handle: &Handle) -> Response {
+    use crate::schema::messages;
+    let mut stage = ExportStage::Header;
+    let mut batches = 0;
+    let mut rows = 0;
+    let body = stream_body(handle, move || {
+        let _permit = &permit;
+        let load_batch = |after_id: i32, limit: i64| -> Result<Vec<Message>, String> {
+            let batch = messages::table
+                .filter(messages::id.gt(after_id))
+                .order(messages::id.asc())
+                .limit(limit)
+                .load::<Message>(&*connection)
+                .and_then(|batch| resolve_contents(batch, &*connection))
+                .map_err(|error| error.to_string())?;
+            batches += 1;
+            rows += batch.len();
+            Ok(batch)
+        };
+        let (chunk, next) = match csv_chunk(&stage, load_batch)? {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+        if let ExportStage::Done = next {
+            debug!("Exported {} rows as CSV in {} batches", rows, batches);
+        }
+        stage = next;
+        Ok(Some(chunk.into_bytes()))
+    }, None);
+    let mut response = Response::new()
+        .with_header(ContentType("text/csv; charset=utf-8".parse().unwrap()))
+        .with_body(body);
+    response.headers_mut().set_raw("Content-Disposition", "attachment; filename=\"messages.csv\"");
+    response
+}
+
+/// The CSV chunk of `stage` and the stage after it, `None` once the export is done.
+/// Rows come from `load_batch`, called with the id to continue after and the batch size.
+fn csv_chunk<F>(stage: &ExportStage, load_batch: F) -> Result<Option<(String, ExportStage)>, String>
+    where F: FnOnce(i32, i64) -> Result<Vec<Message>, String> {
+    let chunk = match *stage {
+        ExportStage::Header => (String::from(CSV_EXPORT_HEADER), ExportStage::Rows { after_id: 0 }),
+        ExportStage::Rows { after_id } => {
+            let batch = load_batch(after_id, CSV_EXPORT_BATCH_SIZE)?;
+            let next = match batch.last() {
+                Some(last) if batch.len() as i64 == CSV_EXPORT_BATCH_SIZE => ExportStage::Rows { after_id: last.id },
+                _ => ExportStage::Done,
+            };
+            (batch.iter().map(csv_record).collect::<String>(), next)
+        }
+        ExportStage::Footer | ExportStage::Done => return Ok(None),
+    };
+    Ok(Some(chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i32) -> Message {
+        Message {
+            id,
+            username: String::from("peter"),
+            message: format!("message {}, exported", id),
+            timestamp: 1_000 + i64::from(id),
+            ip: None,
+            user_agent: None,
+            views: 0,
+            content_hash: None,
+            signature: None,
+            visibility: String::from("public"),
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn exports_a_large_table_one_bounded_batch_at_a_time() {
+        let table = (1..=2500).map(message).collect::<Vec<_>>();
+        let mut batch_sizes = Vec::new();
+        let mut csv = String::new();
+        let mut stage = ExportStage::Header;
+        loop {
+            let load_batch = |after_id: i32, limit: i64| -> Result<Vec<Message>, String> {
+                let batch = table
+                    .iter()
+                    .filter(|row| row.id > after_id)
+                    .take(limit as usize)
+                    .map(|row| message(row.id))
+                    .collect::<Vec<_>>();
+                batch_sizes.push(batch.len());
+                Ok(batch)
+            };
+            match csv_chunk(&stage, load_batch).unwrap() {
+                Some((chunk, next)) => {
+                    csv.push_str(&chunk);
+                    stage = next;
+                }
+                None => break,
+            }
+        }
+        assert_eq!(batch_sizes, vec![1000, 1000, 500]);
+        let lines = csv.split_terminator("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2501);
+        assert_eq!(format!("{}\r\n", lines[0]), CSV_EXPORT_HEADER);
+        assert_eq!(lines[1], "1,peter,\"message 1, exported\",1001,,,0");
+        assert_eq!(lines[2500], "2500,peter,\"message 2500, exported\",3500,,,0");
+    }
+
+    #[test]
+    fn reads_one_more_batch_after_an_exactly_full_one() {
+        let table = (1..=1000).map(message).collect::<Vec<_>>();
+        let mut loads = 0;
+        let mut stage = ExportStage::Rows { after_id: 0 };
+        while let Some((_, next)) = csv_chunk(&stage, |after_id, _| {
+            loads += 1;
+            Ok(table.iter().filter(|row| row.id > after_id).map(|row| message(row.id)).collect())
+        }).unwrap() {
+            stage = next;
+        }
+        assert_eq!(loads, 2);
+    }
+}
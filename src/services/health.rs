@@ -0,0 +1,280 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use diesel::prelude::*;
+use futures::future::{Future, ok as futureOk};
+use hyper::StatusCode;
+use serde_json::Value;
+
+use super::data_source::{CountingConnection, DbPool};
+use super::error::ServiceError;
+use super::scheduler::ScheduledConnection;
+use super::state::ServiceState;
+
+/// A database answering slower than this marks the service as degraded.
+const DEGRADED_DB_LATENCY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HealthStatus {
+    Ok,
+    Degraded,
+    Down,
+}
+
+impl HealthStatus {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            HealthStatus::Ok => "ok",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Down => "down",
+        }
+    }
+}
+
+struct DatabaseCheck {
+    reachable: bool,
+    /// No connection was free for the check, the database itself may be fine.
+    pool_exhausted: bool,
+    latency: Duration,
+}
+
+/// The outcome of writing to `health_checks`.
+#[derive(Clone, Copy, Debug)]
+pub struct WriteCheck {
+    writable: bool,
+    latency: Duration,
+    checked_at: Instant,
+}
+
+/// Builds the `/health` payload; `down` answers 503 so load balancers take the instance out.
+/// With `deep` a database that can be read but not written, e.g. one that went read-only
+/// or ran out of disk, is down too. The write uses a connection from `connect`, which
+/// queues for it like any other write.
+pub fn health_report<F>(
+    state: &Arc<ServiceState>,
+    verbose: bool,
+    deep: bool,
+    connect: F,
+) -> Box<dyn Future<Item=(StatusCode, Value), Error=ServiceError>>
+    where F: FnOnce() -> Box<dyn Future<Item=ScheduledConnection, Error=ServiceError>> {
+    let database = check_database(&state.pool);
+    let write: Box<dyn Future<Item=Option<WriteCheck>, Error=ServiceError>> = if !deep || !database.reachable {
+        Box::new(futureOk(None))
+    } else if let Some(last) = recent_write_check(state) {
+        Box::new(futureOk(Some(last)))
+    } else {
+        let started = Instant::now();
+        let state = state.clone();
+        Box::new(connect().then(move |connection| {
+            let written = connection
+                .map_err(|error| error.to_string())
+                .and_then(|connection| write_health_check(&connection));
+            Ok(Some(record_write_check(&state.last_write_check, written, started)))
+        }))
+    };
+    let state = state.clone();
+    Box::new(write.map(move |write| report(&state, verbose, &database, write)))
+}
+
+fn report(state: &ServiceState, verbose: bool, database: &DatabaseCheck, write: Option<WriteCheck>) -> (StatusCode, Value) {
+    let status = if !database.reachable || write.map_or(false, |write| !write.writable) {
+        HealthStatus::Down
+    } else if !state.is_ready() || database.pool_exhausted || database.latency > DEGRADED_DB_LATENCY {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Ok
+    };
+    let http_status = match status {
+        HealthStatus::Down => StatusCode::ServiceUnavailable,
+        _ => StatusCode::Ok,
+    };
+
+    if !verbose {
+        return (http_status, json!({"status": status.as_str()}));
+    }
+    let mut payload = json!({
+        "status": status.as_str(),
+        "components": {
+            "database": {
+                "status": if database.reachable { "up" } else { "down" },
+                "latency_ms": as_millis(database.latency),
+            },
+            "pool": pool_utilization(&state.pool),
+        },
+        "ready": state.is_ready(),
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    if let Some(write) = write {
+        payload["components"]["database_write"] = json!({
+            "status": if write.writable { "up" } else { "down" },
+            "latency_ms": as_millis(write.latency),
+            "age_secs": write.checked_at.elapsed().as_secs(),
+        });
+    }
+    (http_status, payload)
+}
+
+/// The last write check if it is younger than `HEALTH_WRITE_INTERVAL_SECS`, repeated
+/// instead of writing again so frequent probes don't add write load.
+fn recent_write_check(state: &ServiceState) -> Option<WriteCheck> {
+    let last_write_check = *state.last_write_check.lock().unwrap();
+    last_write_check.filter(|last| last.checked_at.elapsed() < state.config.health_write_interval)
+}
+
+/// Upserts the single `health_checks` row, committed so the commit's flush to disk is
+/// checked too.
+fn write_health_check(db_connection: &CountingConnection) -> Result<(), String> {
+    use crate::schema::health_checks;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+    diesel::insert_into(health_checks::table)
+        .values((health_checks::id.eq(1), health_checks::checked_at.eq(now)))
+        .on_conflict(health_checks::id)
+        .do_update()
+        .set(health_checks::checked_at.eq(now))
+        .execute(db_connection)
+        .map(|_| ())
+        .map_err(|error| error.to_string())
+}
+
+/// Caches the outcome of a write started at `started` for the probes that follow.
+fn record_write_check(last_write_check: &Mutex<Option<WriteCheck>>, written: Result<(), String>, started: Instant) -> WriteCheck {
+    if let Err(ref error) = written {
+        warn!("Health check write failed: {}", error);
+    }
+    let write_check = WriteCheck {
+        writable: written.is_ok(),
+        latency: started.elapsed(),
+        checked_at: Instant::now(),
+    };
+    *last_write_check.lock().unwrap() = Some(write_check);
+    write_check
+}
+
+/// Runs `SELECT 1` on an idle connection, never waiting for one. The pool refills itself
+/// in the background, so without an idle connection it is either busy with all of them
+/// or cannot reach the database.
+fn check_database(pool: &DbPool) -> DatabaseCheck {
+    let started = Instant::now();
+    let (reachable, pool_exhausted) = match pool.try_get() {
+        Some(connection) => match diesel::sql_query("SELECT 1").execute(&connection) {
+            Ok(_) => (true, false),
+            Err(error) => {
+                warn!("Health check query failed: {}", error);
+                (false, false)
+            }
+        },
+        None if pool.state().connections >= pool.max_size() => (true, true),
+        None => (false, false),
+    };
+    DatabaseCheck {
+        reachable,
+        pool_exhausted,
+        latency: started.elapsed(),
+    }
+}
+
+fn pool_utilization(pool: &DbPool) -> Value {
+    let pool_state = pool.state();
+    let in_use = pool_state.connections - pool_state.idle_connections;
+    json!({
+        "max_size": pool.max_size(),
+        "connections": pool_state.connections,
+        "idle": pool_state.idle_connections,
+        "in_use": in_use,
+        "utilization": f64::from(in_use) / f64::from(pool.max_size()),
+    })
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use super::super::config::Config;
+    use super::super::data_source::{build_pool, checkout};
+
+    fn state(ready: bool) -> ServiceState {
+        let mut config = Config::from_env().unwrap();
+        config.database_url = String::from("postgresql://postgres@localhost:1");
+        let state = ServiceState::new(config);
+        state.ready.store(ready, Ordering::SeqCst);
+        state
+    }
+
+    fn database(latency_ms: u64) -> DatabaseCheck {
+        DatabaseCheck {
+            reachable: true,
+            pool_exhausted: false,
+            latency: Duration::from_millis(latency_ms),
+        }
+    }
+
+    #[test]
+    fn reports_ok_degraded_and_down() {
+        let ready = state(true);
+        let status = |state: &ServiceState, database: DatabaseCheck, write: Option<WriteCheck>| {
+            let (http_status, payload) = report(state, false, &database, write);
+            (http_status, payload["status"].as_str().unwrap().to_string())
+        };
+        assert_eq!(status(&ready, database(1), None), (StatusCode::Ok, String::from("ok")));
+        assert_eq!(status(&state(false), database(1), None), (StatusCode::Ok, String::from("degraded")));
+        assert_eq!(status(&ready, database(600), None), (StatusCode::Ok, String::from("degraded")));
+        let failed_write = WriteCheck {
+            writable: false,
+            latency: Duration::from_millis(1),
+            checked_at: Instant::now(),
+        };
+        assert_eq!(
+            status(&ready, database(1), Some(failed_write)),
+            (StatusCode::ServiceUnavailable, String::from("down"))
+        );
+    }
+
+    #[test]
+    fn details_the_components_when_verbose() {
+        let state = state(true);
+        let unreachable = check_database(&state.pool);
+        let (http_status, payload) = report(&state, true, &unreachable, None);
+        assert_eq!(http_status, StatusCode::ServiceUnavailable);
+        assert_eq!(payload["components"]["database"]["status"], "down");
+        assert_eq!(payload["components"]["pool"]["max_size"], json!(state.pool.max_size()));
+        assert_eq!(payload["ready"], true);
+        assert!(payload["components"].get("database_write").is_none());
+    }
+
+    #[test]
+    fn reports_an_unreachable_database_without_waiting_for_the_pool() {
+        let checkout_timeout = Duration::from_secs(2);
+        let database = check_database(&build_pool("postgresql://postgres@localhost:1", 1, checkout_timeout, 1));
+        assert!(!database.reachable);
+        assert!(database.latency < checkout_timeout);
+    }
+
+    #[test]
+    fn caches_a_failed_write_as_not_writable() {
+        let last_write_check = Mutex::new(None);
+        let write_check = record_write_check(&last_write_check, Err(String::from("disk full")), Instant::now());
+        assert!(!write_check.writable);
+        assert!(!last_write_check.lock().unwrap().unwrap().writable);
+    }
+
+    /// Needs `DATABASE_URL`, migrated with `diesel migration run`.
+    #[test]
+    #[ignore]
+    fn detects_a_read_only_database() {
+        let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| String::from("postgresql://postgres@localhost:5432"));
+        let db_connection = checkout(&build_pool(&database_url, 1, Duration::from_secs(5), 5)).unwrap();
+        diesel::sql_query("SET default_transaction_read_only = on").execute(&*db_connection).unwrap();
+        let last_write_check = Mutex::new(None);
+        let written = write_health_check(&db_connection);
+        assert!(written.is_err());
+        assert!(!record_write_check(&last_write_check, written, Instant::now()).writable);
+    }
+}
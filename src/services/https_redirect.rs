@@ -0,0 +1,120 @@
+use std::net::SocketAddr;
+use std::str;
+
+use futures::future::{ok as futureOk, FutureResult};
+use hyper::{Method, StatusCode};
+use hyper::header::{ContentLength, ContentType, Headers, Host, Location};
+use hyper::server::{Request, Response};
+use ipnet::IpNet;
+
+use super::client_ip::is_trusted;
+use super::config::HttpToHttps;
+
+/// Whether the request reached us without TLS. The service only speaks plain HTTP, TLS ends
+/// at a proxy in front of it, so only a trusted proxy's `X-Forwarded-Proto: https` (the last
+/// one, which it appended) tells a request apart from one sent to the service directly.
+pub fn is_plaintext(remote_addr: Option<SocketAddr>, headers: &Headers, trusted_proxies: &[IpNet]) -> bool {
+    match remote_addr {
+        Some(peer) if is_trusted(&peer.ip(), trusted_proxies) => forwarded_proto(headers).map_or(true, |proto| proto != "https"),
+        _ => true,
+    }
+}
+
+/// The answer to a plaintext request under `HTTP_TO_HTTPS`: a permanent redirect to the
+/// `https://` URL, or 426 Upgrade Required. Without a `Host` there is no URL to redirect
+/// to, so such requests get the 426 too.
+pub fn https_response(mode: HttpToHttps, request: &Request) -> FutureResult<Response, hyper::Error> {
+    let host = request.headers().get::<Host>().map(|host| host.hostname().to_string());
+    match (mode, host) {
+        (HttpToHttps::Redirect, Some(host)) => {
+            let location = match request.query() {
+                Some(query) => format!("https://{}{}?{}", host, request.path(), query),
+                None => format!("https://{}{}", host, request.path()),
+            };
+            // 301 lets clients turn a POST into a GET, 308 keeps the method and body
+            let status = match *request.method() {
+                Method::Get | Method::Head => StatusCode::MovedPermanently,
+                _ => StatusCode::PermanentRedirect,
+            };
+            futureOk(Response::new().with_status(status).with_header(Location::new(location)).with_header(ContentLength(0)))
+        }
+        _ => {
+            let body = json!({"error": "TLS required, use https"}).to_string();
+            let mut response = Response::new()
+                .with_status(StatusCode::UpgradeRequired)
+                .with_header(ContentType::json())
+                .with_header(ContentLength(body.len() as u64))
+                .with_body(body);
+            response.headers_mut().set_raw("Upgrade", "TLS/1.2, HTTP/1.1");
+            response.headers_mut().set_raw("Connection", "Upgrade");
+            futureOk(response)
+        }
+    }
+}
+
+fn forwarded_proto(headers: &Headers) -> Option<String> {
+    let raw = headers.get_raw("X-Forwarded-Proto")?;
+    raw.iter()
+        .filter_map(|line| str::from_utf8(line).ok())
+        .flat_map(|line| line.split(','))
+        .map(|proto| proto.trim().to_ascii_lowercase())
+        .filter(|proto| !proto.is_empty())
+        .last()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use super::*;
+
+    fn request(method: Method, uri: &str, host: Option<&str>) -> Request {
+        let mut request = Request::new(method, uri.parse().unwrap());
+        if let Some(host) = host {
+            request.headers_mut().set(Host::new(host.to_string(), None));
+        }
+        request
+    }
+
+    #[test]
+    fn trusts_the_forwarded_proto_of_trusted_proxies_only() {
+        let trusted_proxies = vec!["10.0.0.0/8".parse::<IpNet>().unwrap()];
+        let proxy = Some("10.0.0.1:4000".parse().unwrap());
+        let client = Some("203.0.113.9:4000".parse().unwrap());
+        let mut headers = Headers::new();
+        assert!(is_plaintext(proxy, &headers, &trusted_proxies));
+        headers.set_raw("X-Forwarded-Proto", "http, HTTPS");
+        assert!(!is_plaintext(proxy, &headers, &trusted_proxies));
+        assert!(is_plaintext(client, &headers, &trusted_proxies));
+        assert!(is_plaintext(None, &headers, &trusted_proxies));
+        headers.set_raw("X-Forwarded-Proto", "https, http");
+        assert!(is_plaintext(proxy, &headers, &trusted_proxies));
+    }
+
+    #[test]
+    fn redirects_to_the_https_url_keeping_the_method_of_writes() {
+        let get = https_response(HttpToHttps::Redirect, &request(Method::Get, "/messages?limit=5", Some("example.com")))
+            .wait()
+            .unwrap();
+        assert_eq!(get.status(), StatusCode::MovedPermanently);
+        assert_eq!(
+            get.headers().get::<Location>(),
+            Some(&Location::new("https://example.com/messages?limit=5"))
+        );
+        let post = https_response(HttpToHttps::Redirect, &request(Method::Post, "/", Some("example.com")))
+            .wait()
+            .unwrap();
+        assert_eq!(post.status(), StatusCode::PermanentRedirect);
+        assert_eq!(post.headers().get::<Location>(), Some(&Location::new("https://example.com/")));
+    }
+
+    #[test]
+    fn asks_for_an_upgrade_when_rejecting_or_without_a_host() {
+        for &(mode, host) in &[(HttpToHttps::Reject, Some("example.com")), (HttpToHttps::Redirect, None)] {
+            let response = https_response(mode, &request(Method::Get, "/", host)).wait().unwrap();
+            assert_eq!(response.status(), StatusCode::UpgradeRequired);
+            assert_eq!(response.headers().get_raw("Upgrade").and_then(|raw| raw.one()), Some(&b"TLS/1.2, HTTP/1.1"[..]));
+            assert!(response.headers().get::<Location>().is_none());
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+use super::data_source::{checkout, DbPool};
+
+/// Columns the list query filters or sorts on.
+const FILTERED_COLUMNS: &[&str] = &["timestamp", "username"];
+
+#[derive(QueryableByName)]
+struct IndexDefinition {
+    #[sql_type = "Text"]
+    indexdef: String,
+}
+
+/// Logs a recommendation for every filtered column of `messages` that has no
+/// index leading with it, since range scans on such columns read the whole table.
+pub fn advise_indexes(pool: &DbPool) {
+    let connection = match checkout(pool) {
+        Ok(connection) => connection,
+        Err(error) => {
+            warn!("Skipping index advisory: {}", error);
+            return;
+        }
+    };
+    let indexes = match diesel::sql_query("SELECT indexdef FROM pg_indexes WHERE tablename = 'messages'")
+        .load::<IndexDefinition>(&connection) {
+        Ok(indexes) => indexes,
+        Err(error) => {
+            warn!("Skipping index advisory: {}", error);
+            return;
+        }
+    };
+    let indexdefs = indexes.into_iter().map(|index| index.indexdef).collect::<Vec<String>>();
+    for recommendation in recommendations(&indexdefs) {
+        warn!("{}", recommendation);
+    }
+}
+
+/// What is missing from the `messages` indexes defined by `indexdefs`.
+fn recommendations(indexdefs: &[String]) -> Vec<String> {
+    let leading_columns = indexdefs
+        .iter()
+        .filter_map(|indexdef| leading_column(indexdef))
+        .collect::<Vec<String>>();
+    let mut recommendations = FILTERED_COLUMNS
+        .iter()
+        .filter(|column| !leading_columns.iter().any(|leading| leading == *column))
+        .map(|column| {
+            format!(
+                "No index on messages.{0}, queries filtering on it scan the whole table. \
+                 Consider: CREATE INDEX messages_{0}_idx ON messages ({0});",
+                column
+            )
+        })
+        .collect::<Vec<String>>();
+    // a btree index can't serve `LIKE '%q%'`, only a trigram one can
+    if !indexdefs.iter().any(|indexdef| indexdef.contains("gin_trgm_ops")) {
+        recommendations.push(String::from(
+            "No trigram index on messages.message, every q search scans the whole table. \
+             Consider: CREATE EXTENSION pg_trgm; \
+             CREATE INDEX messages_message_trgm_idx ON messages USING gin (message gin_trgm_ops);",
+        ));
+    }
+    recommendations
+}
+
+/// First column of an index definition such as
+/// `CREATE INDEX messages_timestamp_idx ON public.messages USING btree ("timestamp", id)`.
+fn leading_column(indexdef: &str) -> Option<String> {
+    let columns = &indexdef[indexdef.find('(')? + 1..];
+    let end = columns.find(|c| c == ',' || c == ')')?;
+    Some(columns[..end].trim().trim_matches('"').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_leading_column() {
+        let indexdef = "CREATE INDEX messages_timestamp_idx ON public.messages USING btree (\"timestamp\", id)";
+        assert_eq!(leading_column(indexdef), Some(String::from("timestamp")));
+    }
+
+    #[test]
+    fn recommends_the_missing_indexes() {
+        let indexdefs = vec![String::from("CREATE UNIQUE INDEX messages_pkey ON public.messages USING btree (id)")];
+        let recommendations = recommendations(&indexdefs);
+        assert_eq!(recommendations.len(), 3);
+        assert!(recommendations[0].contains("CREATE INDEX messages_timestamp_idx ON messages (timestamp);"));
+        assert!(recommendations[1].contains("CREATE INDEX messages_username_idx ON messages (username);"));
+        assert!(recommendations[2].contains("gin_trgm_ops"));
+    }
+
+    #[test]
+    fn recommends_nothing_when_indexed() {
+        let indexdefs = vec![
+            String::from("CREATE INDEX messages_timestamp_idx ON public.messages USING btree (\"timestamp\", id)"),
+            String::from("CREATE INDEX messages_username_idx ON public.messages USING btree (username)"),
+            String::from("CREATE INDEX messages_message_trgm_idx ON public.messages USING gin (message gin_trgm_ops)"),
+        ];
+        assert!(recommendations(&indexdefs).is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+use serde_json::Value;
+
+/// Casing of the keys in JSON responses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldCase {
+    Snake,
+    Camel,
+}
+
+/// Renames the object keys of `value`, recursively, from the snake_case the
+/// models serialize with to `case`.
+pub fn apply_field_case(value: Value, case: FieldCase) -> Value {
+    match (value, case) {
+        (value, FieldCase::Snake) => value,
+        (Value::Object(map), FieldCase::Camel) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (snake_to_camel(&key), apply_field_case(value, case)))
+                .collect(),
+        ),
+        (Value::Array(values), FieldCase::Camel) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| apply_field_case(value, case))
+                .collect(),
+        ),
+        (value, FieldCase::Camel) => value,
+    }
+}
+
+fn snake_to_camel(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper_next = false;
+    for c in key.chars() {
+        if c == '_' && !camel.is_empty() {
+            upper_next = true;
+        } else if upper_next {
+            camel.extend(c.to_uppercase());
+            upper_next = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Value {
+        json!({
+            "messages": [{"user_name": "peter", "created_at": 100}],
+            "next_cursor": null,
+        })
+    }
+
+    #[test]
+    fn keeps_snake_case_keys_by_default() {
+        assert_eq!(apply_field_case(payload(), FieldCase::Snake), payload());
+    }
+
+    #[test]
+    fn renames_nested_keys_to_camel_case() {
+        assert_eq!(
+            apply_field_case(payload(), FieldCase::Camel),
+            json!({
+                "messages": [{"userName": "peter", "createdAt": 100}],
+                "nextCursor": null,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_a_leading_underscore() {
+        assert_eq!(snake_to_camel("_links"), "_links");
+        assert_eq!(snake_to_camel("id"), "id");
+    }
+}
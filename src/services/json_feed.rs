@@ -0,0 +1,83 @@
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde_json::Value;
+
+use super::data_source::models::Message;
+
+pub const JSON_FEED_CONTENT_TYPE: &str = "application/feed+json";
+
+/// A JSON Feed 1.1 document (https://jsonfeed.org/version/1.1) of `messages`, newest first.
+/// URLs are left out since the service doesn't know the scheme and host it is reached by.
+pub fn json_feed(messages: &[Message]) -> Value {
+    let items = messages
+        .iter()
+        .map(|message| {
+            let mut item = json!({
+                "id": message.id.to_string(),
+                "content_text": message.message,
+                "authors": [{"name": message.username}],
+            });
+            if let Some(date_published) = rfc3339(message.timestamp) {
+                item["date_published"] = json!(date_published);
+            }
+            item
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": "microservice",
+        "items": items,
+    })
+}
+
+fn rfc3339(timestamp: i64) -> Option<String> {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i32, timestamp: i64) -> Message {
+        Message {
+            id,
+            username: String::from("peter"),
+            message: format!("message {}", id),
+            timestamp,
+            ip: None,
+            user_agent: None,
+            views: 0,
+            content_hash: None,
+            signature: None,
+            visibility: String::from("public"),
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn has_the_required_json_feed_fields() {
+        let feed = json_feed(&[message(2, 1_600_000_000), message(1, 1_500_000_000)]);
+        assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+        assert!(feed["title"].is_string());
+        let items = feed["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0],
+            json!({
+                "id": "2",
+                "content_text": "message 2",
+                "authors": [{"name": "peter"}],
+                "date_published": "2020-09-13T12:26:40Z",
+            })
+        );
+        assert_eq!(items[1]["id"], "1");
+    }
+
+    #[test]
+    fn leaves_out_dates_out_of_range() {
+        let feed = json_feed(&[message(1, i64::max_value())]);
+        assert!(feed["items"][0].get("date_published").is_none());
+        assert!(json_feed(&[])["items"].as_array().unwrap().is_empty());
+    }
+}
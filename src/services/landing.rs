@@ -0,0 +1,37 @@
+use futures::future::{ok as futureOk, FutureResult};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::Response;
+use hyper::StatusCode;
+use maud::html;
+
+/// `/` with `ROOT_MODE=landing`: the `LANDING_PAGE_FILE` if set, else a short welcome
+/// page pointing at the list, which moves to `/messages`.
+pub fn landing_page_response(landing_page: Option<&str>) -> FutureResult<Response, hyper::Error> {
+    let body = match landing_page {
+        Some(landing_page) => String::from(landing_page),
+        None => render_landing_page(),
+    };
+    futureOk(
+        Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentType::html())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body),
+    )
+}
+
+fn render_landing_page() -> String {
+    (html! {
+        head {
+            title { "microservice" }
+            meta charset="utf-8";
+        }
+        body {
+            h1 { "Welcome" }
+            p {
+                "Read the messages at " a href="/messages" { "/messages" }
+                ", or see " a href="/openapi.json" { "the API description" } "."
+            }
+        }
+    }).into_string()
+}
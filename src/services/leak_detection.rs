@@ -0,0 +1,133 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Shortest pause between two scans for held connections.
+const MIN_SCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Remembers who holds which connection, to warn about the ones held too long.
+pub struct LeakDetector {
+    threshold: Duration,
+    held: Mutex<HeldConnections>,
+}
+
+struct HeldConnections {
+    next_id: usize,
+    by_id: HashMap<usize, HeldConnection>,
+}
+
+struct HeldConnection {
+    since: Instant,
+    /// What checked the connection out, e.g. the route.
+    context: String,
+    reported: bool,
+}
+
+/// Kept next to a checked out connection; dropping it marks the connection returned.
+pub struct LeakGuard {
+    detector: Arc<LeakDetector>,
+    id: usize,
+}
+
+impl LeakDetector {
+    pub fn new(threshold: Duration) -> Self {
+        LeakDetector {
+            threshold,
+            held: Mutex::new(HeldConnections {
+                next_id: 0,
+                by_id: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn track(detector: &Arc<LeakDetector>, context: String) -> LeakGuard {
+        let mut held = detector.held.lock().unwrap();
+        let id = held.next_id;
+        held.next_id = held.next_id.wrapping_add(1);
+        held.by_id.insert(id, HeldConnection {
+            since: Instant::now(),
+            context,
+            reported: false,
+        });
+        LeakGuard {
+            detector: detector.clone(),
+            id,
+        }
+    }
+
+    /// Warns once about every connection held longer than the threshold, and returns
+    /// the contexts warned about.
+    fn scan(&self) -> Vec<String> {
+        let mut held = self.held.lock().unwrap();
+        let mut reported = Vec::new();
+        for connection in held.by_id.values_mut() {
+            let held_for = connection.since.elapsed();
+            if !connection.reported && held_for > self.threshold {
+                connection.reported = true;
+                warn!(
+                    "Connection held by {} for {}ms without being returned to the pool, possible leak",
+                    connection.context,
+                    as_millis(held_for)
+                );
+                reported.push(connection.context.clone());
+            }
+        }
+        reported
+    }
+}
+
+impl Drop for LeakGuard {
+    fn drop(&mut self) {
+        let mut held = self.detector.held.lock().unwrap();
+        if let Some(connection) = held.by_id.remove(&self.id) {
+            if connection.reported {
+                info!(
+                    "Connection held by {} returned after {}ms",
+                    connection.context,
+                    as_millis(connection.since.elapsed())
+                );
+            }
+        }
+    }
+}
+
+/// Scans for held connections forever, meant to run on its own thread.
+pub fn watch_for_leaks(detector: &LeakDetector) {
+    let interval = cmp::max(detector.threshold / 2, MIN_SCAN_INTERVAL);
+    loop {
+        thread::sleep(interval);
+        detector.scan();
+    }
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_once_about_a_held_connection() {
+        let detector = Arc::new(LeakDetector::new(Duration::from_millis(20)));
+        let _held = LeakDetector::track(&detector, String::from("GET /messages"));
+        let returned = LeakDetector::track(&detector, String::from("GET /health"));
+        assert!(detector.scan().is_empty());
+        drop(returned);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(detector.scan(), vec![String::from("GET /messages")]);
+        assert!(detector.scan().is_empty());
+    }
+
+    #[test]
+    fn forgets_returned_connections() {
+        let detector = Arc::new(LeakDetector::new(Duration::from_millis(20)));
+        drop(LeakDetector::track(&detector, String::from("GET /messages")));
+        assert!(detector.held.lock().unwrap().by_id.is_empty());
+        thread::sleep(Duration::from_millis(30));
+        assert!(detector.scan().is_empty());
+    }
+}
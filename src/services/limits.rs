@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Counts in-flight requests per client address.
+pub struct PerIpLimiter {
+    max_per_ip: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Held for the lifetime of a request; dropping it releases the slot.
+pub struct IpPermit {
+    limiter: Arc<PerIpLimiter>,
+    ip: IpAddr,
+}
+
+impl PerIpLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        PerIpLimiter {
+            max_per_ip,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `None` when `ip` already has `max_per_ip` requests in flight.
+    pub fn try_acquire(limiter: &Arc<PerIpLimiter>, ip: IpAddr) -> Option<IpPermit> {
+        let mut active = limiter.active.lock().unwrap();
+        let count = active.entry(ip).or_insert(0);
+        if limiter.max_per_ip > 0 && *count >= limiter.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(IpPermit {
+            limiter: limiter.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().unwrap();
+        let remaining = match active.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => return,
+        };
+        if remaining == 0 {
+            active.remove(&self.ip);
+        }
+    }
+}
+
+/// Counts the exports being streamed, which each hold a connection and read every row.
+pub struct ExportLimiter {
+    max_exports: usize,
+    active: Mutex<usize>,
+}
+
+/// Held until an export has been sent; dropping it releases the slot.
+pub struct ExportPermit {
+    limiter: Arc<ExportLimiter>,
+}
+
+impl ExportLimiter {
+    pub fn new(max_exports: usize) -> Self {
+        ExportLimiter {
+            max_exports,
+            active: Mutex::new(0),
+        }
+    }
+
+    /// Returns `None` when `max_exports` exports are running already.
+    pub fn try_acquire(limiter: &Arc<ExportLimiter>) -> Option<ExportPermit> {
+        let mut active = limiter.active.lock().unwrap();
+        if limiter.max_exports > 0 && *active >= limiter.max_exports {
+            return None;
+        }
+        *active += 1;
+        Some(ExportPermit {
+            limiter: limiter.clone(),
+        })
+    }
+}
+
+impl Drop for ExportPermit {
+    fn drop(&mut self) {
+        *self.limiter.active.lock().unwrap() -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_one_ip_while_another_proceeds() {
+        let limiter = Arc::new(PerIpLimiter::new(2));
+        let busy = "203.0.113.7".parse().unwrap();
+        let other = "198.51.100.9".parse().unwrap();
+        let first = PerIpLimiter::try_acquire(&limiter, busy).unwrap();
+        let _second = PerIpLimiter::try_acquire(&limiter, busy).unwrap();
+        assert!(PerIpLimiter::try_acquire(&limiter, busy).is_none());
+        assert!(PerIpLimiter::try_acquire(&limiter, other).is_some());
+
+        // a finished request frees its slot
+        drop(first);
+        assert!(PerIpLimiter::try_acquire(&limiter, busy).is_some());
+    }
+
+    #[test]
+    fn forgets_ips_without_requests_in_flight() {
+        let limiter = Arc::new(PerIpLimiter::new(1));
+        drop(PerIpLimiter::try_acquire(&limiter, "203.0.113.7".parse().unwrap()));
+        assert!(limiter.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn releases_an_export_slot_when_the_permit_is_dropped() {
+        let limiter = Arc::new(ExportLimiter::new(2));
+        let first = ExportLimiter::try_acquire(&limiter).unwrap();
+        let _second = ExportLimiter::try_acquire(&limiter).unwrap();
+        assert!(ExportLimiter::try_acquire(&limiter).is_none());
+        drop(first);
+        let _third = ExportLimiter::try_acquire(&limiter).unwrap();
+        assert!(ExportLimiter::try_acquire(&limiter).is_none());
+    }
+
+    #[test]
+    fn runs_any_number_of_exports_without_a_limit() {
+        let limiter = Arc::new(ExportLimiter::new(0));
+        let permits = (0..10).filter_map(|_| ExportLimiter::try_acquire(&limiter)).collect::<Vec<_>>();
+        assert_eq!(permits.len(), 10);
+        drop(permits);
+        assert_eq!(*limiter.active.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use futures::future::{ok as futureOk, FutureResult};
+use hyper::header::{ContentLength, ContentType, RetryAfter};
+use hyper::server::Response;
+use hyper::StatusCode;
+use maud::html;
+
+use super::negotiation::ResponseFormat;
+
+pub const DEFAULT_MAINTENANCE_MESSAGE: &str = "The service is down for maintenance, please try again later.";
+
+/// 503 answered by data endpoints in maintenance mode: a small page for browsers,
+/// `{"error": message, "code": "maintenance"}` for JSON and protobuf clients.
+pub fn maintenance_response(
+    format: ResponseFormat,
+    message: &str,
+    retry_after: Option<u64>,
+) -> FutureResult<Response, hyper::Error> {
+    let (content_type, body) = match format {
+        ResponseFormat::Json | ResponseFormat::Ndjson | ResponseFormat::Protobuf => (
+            ContentType::json(),
+            json!({"error": message, "code": "maintenance"}).to_string(),
+        ),
+        ResponseFormat::Html => (ContentType::html(), render_maintenance_page(message)),
+    };
+    let response = Response::new()
+        .with_status(StatusCode::ServiceUnavailable)
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(content_type)
+        .with_body(body);
+    let response = match retry_after {
+        Some(seconds) => response.with_header(RetryAfter::Delay(Duration::from_secs(seconds))),
+        None => response,
+    };
+    futureOk(response)
+}
+
+fn render_maintenance_page(message: &str) -> String {
+    (html! {
+        head {
+            title { "microservice - maintenance" }
+            meta charset="utf-8";
+        }
+        body {
+            h1 { "Down for maintenance" }
+            p { (message) }
+        }
+    }).into_string()
+}
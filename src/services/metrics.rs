@@ -0,0 +1,108 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::data_source::DbPool;
+
+const BUCKETS: usize = 10;
+
+/// Upper bounds in seconds of the `db_pool_checkout_wait_seconds` buckets, `+Inf` aside.
+const CHECKOUT_WAIT_BUCKETS: [f64; BUCKETS] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The pool metrics of `/metrics`, in the Prometheus text format.
+///
+/// Connection gauges are read from the r2d2 pool state at every scrape, only the
+/// checkout waits have to be recorded as they happen.
+#[derive(Default)]
+pub struct PoolMetrics {
+    checkout_waits: Mutex<Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not yet cumulative.
+    counts: [u64; BUCKETS],
+    count: u64,
+    sum: f64,
+}
+
+impl PoolMetrics {
+    /// Records how long a request waited for its connection, failed checkouts included.
+    pub fn observe_checkout_wait(&self, wait: Duration) {
+        let seconds = wait.as_secs() as f64 + f64::from(wait.subsec_nanos()) / 1e9;
+        let mut histogram = self.checkout_waits.lock().unwrap();
+        if let Some(bucket) = CHECKOUT_WAIT_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            histogram.counts[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    pub fn render(&self, pool: &DbPool) -> String {
+        let state = pool.state();
+        let mut text = String::new();
+        gauge(&mut text, "db_pool_connections_total", "Open connections of the pool", state.connections);
+        gauge(&mut text, "db_pool_connections_idle", "Open connections not checked out", state.idle_connections);
+        gauge(
+            &mut text,
+            "db_pool_connections_in_use",
+            "Connections checked out by requests",
+            state.connections - state.idle_connections,
+        );
+        gauge(&mut text, "db_pool_connections_max", "DB_POOL_SIZE", pool.max_size());
+
+        let histogram = self.checkout_waits.lock().unwrap();
+        let name = "db_pool_checkout_wait_seconds";
+        let _ = writeln!(text, "# HELP {} Time requests waited for a database connection", name);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in CHECKOUT_WAIT_BUCKETS.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+        let _ = writeln!(text, "{}_sum {}", name, histogram.sum);
+        let _ = writeln!(text, "{}_count {}", name, histogram.count);
+        text
+    }
+}
+
+fn gauge(text: &mut String, name: &str, help: &str, value: u32) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} gauge", name);
+    let _ = writeln!(text, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::data_source::build_pool;
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_checkout_waits_and_pool_gauges() {
+        let pool = build_pool("postgresql://postgres@localhost:1", 2, Duration::from_millis(50), 1);
+        let metrics = PoolMetrics::default();
+        for &millis in &[3, 30, 10_000] {
+            metrics.observe_checkout_wait(Duration::from_millis(millis));
+        }
+        let text = metrics.render(&pool);
+        let lines = text.lines().collect::<Vec<_>>();
+        for line in &[
+            "db_pool_connections_max 2",
+            "# TYPE db_pool_checkout_wait_seconds histogram",
+            "db_pool_checkout_wait_seconds_bucket{le=\"0.001\"} 0",
+            "db_pool_checkout_wait_seconds_bucket{le=\"0.005\"} 1",
+            "db_pool_checkout_wait_seconds_bucket{le=\"0.05\"} 2",
+            "db_pool_checkout_wait_seconds_bucket{le=\"5\"} 2",
+            "db_pool_checkout_wait_seconds_bucket{le=\"+Inf\"} 3",
+            "db_pool_checkout_wait_seconds_count 3",
+        ] {
+            assert!(lines.contains(line), "{} missing from\n{}", line, text);
+        }
+        for gauge in &["db_pool_connections_total ", "db_pool_connections_idle ", "db_pool_connections_in_use "] {
+            assert!(lines.iter().any(|line| line.starts_with(gauge)), "{} missing from\n{}", gauge, text);
+        }
+    }
+}
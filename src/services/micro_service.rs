@@ -1,116 +1,980 @@
+use std::cmp;
 use std::collections::HashMap;
-use std::env;
 use std::error::Error as StdError;
 use std::fmt;
-use std::io::{self, Error as IoError, ErrorKind as IoErrorKind};
-use std::str::Utf8Error;
+use std::io;
+use std::net::IpAddr;
+use std::str::{self, FromStr, Utf8Error};
 use std::string::FromUtf8Error;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use diesel::pg::PgConnection;
+use diesel::pg::Pg;
 use diesel::prelude::*;
-use futures::future::{err as futureErr, Future, FutureResult, ok as futureOk};
+use futures::future::{err as futureErr, Either, Future, FutureResult, ok as futureOk};
 use futures::Stream;
-use hyper::{Chunk, StatusCode};
+use hyper::StatusCode;
+use hyper::mime;
 use hyper::Error as hyperError;
-use hyper::header::{ContentLength, ContentType};
-use hyper::Method::{Get, Post};
+use hyper::header::{Allow, ContentLength, ContentType, Headers, RetryAfter, UserAgent};
 use hyper::server::{Request, Response, Service};
-use maud::html;
+use maud::{html, Markup, PreEscaped};
+use sha2::{Digest, Sha256};
+use tokio_core::reactor::{Handle, Timeout};
+use unicode_normalization::UnicodeNormalization;
 use url::form_urlencoded;
 
-use dotenv::dotenv;
-
+use super::access_log::log_access;
+use super::activity::{parse_activity_query, query_activity, render_activity_json};
+use super::allowed_hosts::is_allowed_host;
+use super::audit::{recent_audit_entries, AuditContext, DEFAULT_AUDIT_ENTRIES, MAX_AUDIT_ENTRIES};
+use super::auth::is_admin;
+use super::client_ip::client_ip;
+use super::compression::{accepts_gzip, compress_response};
+use super::config::{Config, ControlCharPolicy, InsertReturning, MissingIds};
+use super::content_dedup::{message_has_emoji, message_text, resolve_content, resolve_contents, store_content};
+use super::cursor::encode_cursor;
+use super::data_source::{checkout, CountingConnection, QueryBudget};
 use super::data_source::models::Message;
-use super::data_source::models::NewMessage;
+use super::data_source::models::{NewMessage, ReplicatedMessage, RequestMeta};
+use super::dual_write::SecondaryStore;
+use super::error::ServiceError;
+use super::exports::{make_csv_export_response, make_sql_export_response, EXPORT_BATCH_SIZE};
+use super::explain::explain_analyze;
+use super::health::health_report;
+use super::https_redirect::{https_response, is_plaintext};
+use super::json_case::{apply_field_case, FieldCase};
+use super::json_feed::{json_feed, JSON_FEED_CONTENT_TYPE};
+use super::landing::landing_page_response;
+use super::leak_detection::LeakDetector;
+use super::limits::{ExportLimiter, PerIpLimiter};
+use super::maintenance::maintenance_response;
+use super::metrics::METRICS_CONTENT_TYPE;
+use super::moderation::moderate;
+use super::negotiation::{is_htmx_request, prefers_plain_text, response_format, ResponseFormat};
+use super::openapi::{api_index, openapi_document, root_methods};
+use super::protobuf::encode_message_list;
+use super::queue::RequestQueue;
+use super::request_body::{body_encoding, check_content_length, declared_length, decode_body, has_content_type, require_content_type};
+use super::routes::Route;
+use super::safe_html::safe_html_sanitizer;
+use super::scheduler::{checkout_blocking, Access, CheckoutScheduler, ScheduledConnection};
+use super::signing::{sign, verify};
+use super::single_flight::SingleFlight;
+use super::state::ServiceState;
+use super::static_files::{favicon_response, robots_txt_response};
+use super::streaming::stream_body;
+use super::time_display::TimeDisplay;
+use super::timeline::{parse_timeline_query, query_timeline, TimelineQuery};
+use super::trace_headers::TraceHeaders;
+use super::word_stats::top_words;
+use super::write_buffer::{BufferedInsert, WriteBuffer};
+
+/// Length of the `username VARCHAR(128)` column; longer names are refused by Postgres.
+const MAX_USERNAME_LEN: usize = 128;
+
+/// Largest `?sample=` honored; bigger requests are capped to it.
+const MAX_SAMPLE_SIZE: i64 = 100;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// Largest number of ids accepted by `GET /messages?ids=`.
+const MAX_BATCH_IDS: usize = 100;
+
+/// Most instants accepted by `?timestamps=`.
+const MAX_TIMESTAMPS: usize = 100;
+
+/// Run by `POST /admin/maintenance`.
+const DB_MAINTENANCE_COMMAND: &str = "VACUUM ANALYZE messages";
+
+/// Default and largest number of `/stats/words` words.
+const DEFAULT_WORD_STATS_LIMIT: usize = 20;
+const MAX_WORD_STATS_LIMIT: usize = 100;
 
-const DEFAULT_DATABASE_URL: &'static str = "postgresql://postgres@localhost:5432";
+/// Values of `messages.visibility`.
+pub const VISIBILITY_PUBLIC: &str = "public";
+const VISIBILITY_PRIVATE: &str = "private";
 
-pub struct MicroService;
+/// Most `known_ids` in a `POST /messages/sync` snapshot.
+const MAX_SYNC_KNOWN_IDS: usize = 1000;
+
+/// Most messages pinned at once.
+const MAX_PINNED_MESSAGES: i64 = 10;
+/// The advisory lock `set_pinned` takes, an arbitrary key no other lock uses.
+const PIN_LOCK_KEY: i64 = 0x7069_6e6e_6564;
+
+/// Messages in `/feed.json`.
+const FEED_ITEMS: i64 = 50;
+
+no_arg_sql_function!(random, diesel::sql_types::Double);
+sql_function!(fn char_length(x: diesel::sql_types::Text) -> diesel::sql_types::Integer);
+
+type ResponseFuture = Box<dyn Future<Item=Response, Error=hyper::Error>>;
+
+#[derive(Clone)]
+pub struct MicroService {
+    state: Arc<ServiceState>,
+    handle: Handle,
+    /// List queries in flight, for `DEDUPE_GET_QUERIES`.
+    list_flights: Rc<SingleFlight<Vec<Message>>>,
+    /// Inserts in flight by client and `Idempotency-Key`, with `COALESCE_IDEMPOTENT_INSERTS=1`.
+    insert_flights: Rc<SingleFlight<InsertOutcome>>,
+}
 
 impl Service for MicroService {
     type Request = Request;
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<dyn Future<Item=Self::Response, Error=Self::Error>>;
+    type Future = ResponseFuture;
 
     fn call(&self, request: Request) -> Self::Future {
-        let db_connection = match connect_to_db() {
-            Some(connection) => connection,
-            None => {
-                return Box::new(futures::future::ok(
-                    Response::new().with_status(StatusCode::InternalServerError),
-                ));
+        let started = Instant::now();
+        let method = request.method().clone();
+        let path = request.path().to_string();
+        let sample_rate = self.state.config.access_log_sample_rate;
+        let trace_headers = if self.state.config.trace_headers {
+            let trace_headers = TraceHeaders::from_request(request.headers());
+            debug!("{} {} {}", method, path, trace_headers);
+            Some(trace_headers)
+        } else {
+            None
+        };
+        Box::new(self.serve(request).map(move |mut response| {
+            if let Some(ref trace_headers) = trace_headers {
+                trace_headers.apply(response.headers_mut());
+            }
+            log_access(&method, &path, response.status(), started.elapsed(), sample_rate);
+            response
+        }))
+    }
+}
+
+impl MicroService {
+    pub fn new(
+        state: Arc<ServiceState>,
+        handle: Handle,
+        list_flights: Rc<SingleFlight<Vec<Message>>>,
+        insert_flights: Rc<SingleFlight<InsertOutcome>>,
+    ) -> Self {
+        MicroService { state, handle, list_flights, insert_flights }
+    }
+
+    /// Admits the request past the per-client limit and the queue, then routes it.
+    fn serve(&self, request: Request) -> ResponseFuture {
+        let client = client_ip(request.remote_addr(), request.headers(), &self.state.config.trusted_proxies);
+        debug!("{} {} from {:?}", request.method(), request.path(), client);
+
+        if let Some(ip) = client {
+            if let Err(retry_after) = self.state.rate_limiter.check(ip, request.method().as_ref()) {
+                warn!("Rate limit of {} {} exceeded", ip, request.method());
+                return Box::new(make_service_error_response(&ServiceError::RateLimited(retry_after)));
+            }
+        }
+
+        let permit = match client {
+            Some(ip) => match PerIpLimiter::try_acquire(&self.state.per_ip_limiter, ip) {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("Too many concurrent requests from {}", ip);
+                    return Box::new(make_error_response(
+                        StatusCode::TooManyRequests,
+                        "Too many concurrent requests",
+                    ));
+                }
+            },
+            None => None,
+        };
+
+        let service = self.clone();
+        let queued = RequestQueue::acquire(&self.state.request_queue, &self.handle);
+        Box::new(queued.then(move |queued| -> ResponseFuture {
+            let queue_permit = match queued {
+                Ok(queue_permit) => queue_permit,
+                Err(rejection) => {
+                    warn!("Rejecting request, queue {:?}", rejection);
+                    return Box::new(make_error_response(
+                        StatusCode::ServiceUnavailable,
+                        "Server is busy, try again later",
+                    ));
+                }
+            };
+            let gzip_level = if service.state.config.gzip_responses && accepts_gzip(request.headers()) {
+                Some(service.state.config.gzip_level)
+            } else {
+                None
+            };
+            // the permits are released once the response has been produced
+            let response = service.route(request, client).and_then(move |response| -> ResponseFuture {
+                match gzip_level {
+                    Some(level) => compress_response(response, level),
+                    None => Box::new(futureOk(response)),
+                }
+            });
+            Box::new(response.then(move |result| {
+                drop(queue_permit);
+                drop(permit);
+                result
+            }))
+        }))
+    }
+
+    /// Answers the request, or 503 with code `timeout` once the route's timeout is up.
+    fn route(&self, request: Request, client: Option<IpAddr>) -> ResponseFuture {
+        let config = &self.state.config;
+        let route = Route::of(request.method(), request.path()).with_root_mode(config.root_mode, request.query());
+        if let Some(ref allowed_hosts) = config.allowed_hosts {
+            if !route.always_available() && !is_allowed_host(request.headers(), allowed_hosts) {
+                return Box::new(make_error_response(StatusCode::BadRequest, "host not allowed"));
+            }
+        }
+        if let Some(http_to_https) = config.http_to_https {
+            // probes talk to the service directly, past the TLS proxy
+            let is_probe = route == Route::Health || route == Route::Ready || route == Route::Metrics;
+            if !is_probe && is_plaintext(request.remote_addr(), request.headers(), &config.trusted_proxies) {
+                return Box::new(https_response(http_to_https, &request));
+            }
+        }
+        if config.maintenance_mode && !route.always_available() && route != Route::NotFound {
+            return Box::new(maintenance_response(
+                response_format(request.headers()),
+                &config.maintenance_message,
+                config.maintenance_retry_after,
+            ));
+        }
+        if config.require_user_agent && !route.always_available() && !request.headers().has::<UserAgent>() {
+            return Box::new(make_error_response(StatusCode::BadRequest, "User-Agent header required"));
+        }
+        if !route.always_available() && !self.state.is_ready() {
+            return Box::new(make_service_error_response(&ServiceError::Starting));
+        }
+
+        let response = self.dispatch(route, request, client);
+        let response = match config.constant_time_floor {
+            Some(floor) if route.is_sensitive_lookup() => pad_response_time(response, floor, &self.handle),
+            _ => response,
+        };
+        let timeout = match self.state.config.timeout_for(route) {
+            Some(timeout) => timeout,
+            None => return response,
+        };
+        let timeout = match Timeout::new(timeout, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(error) => {
+                error!("Error creating request timeout: {}", error);
+                return response;
             }
         };
+        Box::new(response.select2(timeout).then(move |result| -> ResponseFuture {
+            match result {
+                Ok(Either::A((response, _))) => Box::new(futureOk(response)),
+                Err(Either::A((error, _))) => Box::new(futureErr(error)),
+                Ok(Either::B(_)) | Err(Either::B(_)) => {
+                    debug!("Route {} timed out", route.name());
+                    Box::new(make_service_error_response(&ServiceError::Timeout))
+                }
+            }
+        }))
+    }
 
-        match (request.method(), request.path()) {
-            (&Post, "/") => {
-                let future = request
+    fn dispatch(&self, route: Route, request: Request, client: Option<IpAddr>) -> ResponseFuture {
+        match route {
+            Route::Ready => {
+                let response = if self.state.is_ready() {
+                    make_json_response(StatusCode::Ok, json!({"status": "ready"}).to_string())
+                } else {
+                    make_service_error_response(&ServiceError::Starting)
+                };
+                Box::new(response)
+            }
+            Route::Health => {
+                let verbose = self.state.config.health_verbose || query_flag(request.query(), "verbose");
+                let deep = query_flag(request.query(), "deep");
+                let service = self.clone();
+                let report = health_report(&self.state, verbose, deep, move || service.connection(route, Access::Write));
+                Box::new(report.then(|report| match report {
+                    Ok((status, payload)) => make_json_response(status, payload.to_string()),
+                    Err(error) => make_service_error_response(&error),
+                }))
+            }
+            Route::Metrics => match self.state.pool_metrics {
+                Some(ref metrics) => {
+                    let text = metrics.render(&self.state.pool);
+                    Box::new(futureOk(
+                        Response::new()
+                            .with_header(ContentType(METRICS_CONTENT_TYPE.parse().unwrap()))
+                            .with_header(ContentLength(text.len() as u64))
+                            .with_body(text),
+                    ))
+                }
+                None => Box::new(futureOk(Response::new().with_status(StatusCode::NotFound))),
+            },
+            Route::Version => {
+                let payload = json!({"version": env!("CARGO_PKG_VERSION")});
+                Box::new(make_json_response(StatusCode::Ok, payload.to_string()))
+            }
+            Route::OpenApi => Box::new(make_json_response(StatusCode::Ok, openapi_document().to_string())),
+            Route::Index => {
+                let response = make_json_response(StatusCode::Ok, api_index().to_string())
+                    .map(|response| response.with_header(Allow(root_methods())));
+                Box::new(response)
+            }
+            Route::Insert => {
+                let service = self.clone();
+                let moderation = self.state.config.moderation.clone();
+                let handle = self.handle.clone();
+                let request_meta = request_meta(&self.state.config, client, request.headers());
+                let plain_text = prefers_plain_text(request.headers());
+                let audit = audit_context(&self.state.config, client, route);
+                let normalize_unicode = self.state.config.normalize_unicode;
+                let control_char_policy = self.state.config.control_char_policy;
+                // with the lenient policy an untyped body is parsed as a form, as it always was
+                let has_type = self.state.config.lenient_content_type || has_content_type(request.headers());
+                let encoding = match body_encoding(request.headers()) {
+                    Ok(encoding) => encoding,
+                    Err(error) => return Box::new(make_service_error_response(&error)),
+                };
+                let content_length = declared_length(request.headers());
+                let idempotency_key = if self.state.config.coalesce_idempotent_inserts {
+                    idempotency_key(request.headers()).map(|key| format!("{:?} {}", client, key))
+                } else {
+                    None
+                };
+                let insert = request
                     .body()
                     .concat2()
-                    .and_then(parse_form)
-                    .and_then(move |new_message| write_to_db(new_message, &db_connection))
-                    .then(make_post_response);
-                Box::new(future)
-            }
-            (&Get, "/") => {
-                let time_range = match request.query() {
-                    Some(query) => parse_query(query),
-                    None => Ok(TimeRange {
-                        before: None,
-                        after: None,
+                    .map_err(ServiceError::from)
+                    .and_then(move |body| check_content_length(body, content_length))
+                    .and_then(move |body| require_content_type(body, has_type))
+                    .and_then(move |body| decode_body(body, encoding))
+                    .and_then(move |body| parse_form(body, request_meta))
+                    .map(move |new_message| if normalize_unicode { normalize_nfc(new_message) } else { new_message })
+                    .and_then(move |new_message| match control_char_policy {
+                        Some(policy) => apply_control_char_policy(new_message, policy),
+                        None => Ok(new_message),
+                    })
+                    .and_then(move |new_message| -> Box<dyn Future<Item=NewMessage, Error=ServiceError>> {
+                        match moderation {
+                            Some(ref moderation) => moderate(new_message, moderation, &handle),
+                            None => Box::new(futureOk(new_message)),
+                        }
+                    })
+                    .and_then(move |new_message| {
+                        service.connection(route, Access::Write).then(move |connection| {
+                            let state = &service.state;
+                            match connection {
+                                Ok(db_connection) => Either::A(
+                                    write_to_db(new_message, audit, &state.config, state.secondary.as_ref(), &db_connection)
+                                        .map(InsertOutcome::Inserted),
+                                ),
+                                Err(error) => Either::B(queue_write(new_message, audit, error, state.write_buffer.as_ref())),
+                            }
+                        })
+                    });
+                match idempotency_key {
+                    // a request joining one in flight never reads its own body, the insert is the first request's
+                    Some(key) => {
+                        let flight = SingleFlight::run(&self.insert_flights, key, move || Box::new(insert));
+                        Box::new(flight.then(move |result| match result {
+                            Ok(outcome) => make_post_response(Ok(*outcome), plain_text),
+                            Err(error) => make_service_error_response(&error),
+                        }))
+                    }
+                    None => Box::new(insert.then(move |result| make_post_response(result, plain_text))),
+                }
+            }
+            Route::List => {
+                let mut render_options = self.render_options(&request, response_format(request.headers()));
+                if render_options.format == ResponseFormat::Html && is_htmx_request(request.headers()) {
+                    render_options.htmx_list_url = Some(request.uri().to_string());
+                }
+                let message_query = match request.query() {
+                    Some(query) => parse_query(query, &self.state.config),
+                    None => Ok(MessageQuery::default()),
+                };
+                let mut message_query = match message_query {
+                    Ok(message_query) => message_query,
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                message_query.include_private = render_options.show_meta;
+                if query_flag(request.query(), "explain") {
+                    // plans show table sizes and timings, not for the public
+                    if !render_options.show_meta {
+                        return Box::new(make_error_response(StatusCode::Unauthorized, "admin token required"));
+                    }
+                    let state = self.state.clone();
+                    return self.with_connection(route, Access::Read, move |db_connection| {
+                        make_explain_response(&message_query, &state.config, db_connection)
+                    });
+                }
+                let page = match parse_page(request.query(), self.state.config.max_offset, self.state.config.cap_offset) {
+                    Ok(page) => page,
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                if let Some(page) = page {
+                    if message_query.sample.is_some() {
+                        return Box::new(make_error_response(StatusCode::BadRequest, "'page' can't be combined with 'sample'"));
+                    }
+                    let state = self.state.clone();
+                    return self.with_connection(route, Access::Read, move |db_connection| {
+                        make_page_response(&message_query, page, &state.config, &render_options, db_connection)
+                    });
+                }
+                // a random sample has no order to take a range of
+                let items = match message_query.sample {
+                    Some(_) => None,
+                    None => parse_item_range(request.headers()),
+                };
+                // HTMX fragments are paged, the pager asks for the following range
+                let items = match items {
+                    None if render_options.htmx_list_url.is_some() && message_query.sample.is_none() => Some(ItemRange {
+                        first: 0,
+                        last: DEFAULT_PAGE_SIZE - 1,
                     }),
+                    items => items,
                 };
-                let response = match time_range {
-                    Ok(time_range) => make_get_response(query_db(time_range, &db_connection)),
-                    Err(error) => make_error_response(&error),
+                let items = match items.map(|items| limit_offset(items, self.state.config.max_offset, self.state.config.cap_offset)) {
+                    Some(Err(error)) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                    Some(Ok(items)) => Some(items),
+                    None => None,
                 };
-                Box::new(response)
+                // answered without a connection, streamed formats included
+                if items.is_none() && message_query.matches_nothing() {
+                    return Box::new(make_get_response(Some(Vec::new()), &render_options).map(with_empty_range_hint));
+                }
+                let framing = match render_options.format {
+                    // a range is sent in one piece, like the JSON array's
+                    ResponseFormat::Ndjson if items.is_none() => Some(ListFraming::Ndjson),
+                    ResponseFormat::Json if self.state.config.stream_json_lists && items.is_none() => {
+                        Some(ListFraming::JsonArray)
+                    }
+                    _ => None,
+                };
+                if let Some(framing) = framing {
+                    let state = self.state.clone();
+                    let handle = self.handle.clone();
+                    return Box::new(self.connection(route, Access::Read).then(move |connection| match connection {
+                        Ok(connection) => futureOk(make_streamed_list_response(
+                            framing,
+                            message_query,
+                            state,
+                            render_options,
+                            connection,
+                            &handle,
+                        )),
+                        Err(error) => make_service_error_response(&error),
+                    }));
+                }
+                if self.state.config.dedupe_get_queries && items.is_none() && message_query.sample.is_none() {
+                    return self.shared_list(route, message_query, render_options);
+                }
+                let state = self.state.clone();
+                self.with_connection(route, Access::Read, move |db_connection| match items {
+                    Some(items) => make_range_response(&message_query, items, &state.config, &render_options, db_connection),
+                    None => make_get_response(query_db(message_query, &state.config, db_connection), &render_options),
+                })
+            }
+            Route::MessageCount => {
+                let message_query = match request.query() {
+                    Some(query) => parse_query(query, &self.state.config),
+                    None => Ok(MessageQuery::default()),
+                };
+                let mut message_query = match message_query {
+                    Ok(message_query) => message_query,
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                message_query.include_private = is_admin(request.headers(), &self.state.config.admin_token);
+                if message_query.matches_nothing() {
+                    let response = make_json_response(StatusCode::Ok, json!({"count": 0}).to_string());
+                    return Box::new(response.map(with_empty_range_hint));
+                }
+                let state = self.state.clone();
+                self.with_connection(route, Access::Read, move |db_connection| match count_db(&message_query, &state.config, db_connection) {
+                    Some(count) => make_json_response(StatusCode::Ok, json!({"count": count}).to_string()),
+                    None => futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+                })
+            }
+            Route::Messages => {
+                let render_options = self.render_options(&request, ResponseFormat::Json);
+                let ids = match parse_id_list(request.query()) {
+                    Ok(ids) => ids,
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                let missing_ids = self.state.config.missing_ids;
+                let include_private = render_options.show_meta;
+                self.with_connection(route, Access::Read, move |db_connection| match query_db_by_ids(&ids, include_private, db_connection) {
+                    Some(messages) => match render_batch_json(&ids, messages, missing_ids, &render_options) {
+                        Ok(payload) => make_json_response(StatusCode::Ok, payload.to_string()),
+                        Err(error) => make_service_error_response(&error.into()),
+                    },
+                    None => futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+                })
+            }
+            Route::Message => {
+                let render_options = self.render_options(&request, ResponseFormat::Json);
+                let id = match request.path()["/messages/".len()..].parse::<i32>() {
+                    Ok(id) => id,
+                    Err(_) => return Box::new(futureOk(Response::new().with_status(StatusCode::NotFound))),
+                };
+                let track_views = self.state.config.track_views;
+                let access = if track_views { Access::Write } else { Access::Read };
+                let include_private = render_options.show_meta;
+                self.with_connection(route, access, move |db_connection| match query_message(id, track_views, include_private, db_connection) {
+                    Ok(Some(message)) => match message_json(&message, &render_options) {
+                        Ok(mut payload) => {
+                            payload["views"] = json!(message.views);
+                            let payload = apply_field_case(payload, render_options.field_case);
+                            make_json_response(StatusCode::Ok, payload.to_string())
+                        }
+                        Err(error) => make_service_error_response(&error.into()),
+                    },
+                    Ok(None) => make_error_response(StatusCode::NotFound, "message not found"),
+                    Err(error) => {
+                        error!("Error query Db: {}", error);
+                        futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                    }
+                })
+            }
+            Route::VerifyMessage => {
+                let key = match self.state.config.message_signing_key {
+                    Some(ref key) => key.clone(),
+                    None => return Box::new(make_error_response(StatusCode::NotFound, "message signing is not enabled")),
+                };
+                let path = request.path();
+                let id = match path["/messages/".len()..path.len() - "/verify".len()].parse::<i32>() {
+                    Ok(id) => id,
+                    Err(_) => return Box::new(futureOk(Response::new().with_status(StatusCode::NotFound))),
+                };
+                let include_private = is_admin(request.headers(), &self.state.config.admin_token);
+                self.with_connection(route, Access::Read, move |db_connection| match query_message(id, false, include_private, db_connection) {
+                    Ok(Some(message)) => {
+                        let payload = json!({
+                            "id": message.id,
+                            "signed": message.signature.is_some(),
+                            "valid": verify(&key, &message),
+                        });
+                        make_json_response(StatusCode::Ok, payload.to_string())
+                    }
+                    Ok(None) => make_error_response(StatusCode::NotFound, "message not found"),
+                    Err(error) => {
+                        error!("Error query Db: {}", error);
+                        futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                    }
+                })
+            }
+            Route::PinMessage => {
+                if !is_admin(request.headers(), &self.state.config.admin_token) {
+                    return Box::new(make_error_response(StatusCode::Unauthorized, "admin token required"));
+                }
+                let path = request.path();
+                let id = match path["/messages/".len()..path.len() - "/pin".len()].parse::<i32>() {
+                    Ok(id) => id,
+                    Err(_) => return Box::new(futureOk(Response::new().with_status(StatusCode::NotFound))),
+                };
+                let args = form_urlencoded::parse(request.query().unwrap_or("").as_bytes())
+                    .into_owned()
+                    .collect::<HashMap<String, String>>();
+                let pinned = match parse_arg::<bool>(&args, "pinned") {
+                    Ok(pinned) => pinned.unwrap_or(true),
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                let audit = audit_context(&self.state.config, client, route);
+                self.with_connection(route, Access::Write, move |db_connection| {
+                    let outcome = db_connection.transaction(|| {
+                        let outcome = set_pinned(id, pinned, db_connection)?;
+                        if outcome == PinOutcome::Updated {
+                            if let Some(ref audit) = audit {
+                                audit.record(Some(id), true, db_connection)?;
+                            }
+                        }
+                        Ok(outcome)
+                    });
+                    match outcome {
+                        Ok(PinOutcome::Updated) => make_json_response(StatusCode::Ok, json!({"id": id, "pinned": pinned}).to_string()),
+                        Ok(PinOutcome::NotFound) => make_error_response(StatusCode::NotFound, "message not found"),
+                        Ok(PinOutcome::LimitReached) => make_error_response(
+                            StatusCode::Conflict,
+                            &format!("at most {} messages can be pinned", MAX_PINNED_MESSAGES),
+                        ),
+                        Err(error) => {
+                            error!("Error pinning message: {}", error);
+                            if let Some(ref audit) = audit {
+                                audit.record_failure(db_connection);
+                            }
+                            futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                        }
+                    }
+                })
+            }
+            Route::Sync => {
+                let render_options = self.render_options(&request, ResponseFormat::Json);
+                let has_type = self.state.config.lenient_content_type || has_content_type(request.headers());
+                let encoding = match body_encoding(request.headers()) {
+                    Ok(encoding) => encoding,
+                    Err(error) => return Box::new(make_service_error_response(&error)),
+                };
+                let content_length = declared_length(request.headers());
+                let service = self.clone();
+                let include_private = render_options.show_meta;
+                let sync = request
+                    .body()
+                    .concat2()
+                    .map_err(ServiceError::from)
+                    .and_then(move |body| check_content_length(body, content_length))
+                    .and_then(move |body| require_content_type(body, has_type))
+                    .and_then(move |body| decode_body(body, encoding))
+                    .and_then(parse_sync_snapshot)
+                    .and_then(move |snapshot| {
+                        service.connection(route, Access::Read).and_then(move |db_connection| {
+                            match sync_messages(&snapshot, include_private, &db_connection) {
+                                Ok(diff) => render_sync_json(diff, &snapshot, &render_options).map_err(ServiceError::from),
+                                Err(error) => {
+                                    error!("Error query Db: {}", error);
+                                    Err(ServiceError::Internal(String::from("service error")))
+                                }
+                            }
+                        })
+                    });
+                Box::new(sync.then(|payload| match payload {
+                    Ok(payload) => make_json_response(StatusCode::Ok, payload.to_string()),
+                    Err(error) => make_service_error_response(&error),
+                }))
+            }
+            Route::Timeline => {
+                let render_options = self.render_options(&request, ResponseFormat::Json);
+                let cursor_key = self.state.config.cursor_signing_key.clone();
+                let timeline_query = match parse_timeline_query(request.query(), cursor_key.as_ref().map(String::as_str)) {
+                    Ok(timeline_query) => timeline_query,
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                let include_private = render_options.show_meta;
+                self.with_connection(route, Access::Read, move |db_connection| match query_timeline(&timeline_query, include_private, db_connection) {
+                    Some(messages) => match render_timeline_json(messages, &timeline_query, cursor_key.as_ref().map(String::as_str), &render_options) {
+                        Ok(payload) => make_json_response(StatusCode::Ok, payload.to_string()),
+                        Err(error) => make_service_error_response(&error.into()),
+                    },
+                    None => futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+                })
+            }
+            Route::BackfillTimestamps => {
+                if !is_admin(request.headers(), &self.state.config.admin_token) {
+                    return Box::new(make_error_response(StatusCode::Unauthorized, "admin token required"));
+                }
+                let audit = audit_context(&self.state.config, client, route);
+                self.with_connection(route, Access::Write, move |db_connection| {
+                    let updated = db_connection.transaction(|| {
+                        let updated = backfill_timestamps(db_connection)?;
+                        if let Some(ref audit) = audit {
+                            audit.record(None, true, db_connection)?;
+                        }
+                        Ok(updated)
+                    });
+                    match updated {
+                        Ok(updated) => make_json_response(StatusCode::Ok, json!({"updated": updated}).to_string()),
+                        Err(error) => {
+                            error!("Error backfilling timestamps: {}", error);
+                            if let Some(ref audit) = audit {
+                                audit.record_failure(db_connection);
+                            }
+                            futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                        }
+                    }
+                })
+            }
+            Route::DbMaintenance => {
+                if !is_admin(request.headers(), &self.state.config.admin_token) {
+                    return Box::new(make_error_response(StatusCode::Unauthorized, "admin token required"));
+                }
+                if let Err(retry_after) = self.state.claim_db_maintenance() {
+                    return Box::new(make_service_error_response(&ServiceError::RateLimited(retry_after)));
+                }
+                let audit = audit_context(&self.state.config, client, route);
+                self.with_connection(route, Access::Write, move |db_connection| {
+                    let started = Instant::now();
+                    // VACUUM refuses to run in a transaction, diesel sends it on its own
+                    let result = diesel::sql_query(DB_MAINTENANCE_COMMAND).execute(db_connection);
+                    // so its audit entry can only follow it
+                    if let Some(ref audit) = audit {
+                        if let Err(error) = audit.record(None, result.is_ok(), db_connection) {
+                            error!("Error recording audit entry: {}", error);
+                        }
+                    }
+                    match result {
+                        Ok(_) => {
+                            let elapsed = started.elapsed();
+                            let duration_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+                            info!("{} took {}ms", DB_MAINTENANCE_COMMAND, duration_ms);
+                            let payload = json!({"command": DB_MAINTENANCE_COMMAND, "duration_ms": duration_ms});
+                            make_json_response(StatusCode::Ok, payload.to_string())
+                        }
+                        Err(error) => {
+                            error!("Error running {}: {}", DB_MAINTENANCE_COMMAND, error);
+                            futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                        }
+                    }
+                })
+            }
+            Route::ExportCsv => {
+                if !is_admin(request.headers(), &self.state.config.admin_token) {
+                    return Box::new(make_error_response(StatusCode::Unauthorized, "admin token required"));
+                }
+                let permit = match ExportLimiter::try_acquire(&self.state.export_limiter) {
+                    Some(permit) => permit,
+                    None => return Box::new(make_service_error_response(&ServiceError::ExportsBusy)),
+                };
+                let handle = self.handle.clone();
+                Box::new(self.connection(route, Access::Read).then(move |connection| match connection {
+                    Ok(connection) => futureOk(make_csv_export_response(connection, permit, &handle)),
+                    Err(error) => make_service_error_response(&error),
+                }))
+            }
+            Route::AuditLog => {
+                if !is_admin(request.headers(), &self.state.config.admin_token) {
+                    return Box::new(make_error_response(StatusCode::Unauthorized, "admin token required"));
+                }
+                let args = form_urlencoded::parse(request.query().unwrap_or("").as_bytes())
+                    .into_owned()
+                    .collect::<HashMap<String, String>>();
+                let limit = match parse_arg::<i64>(&args, "limit") {
+                    Ok(limit) => cmp::max(1, cmp::min(limit.unwrap_or(DEFAULT_AUDIT_ENTRIES), MAX_AUDIT_ENTRIES)),
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                self.with_connection(route, Access::Read, move |db_connection| match recent_audit_entries(limit, db_connection) {
+                    Ok(entries) => match serde_json::to_string(&entries) {
+                        Ok(payload) => make_json_response(StatusCode::Ok, payload),
+                        Err(error) => make_service_error_response(&error.into()),
+                    },
+                    Err(error) => {
+                        error!("Error query Db: {}", error);
+                        futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                    }
+                })
+            }
+            Route::ExportSql => {
+                if !is_admin(request.headers(), &self.state.config.admin_token) {
+                    return Box::new(make_error_response(StatusCode::Unauthorized, "admin token required"));
+                }
+                let permit = match ExportLimiter::try_acquire(&self.state.export_limiter) {
+                    Some(permit) => permit,
+                    None => return Box::new(make_service_error_response(&ServiceError::ExportsBusy)),
+                };
+                let handle = self.handle.clone();
+                Box::new(self.connection(route, Access::Read).then(move |connection| match connection {
+                    Ok(connection) => futureOk(make_sql_export_response(connection, permit, &handle)),
+                    Err(error) => make_service_error_response(&error),
+                }))
+            }
+            Route::Activity => {
+                let activity_query = match parse_activity_query(request.query()) {
+                    Ok(activity_query) => activity_query,
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                let now = unix_now();
+                self.with_connection(route, Access::Read, move |db_connection| match query_activity(&activity_query, now, db_connection) {
+                    Some(counts) => {
+                        let payload = render_activity_json(&activity_query, now, counts);
+                        make_json_response(StatusCode::Ok, payload.to_string())
+                    }
+                    None => futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+                })
+            }
+            Route::WordStats => {
+                let args = form_urlencoded::parse(request.query().unwrap_or("").as_bytes())
+                    .into_owned()
+                    .collect::<HashMap<String, String>>();
+                let limit = match parse_arg::<usize>(&args, "limit") {
+                    Ok(limit) => cmp::max(1, cmp::min(limit.unwrap_or(DEFAULT_WORD_STATS_LIMIT), MAX_WORD_STATS_LIMIT)),
+                    Err(error) => return Box::new(make_error_response(StatusCode::BadRequest, &error)),
+                };
+                let state = self.state.clone();
+                self.with_connection(route, Access::Read, move |db_connection| {
+                    match recent_message_texts(state.config.word_stats_window, db_connection) {
+                        Ok(texts) => {
+                            let words = top_words(texts.iter().map(String::as_str), &state.config.stopwords, limit)
+                                .into_iter()
+                                .map(|(word, count)| json!({"word": word, "count": count}))
+                                .collect::<Vec<_>>();
+                            let payload = json!({"messages": texts.len(), "words": words});
+                            make_json_response(StatusCode::Ok, payload.to_string())
+                        }
+                        Err(error) => {
+                            error!("Error query Db: {}", error);
+                            futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                        }
+                    }
+                })
+            }
+            Route::Feed => self.with_connection(route, Access::Read, |db_connection| match recent_messages(FEED_ITEMS, db_connection) {
+                Ok(messages) => {
+                    let payload = json_feed(&messages).to_string();
+                    let response = Response::new()
+                        .with_header(ContentLength(payload.len() as u64))
+                        .with_header(ContentType(JSON_FEED_CONTENT_TYPE.parse().unwrap()))
+                        .with_body(payload);
+                    futureOk(response)
+                }
+                Err(error) => {
+                    error!("Error query Db: {}", error);
+                    futureOk(Response::new().with_status(StatusCode::InternalServerError))
+                }
+            }),
+            Route::Favicon if self.state.config.serve_favicon => Box::new(favicon_response()),
+            Route::RobotsTxt => Box::new(robots_txt_response(&self.state.config.robots_txt)),
+            Route::Landing => Box::new(landing_page_response(self.state.config.landing_page.as_ref().map(String::as_str))),
+            Route::Favicon | Route::NotFound => Box::new(futureOk(Response::new().with_status(StatusCode::NotFound))),
+        }
+    }
+
+    /// Runs the list query, or joins an identical one that is still running.
+    fn shared_list(&self, route: Route, message_query: MessageQuery, render_options: RenderOptions) -> ResponseFuture {
+        let key = format!("{:?}", message_query);
+        let service = self.clone();
+        let flight = SingleFlight::run(&self.list_flights, key, move || {
+            Box::new(service.connection(route, Access::Read).and_then(move |db_connection| {
+                query_db(message_query, &service.state.config, &db_connection)
+                    .ok_or_else(|| ServiceError::Internal(String::from("service error")))
+            }))
+        });
+        Box::new(flight.then(move |result| match result {
+            Ok(messages) => match render_list_response(&messages, &render_options, None) {
+                Ok(response) => futureOk(response),
+                Err(error) => make_service_error_response(&error),
+            },
+            Err(error) => make_service_error_response(&error),
+        }))
+    }
+
+    /// How messages are rendered in `format` for `request`, with the stored client details
+    /// if it carries the admin token.
+    fn render_options(&self, request: &Request, format: ResponseFormat) -> RenderOptions {
+        RenderOptions {
+            format,
+            show_meta: is_admin(request.headers(), &self.state.config.admin_token),
+            field_case: self.state.config.json_field_case,
+            allow_safe_html: self.state.config.allow_safe_html,
+            time_display: self.state.config.time_display.clone(),
+            htmx_list_url: None,
+        }
+    }
+
+    /// Checks a connection out of the pool for `route` once the scheduler grants `access` a slot.
+    /// With `CB_FAILURE_THRESHOLD` an open circuit fails the checkout right away, and
+    /// whether the database could be reached is reported to the breaker.
+    fn connection(&self, route: Route, access: Access) -> Box<dyn Future<Item=ScheduledConnection, Error=ServiceError>> {
+        if let Some(ref breaker) = self.state.circuit_breaker {
+            if let Err(retry_after) = breaker.allow() {
+                return Box::new(futureErr(ServiceError::CircuitOpen(retry_after)));
             }
-            _ => Box::new(futureOk(Response::new().with_status(StatusCode::NotFound))),
         }
+        let state = self.state.clone();
+        let leak_detector = self.state.leak_detector.clone();
+        let max_queries = self.state.config.max_queries_per_request;
+        let strict = self.state.config.max_queries_strict;
+        let requested_at = Instant::now();
+        Box::new(
+            CheckoutScheduler::acquire(&self.state.scheduler, access, &self.handle).and_then(move |permit| {
+                let connection = checkout(&state.pool);
+                if let Some(ref metrics) = state.pool_metrics {
+                    metrics.observe_checkout_wait(requested_at.elapsed());
+                }
+                if let Some(ref breaker) = state.circuit_breaker {
+                    match connection {
+                        Ok(_) => breaker.record_success(),
+                        Err(ServiceError::DbUnavailable(_)) => breaker.record_failure(),
+                        // a busy pool is no sign of a failing database
+                        Err(_) => {}
+                    }
+                }
+                let connection = connection?;
+                connection.start_request(max_queries.map(|max_queries| QueryBudget {
+                    max_queries,
+                    strict,
+                    context: format!("route {}", route.name()),
+                }));
+                let leak_guard = leak_detector.map(|detector| {
+                    LeakDetector::track(&detector, format!("route {} ({:?})", route.name(), access))
+                });
+                Ok(ScheduledConnection::new(connection, permit, leak_guard))
+            }),
+        )
+    }
+
+    /// Runs a synchronous handler with a scheduled connection, answering with the
+    /// checkout error if there is none. The connection is released when `handler` returns.
+    fn with_connection<F>(&self, route: Route, access: Access, handler: F) -> ResponseFuture
+        where F: FnOnce(&CountingConnection) -> FutureResult<Response, hyper::Error> + 'static {
+        Box::new(self.connection(route, access).then(move |connection| match connection {
+            Ok(connection) => handler(&connection),
+            Err(error) => make_service_error_response(&error),
+        }))
     }
 }
 
-/// https://juejin.im/post/5c7a3777f265da2dd773fc38
-fn connect_to_db() -> Option<PgConnection> {
-    // write .env to sysytem path
-    dotenv().ok();
+/// The `limit` newest public messages, for `/feed.json`.
+fn recent_messages(limit: i64, db_connection: &CountingConnection) -> QueryResult<Vec<Message>> {
+    use crate::schema::messages;
+    messages::table
+        .filter(messages::visibility.eq(VISIBILITY_PUBLIC))
+        .order((messages::timestamp.desc(), messages::id.desc()))
+        .limit(limit)
+        .load::<Message>(db_connection)
+        .and_then(|messages| resolve_contents(messages, db_connection))
+}
+
+/// The text of the `window` newest public messages, for `/stats/words`.
+fn recent_message_texts(window: i64, db_connection: &CountingConnection) -> QueryResult<Vec<String>> {
+    use crate::schema::messages;
+    messages::table
+        .select(message_text())
+        .filter(messages::visibility.eq(VISIBILITY_PUBLIC))
+        .order((messages::timestamp.desc(), messages::id.desc()))
+        .limit(window)
+        .load::<String>(db_connection)
+}
+
+/// Loads one message; with `count_view` its view counter is bumped in the same
+/// `UPDATE .. RETURNING`, so concurrent views are never lost.
+/// Private messages are only found with `include_private`.
+fn query_message(id: i32, count_view: bool, include_private: bool, db_connection: &CountingConnection) -> QueryResult<Option<Message>> {
+    use crate::schema::messages;
+    let visibilities = shown_visibilities(include_private);
+    if count_view {
+        diesel::update(messages::table.filter(messages::id.eq(id)).filter(messages::visibility.eq_any(visibilities)))
+            .set(messages::views.eq(messages::views + 1))
+            .get_result::<Message>(db_connection)
+            .optional()?
+    } else {
+        messages::table
+            .filter(messages::id.eq(id))
+            .filter(messages::visibility.eq_any(visibilities))
+            .first::<Message>(db_connection)
+            .optional()?
+    }.map(|message| resolve_content(message, db_connection)).transpose()
+}
 
-    let database_url = env::var("DATABASE_URL").unwrap_or(String::from(DEFAULT_DATABASE_URL));
-    match PgConnection::establish(&database_url) {
-        Ok(connection) => Some(connection),
+fn query_db_by_ids(ids: &[i32], include_private: bool, db_connection: &CountingConnection) -> Option<Vec<Message>> {
+    use crate::schema::messages;
+    match messages::table
+        .filter(messages::id.eq_any(ids))
+        .filter(messages::visibility.eq_any(shown_visibilities(include_private)))
+        .load::<Message>(db_connection)
+        .and_then(|messages| resolve_contents(messages, db_connection)) {
+        Ok(result) => Some(result),
         Err(error) => {
-            error!("Error connection to database {}", error.description());
+            error!("Error query Db: {}", error);
             None
         }
     }
 }
 
-fn query_db(time_range: TimeRange, db_connection: &PgConnection) -> Option<Vec<Message>> {
-    use crate::schema::messages;
-    let TimeRange { before, after } = time_range;
-    let query_result = match (before, after) {
-        (Some(before), Some(after)) => {
-            messages::table
-                .filter(messages::timestamp.lt(before as i64))
-                .filter(messages::timestamp.gt(after as i64))
-                .load::<Message>(db_connection)
-        }
-        (Some(before), _) => {
-            messages::table
-                .filter(messages::timestamp.lt(before as i64))
-                .load::<Message>(db_connection)
-        }
-        (_, Some(after)) => {
-            messages::table
-                .filter(messages::timestamp.gt(after as i64))
-                .load::<Message>(db_connection)
-        }
-        _ => {
-            messages::table.load::<Message>(db_connection)
-        }
-    };
+/// Pinned messages first, then oldest first like the `Range` slices, `id` ordering messages
+/// with the same timestamp so repeated queries list them in the same order.
+fn query_db(message_query: MessageQuery, config: &Config, db_connection: &CountingConnection) -> Option<Vec<Message>> {
+    let query_result = ordered_list_query(&message_query, config)
+        .load::<Message>(db_connection)
+        .and_then(|messages| resolve_contents(messages, db_connection));
 
     match query_result {
         Ok(result) => Some(result),
@@ -121,124 +985,2812 @@ fn query_db(time_range: TimeRange, db_connection: &PgConnection) -> Option<Vec<M
     }
 }
 
-fn write_to_db(new_message: NewMessage, db_connection: &PgConnection) -> FutureResult<i64, hyper::Error> {
-    use crate::schema::messages;
-    let timestamp = diesel::insert_into(messages::table)
-        .values(&new_message)
-        .returning(messages::timestamp)
-        .get_result(db_connection);
-    match timestamp {
-        Ok(timestamp) => futures::future::ok(timestamp),
+/// The plan of the list query for `message_query`, as `text/plain`.
+fn make_explain_response(
+    message_query: &MessageQuery,
+    config: &Config,
+    db_connection: &CountingConnection,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    match explain_analyze(ordered_list_query(message_query, config), db_connection) {
+        Ok(plan) => futureOk(
+            Response::new()
+                .with_header(ContentLength(plan.len() as u64))
+                .with_header(ContentType::plaintext())
+                .with_body(plan),
+        ),
         Err(error) => {
-            error!("Error writing to database: {}", error.description());
-            futures::future::err(hyper::Error::from(IoError::new(IoErrorKind::Other, "service error")))
+            error!("Error query Db: {}", error);
+            futureOk(Response::new().with_status(StatusCode::InternalServerError))
         }
     }
 }
 
-fn parse_form(form_chunk: Chunk) -> FutureResult<NewMessage, hyperError> {
-    let mut form = form_urlencoded::parse(form_chunk.as_ref())
-        .into_owned()
-        .collect::<HashMap<String, String>>();
-    if let Some(message) = form.remove("message") {
-        let username = form.remove("username").unwrap_or(String::from("anonymous"));
-        futureOk(NewMessage {
-            username,
-            message,
-        })
-    } else {
-        futureErr(hyperError::from(IoError::new(
-            IoErrorKind::InvalidInput,
-            "Missing field message",
-        )))
+/// The list query: `sample` random messages, or all of them pinned first, then by timestamp and id.
+fn ordered_list_query<'a>(message_query: &MessageQuery, config: &Config) -> crate::schema::messages::BoxedQuery<'a, Pg> {
+    use crate::schema::messages;
+    let query = filtered_messages(message_query, config);
+    match message_query.sample {
+        // ORDER BY random() reads and sorts every matching row, hence the cap on `sample`
+        Some(sample) => query.order(random).limit(sample),
+        None => query.order((messages::pinned.desc(), messages::timestamp.asc(), messages::id.asc())),
     }
 }
 
-fn make_post_response(result: Result<i64, hyperError>) -> FutureResult<hyper::Response, hyperError> {
-    match result {
-        Ok(timestamp) => {
-            let payload = json!({"timestamp": timestamp}).to_string();
-            let response = Response::new()
-                .with_header(ContentLength(payload.len() as u64))
-                .with_header(ContentType::json())
-                .with_body(payload);
-            debug!("{:?}", response);
-            futureOk(response)
+/// `limit` messages from `offset` on, in the list's stable order so consecutive ranges don't overlap.
+fn query_db_slice(
+    message_query: &MessageQuery,
+    config: &Config,
+    offset: i64,
+    limit: i64,
+    db_connection: &CountingConnection,
+) -> Option<Vec<Message>> {
+    use crate::schema::messages;
+    match filtered_messages(message_query, config)
+        .order((messages::pinned.desc(), messages::timestamp.asc(), messages::id.asc()))
+        .offset(offset)
+        .limit(limit)
+        .load::<Message>(db_connection)
+        .and_then(|messages| resolve_contents(messages, db_connection)) {
+        Ok(result) => Some(result),
+        Err(error) => {
+            error!("Error query Db: {}", error);
+            None
         }
-        Err(error) => make_error_response(error.description()),
     }
 }
 
-fn make_error_response(error_message: &str) -> FutureResult<hyper::Response, hyper::Error> {
-    let payload = json!({"error": error_message}).to_string();
-    let response = Response::new()
-        .with_status(StatusCode::InternalServerError)
-        .with_header(ContentLength(payload.len() as u64))
-        .with_header(ContentType::json())
-        .with_body(payload);
-    debug!("{:?}", response);
-    futures::future::ok(response)
+/// The number of messages the list would match, `sample` aside, in one `COUNT(*)`.
+fn count_db(message_query: &MessageQuery, config: &Config, db_connection: &CountingConnection) -> Option<i64> {
+    use diesel::dsl::count_star;
+    match filtered_messages(message_query, config)
+        .select(count_star())
+        .first::<i64>(db_connection) {
+        Ok(count) => Some(count),
+        Err(error) => {
+            error!("Error query Db: {}", error);
+            None
+        }
+    }
 }
 
+/// The list filters of `message_query` as one boxed query, shared by the list and its count.
+fn filtered_messages<'a>(message_query: &MessageQuery, config: &Config) -> crate::schema::messages::BoxedQuery<'a, Pg> {
+    use crate::schema::messages;
+    let mut query = messages::table.into_boxed();
+    if !message_query.include_private {
+        query = query.filter(messages::visibility.eq(VISIBILITY_PUBLIC));
+    }
+    if let Some(before) = message_query.before {
+        query = query.filter(messages::timestamp.lt(before));
+    }
+    if let Some(after) = message_query.after {
+        query = query.filter(messages::timestamp.gt(after));
+    }
+    if let Some(ref timestamps) = message_query.timestamps {
+        query = query.filter(messages::timestamp.eq_any(timestamps.clone()));
+    }
+    if let Some(ref username) = message_query.username {
+        query = query.filter(messages::username.eq(username.clone()));
+    }
+    if let Some(ref q) = message_query.q {
+        let pattern = format!("%{}%", escape_like(q));
+        query = if config.search_case_insensitive {
+            query.filter(message_text().ilike(pattern))
+        } else {
+            query.filter(message_text().like(pattern))
+        };
+    }
+    if let Some(min_len) = message_query.min_len {
+        query = query.filter(char_length(message_text()).ge(min_len));
+    }
+    if let Some(max_len) = message_query.max_len {
+        query = query.filter(char_length(message_text()).le(max_len));
+    }
+    if let Some(has_emoji) = message_query.has_emoji {
+        query = query.filter(message_has_emoji(has_emoji));
+    }
+    query
+}
 
-struct TimeRange {
-    before: Option<i64>,
-    after: Option<i64>,
+/// The visibilities a reader sees, admins see private messages too.
+pub fn shown_visibilities(include_private: bool) -> Vec<&'static str> {
+    if include_private {
+        vec![VISIBILITY_PUBLIC, VISIBILITY_PRIVATE]
+    } else {
+        vec![VISIBILITY_PUBLIC]
+    }
 }
 
-fn parse_query(query: &str) -> Result<TimeRange, String> {
-    let args = form_urlencoded::parse(&query.as_bytes())
-        .into_owned()
-        .collect::<HashMap<String, String>>();
-    let before = args.get("before").map(|value| value.parse::<i64>());
-    if let Some(ref result) = before {
-        if let Err(ref error) = *result {
-            return Err(format!("Error parsing 'before: {}", error));
+/// Escapes the LIKE wildcards so the search term only matches literally;
+/// backslash is the default LIKE/ILIKE escape character in Postgres.
+fn escape_like(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if c == '\\' || c == '%' || c == '_' {
+            escaped.push('\\');
         }
+        escaped.push(c);
     }
+    escaped
+}
+
+#[derive(Clone, Copy, Debug)]
+enum InsertOutcome {
+    /// Stored, with its timestamp.
+    Inserted(i64),
+    /// In the write buffer, stored once the database is back.
+    Queued,
+}
 
-    let after = args.get("after").map(|value| value.parse::<i64>());
-    if let Some(ref result) = after {
-        if let Err(ref error) = *result {
-            return Err(format!("Error parsing 'after': {}", error));
+/// With `WRITE_BUFFER_PATH` an insert that got no connection because the database is
+/// unreachable is queued instead of failing. Failures after the checkout are not
+/// queued: the insert may have been committed.
+fn queue_write(
+    new_message: NewMessage,
+    audit: Option<AuditContext>,
+    error: ServiceError,
+    write_buffer: Option<&WriteBuffer>,
+) -> FutureResult<InsertOutcome, ServiceError> {
+    let write_buffer = match (write_buffer, &error) {
+        (Some(write_buffer), &ServiceError::DbUnavailable(_)) | (Some(write_buffer), &ServiceError::CircuitOpen(_)) => write_buffer,
+        _ => return futureErr(error),
+    };
+    let buffered = BufferedInsert {
+        new_message,
+        audit_client: audit.map(|audit| audit.client),
+    };
+    match write_buffer.enqueue(&buffered) {
+        Ok(true) => {
+            info!("Database unreachable, queued insert of {}", buffered.new_message.username);
+            futureOk(InsertOutcome::Queued)
+        }
+        Ok(false) => {
+            warn!("Write buffer is full, refusing insert: {}", error);
+            futureErr(error)
+        }
+        Err(io_error) => {
+            error!("Error queuing insert: {}", io_error);
+            futureErr(error)
         }
     }
-    Ok(TimeRange {
-        before: before.map(|b| b.unwrap()),
-        after: after.map(|b| b.unwrap()),
-    })
 }
 
-fn make_get_response(messages: Option<Vec<Message>>) -> FutureResult<hyper::Response, hyper::Error> {
-    let response = match messages {
-        Some(messages) => {
-            let body = render_html(messages);
-            Response::new()
-                .with_header(ContentLength(body.len() as u64))
-                .with_body(body)
-        }
-        None => Response::new().with_status(StatusCode::InternalServerError),
+/// Pause between two attempts to replay the write buffer.
+const WRITE_BUFFER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Writes the inserts `WRITE_BUFFER_PATH` queued, right away to replay what an earlier
+/// run left and then every `WRITE_BUFFER_RETRY_INTERVAL`. Meant to run on its own thread.
+pub fn replay_write_buffer(state: &ServiceState) {
+    let write_buffer = match state.write_buffer {
+        Some(ref write_buffer) => write_buffer,
+        None => return,
     };
-    debug!("{:?}", response);
-    futures::future::ok(response)
+    loop {
+        let replayed = write_buffer.replay(|buffered| {
+            let db_connection = checkout_blocking(&state.scheduler, &state.pool, Access::Write).map_err(|error| error.to_string())?;
+            // audited like the request that queued it, if that was
+            let audit = buffered.audit_client.map(|client| AuditContext {
+                client,
+                operation: Route::Insert,
+            });
+            let written = write_to_db(buffered.new_message, audit, &state.config, state.secondary.as_ref(), &db_connection);
+            match written.wait() {
+                // refused like it would have been when it was sent, retrying can't help
+                Err(ServiceError::BadRequest(reason)) => {
+                    warn!("Dropping buffered insert: {}", reason);
+                    Ok(())
+                }
+                result => result.map(|_| ()).map_err(|error| error.to_string()),
+            }
+        });
+        match replayed {
+            Ok(0) => {}
+            Ok(replayed) => info!("Wrote {} buffered inserts", replayed),
+            Err(error) => error!("Error replaying the write buffer: {}", error),
+        }
+        thread::sleep(WRITE_BUFFER_RETRY_INTERVAL);
+    }
 }
 
-/// https://maud.lambda.xyz/partials.html
-fn render_html(messages: Vec<Message>) -> String {
-    (html! {
-        head {
-            title { "microservice" }
-            //style {"body { font-family: monospace }"}
-            meta charset="utf-8";
+/// Inserts the message and returns its database-assigned timestamp.
+///
+/// With `INSERT_RETURNING=auto` a database rejecting `RETURNING` is detected from its
+/// error message and the insert is retried as `INSERT` + `SELECT`; `INSERT_RETURNING=select`
+/// takes that path directly, which is how the fallback can be exercised against Postgres.
+///
+/// With `MAX_MESSAGES_PER_USER` the author's oldest messages beyond the limit are deleted
+/// in the same transaction as the insert.
+///
+/// With `DEDUP_CONTENT_STORAGE` the text goes to `message_contents`, shared by every
+/// message with the same text.
+///
+/// With `MESSAGE_SIGNING_KEY` the new row is signed, see `signing`.
+///
+/// With `audit` the insert is recorded in `audit_log`, in the same transaction.
+///
+/// With `secondary` the committed row, and the pruning, are then repeated there; see
+/// `SecondaryStore`.
+fn write_to_db(
+    mut new_message: NewMessage,
+    audit: Option<AuditContext>,
+    config: &Config,
+    secondary: Option<&SecondaryStore>,
+    db_connection: &CountingConnection,
+) -> FutureResult<i64, ServiceError> {
+    // the text is moved into `message_contents` by deduplication, but signed and replicated as such
+    let text = new_message.message.clone();
+    let inserted = db_connection.transaction(|| {
+        if config.dedup_content_storage {
+            store_content(&mut new_message, db_connection)?;
         }
-        body {
-            ul {
-                @for message in &messages {
-                    li {
-                        (message.username) " (" (message.timestamp) "): " (message.message)
-                    }
-                }
+        let (id, timestamp) = insert_message(&new_message, config.insert_returning, db_connection)?;
+        let signature = config.message_signing_key.as_ref().map(|key| sign(key, id, timestamp, &new_message.username, &text));
+        if let Some(ref signature) = signature {
+            sign_message(id, signature, db_connection)?;
+        }
+        if let Some(max_messages) = config.max_messages_per_user {
+            let pruned = prune_user_messages(&new_message.username, max_messages, db_connection)?;
+            if pruned > 0 {
+                debug!("Deleted {} old messages of {}", pruned, new_message.username);
             }
         }
-    }).into_string()
-}
\ No newline at end of file
+        if let Some(ref audit) = audit {
+            audit.record(Some(id), true, db_connection)?;
+        }
+        Ok((id, timestamp, signature))
+    });
+    if inserted.is_err() {
+        if let Some(ref audit) = audit {
+            audit.record_failure(db_connection);
+        }
+    }
+    if let Some(secondary) = secondary {
+        if let Ok((id, timestamp, ref signature)) = inserted {
+            let row = ReplicatedMessage {
+                id,
+                username: new_message.username,
+                message: text,
+                timestamp,
+                ip: new_message.ip,
+                user_agent: new_message.user_agent,
+                signature: signature.clone(),
+                visibility: new_message.visibility,
+            };
+            replicate_insert(row, config, secondary);
+        }
+    }
+    match inserted {
+        Ok((_, timestamp, _)) => futures::future::ok(timestamp),
+        Err(ref error) if is_returning_unsupported(error) => {
+            error!("Error writing to database: {}", error);
+            futures::future::err(ServiceError::Internal(String::from(
+                "database does not support INSERT ... RETURNING, set INSERT_RETURNING=select",
+            )))
+        }
+        // `username` is the only length-limited column a client writes
+        Err(ref error) if is_value_too_long(error) => futures::future::err(ServiceError::BadRequest(format!(
+            "username must be at most {} characters",
+            MAX_USERNAME_LEN
+        ))),
+        Err(error) => {
+            error!("Error writing to database: {}", error.description());
+            futures::future::err(ServiceError::Internal(String::from("service error")))
+        }
+    }
+}
+
+/// Inserts the message and returns the new row's id and timestamp.
+fn insert_message(
+    new_message: &NewMessage,
+    insert_returning: InsertReturning,
+    db_connection: &CountingConnection,
+) -> QueryResult<(i32, i64)> {
+    match insert_returning {
+        InsertReturning::Returning => insert_returning_row(new_message, db_connection),
+        InsertReturning::Select => insert_then_select_row(new_message, db_connection),
+        InsertReturning::Auto => match insert_returning_row(new_message, db_connection) {
+            Err(ref error) if is_returning_unsupported(error) => {
+                warn!("Database does not support RETURNING, falling back to SELECT: {}", error);
+                insert_then_select_row(new_message, db_connection)
+            }
+            result => result,
+        },
+    }
+}
+
+fn insert_returning_row(new_message: &NewMessage, db_connection: &CountingConnection) -> QueryResult<(i32, i64)> {
+    use crate::schema::messages;
+    // a savepoint, so a rejected RETURNING doesn't abort the surrounding transaction
+    db_connection.transaction(|| {
+        diesel::insert_into(messages::table)
+            .values(new_message)
+            .returning((messages::id, messages::timestamp))
+            .get_result(db_connection)
+    })
+}
+
+/// Reads the row back by the id the insert drew from the sequence, which `currval` reports
+/// for this session alone, whatever other sessions insert meanwhile.
+fn insert_then_select_row(new_message: &NewMessage, db_connection: &CountingConnection) -> QueryResult<(i32, i64)> {
+    use crate::schema::messages;
+    db_connection.transaction(|| {
+        diesel::insert_into(messages::table)
+            .values(new_message)
+            .execute(db_connection)?;
+        messages::table
+            .select((messages::id, messages::timestamp))
+            .filter(messages::id.eq(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                "currval(pg_get_serial_sequence('messages', 'id'))::int4",
+            )))
+            .first(db_connection)
+    })
+}
+
+/// Queues a committed insert for the secondary store, pruning the author's messages there too.
+fn replicate_insert(row: ReplicatedMessage, config: &Config, secondary: &SecondaryStore) {
+    use crate::schema::messages;
+    let max_messages_per_user = config.max_messages_per_user;
+    secondary.replicate("insert", move |db_connection| db_connection.transaction(|| {
+        diesel::insert_into(messages::table).values(&row).execute(db_connection)?;
+        if let Some(max_messages) = max_messages_per_user {
+            prune_user_messages(&row.username, max_messages, db_connection)?;
+        }
+        Ok(())
+    }));
+}
+
+/// What a client already has, sent to `POST /messages/sync`.
+#[derive(Deserialize)]
+struct SyncSnapshot {
+    /// Highest id the client has seen; newer messages are sent in full.
+    #[serde(default)]
+    last_id: i32,
+    /// Ids the client holds, of which the ones that are gone are reported.
+    #[serde(default)]
+    known_ids: Vec<i32>,
+}
+
+struct SyncDiff {
+    /// Oldest first, at most `MAX_PAGE_SIZE` of them.
+    added: Vec<Message>,
+    /// Whether messages beyond `added` are waiting for the next sync.
+    more: bool,
+    deleted: Vec<i32>,
+}
+
+fn parse_sync_snapshot(body: Vec<u8>) -> FutureResult<SyncSnapshot, ServiceError> {
+    let snapshot = match serde_json::from_slice::<SyncSnapshot>(&body) {
+        Ok(snapshot) => snapshot,
+        Err(error) => return futureErr(ServiceError::BadRequest(format!("Invalid sync snapshot: {}", error))),
+    };
+    if snapshot.known_ids.len() > MAX_SYNC_KNOWN_IDS {
+        return futureErr(ServiceError::BadRequest(format!(
+            "known_ids takes at most {} ids, got {}",
+            MAX_SYNC_KNOWN_IDS,
+            snapshot.known_ids.len()
+        )));
+    }
+    futureOk(snapshot)
+}
+
+/// Messages don't record their deletion, pruning with `MAX_MESSAGES_PER_USER` removes the
+/// rows, so the deleted messages are the known ids with no row left, or none the client
+/// may read anymore.
+fn sync_messages(snapshot: &SyncSnapshot, include_private: bool, db_connection: &CountingConnection) -> QueryResult<SyncDiff> {
+    use crate::schema::messages;
+    let mut added = messages::table
+        .filter(messages::id.gt(snapshot.last_id))
+        .filter(messages::visibility.eq_any(shown_visibilities(include_private)))
+        .order(messages::id.asc())
+        .limit(MAX_PAGE_SIZE + 1)
+        .load::<Message>(db_connection)?;
+    let more = added.len() as i64 > MAX_PAGE_SIZE;
+    added.truncate(MAX_PAGE_SIZE as usize);
+    let added = resolve_contents(added, db_connection)?;
+    let mut deleted = snapshot.known_ids.clone();
+    if !deleted.is_empty() {
+        let mut present = messages::table
+            .filter(messages::id.eq_any(&snapshot.known_ids[..]))
+            .filter(messages::visibility.eq_any(shown_visibilities(include_private)))
+            .select(messages::id)
+            .load::<i32>(db_connection)?;
+        present.sort();
+        deleted.sort();
+        deleted.dedup();
+        deleted.retain(|id| present.binary_search(id).is_err());
+    }
+    Ok(SyncDiff { added, more, deleted })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PinOutcome {
+    Updated,
+    NotFound,
+    /// `MAX_PINNED_MESSAGES` other messages are pinned already.
+    LimitReached,
+}
+
+/// Called inside a transaction: pins are serialized by an advisory lock held until it
+/// ends, so two requests can't both count room for one more pinned message.
+fn set_pinned(id: i32, pinned: bool, db_connection: &CountingConnection) -> QueryResult<PinOutcome> {
+    use crate::schema::messages;
+    use diesel::dsl::count_star;
+    if pinned {
+        diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+            .bind::<diesel::sql_types::BigInt, _>(PIN_LOCK_KEY)
+            .execute(db_connection)?;
+        let other_pinned = messages::table
+            .filter(messages::pinned.eq(true))
+            .filter(messages::id.ne(id))
+            .select(count_star())
+            .first::<i64>(db_connection)?;
+        if other_pinned >= MAX_PINNED_MESSAGES {
+            return Ok(PinOutcome::LimitReached);
+        }
+    }
+    let updated = diesel::update(messages::table.filter(messages::id.eq(id)))
+        .set(messages::pinned.eq(pinned))
+        .execute(db_connection)?;
+    Ok(if updated == 0 { PinOutcome::NotFound } else { PinOutcome::Updated })
+}
+
+/// Stores the signature of the message just inserted; id and timestamp are only known after the insert.
+fn sign_message(id: i32, signature: &str, db_connection: &CountingConnection) -> QueryResult<usize> {
+    use crate::schema::messages;
+    diesel::update(messages::table.filter(messages::id.eq(id)))
+        .set(messages::signature.eq(signature))
+        .execute(db_connection)
+}
+
+/// Deletes all but the `keep` most recent messages of `username`.
+fn prune_user_messages(username: &str, keep: i64, db_connection: &CountingConnection) -> QueryResult<usize> {
+    use crate::schema::messages;
+    let kept_ids = messages::table
+        .select(messages::id)
+        .filter(messages::username.eq(username))
+        .order((messages::timestamp.desc(), messages::id.desc()))
+        .limit(keep)
+        .for_update()
+        .load::<i32>(db_connection)?;
+    diesel::delete(
+        messages::table
+            .filter(messages::username.eq(username))
+            .filter(messages::id.ne_all(kept_ids)),
+    ).execute(db_connection)
+}
+
+fn is_returning_unsupported(error: &diesel::result::Error) -> bool {
+    match *error {
+        diesel::result::Error::DatabaseError(_, ref info) => info.message().contains("RETURNING"),
+        _ => false,
+    }
+}
+
+/// Postgres' string_data_right_truncation (22001); diesel doesn't expose the SQLSTATE,
+/// so it is recognised by its message like `is_returning_unsupported`.
+fn is_value_too_long(error: &diesel::result::Error) -> bool {
+    match *error {
+        diesel::result::Error::DatabaseError(_, ref info) => info.message().starts_with("value too long for type"),
+        _ => false,
+    }
+}
+
+/// Captures the client details to store with a new message, if enabled.
+fn request_meta(config: &Config, client: Option<IpAddr>, headers: &Headers) -> RequestMeta {
+    if !config.store_request_meta {
+        return RequestMeta::default();
+    }
+    RequestMeta {
+        ip: client.map(|ip| stored_ip(config, &ip)),
+        user_agent: headers.get::<UserAgent>().map(|user_agent| user_agent.to_string()),
+    }
+}
+
+/// Who `operation` is recorded for, with `AUDIT_LOG`.
+fn audit_context(config: &Config, client: Option<IpAddr>, operation: Route) -> Option<AuditContext> {
+    if !config.audit_log {
+        return None;
+    }
+    Some(AuditContext {
+        client: client.map(|ip| stored_ip(config, &ip)),
+        operation,
+    })
+}
+
+/// The client address as written to the database, hashed with `HASH_STORED_IP`.
+fn stored_ip(config: &Config, ip: &IpAddr) -> String {
+    if config.hash_stored_ip {
+        hash_ip(ip)
+    } else {
+        ip.to_string()
+    }
+}
+
+fn hash_ip(ip: &IpAddr) -> String {
+    Sha256::digest(ip.to_string().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_form(form_body: Vec<u8>, request_meta: RequestMeta) -> FutureResult<NewMessage, ServiceError> {
+    let mut form = form_urlencoded::parse(&form_body)
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    if let Some(message) = form.remove("message") {
+        let username = form.remove("username").unwrap_or(String::from("anonymous"));
+        let visibility = match form.remove("visibility") {
+            None => String::from(VISIBILITY_PUBLIC),
+            Some(ref visibility) if visibility == VISIBILITY_PUBLIC || visibility == VISIBILITY_PRIVATE => visibility.clone(),
+            Some(visibility) => {
+                return futureErr(ServiceError::BadRequest(format!(
+                    "visibility must be public or private, got '{}'",
+                    visibility
+                )));
+            }
+        };
+        futureOk(NewMessage {
+            username,
+            message,
+            ip: request_meta.ip,
+            user_agent: request_meta.user_agent,
+            content_hash: None,
+            visibility,
+        })
+    } else {
+        futureErr(ServiceError::BadRequest(String::from("Missing field message")))
+    }
+}
+
+/// Composes username and text to Unicode NFC, so visually identical strings are stored,
+/// compared and searched as the same string.
+fn normalize_nfc(mut new_message: NewMessage) -> NewMessage {
+    new_message.username = new_message.username.nfc().collect();
+    new_message.message = new_message.message.nfc().collect();
+    new_message
+}
+
+/// C0 controls and DEL, which can rewrite terminals and forge log lines; tab, newline and
+/// carriage return are ordinary whitespace in a message.
+fn is_unwanted_control(c: char) -> bool {
+    (c < '\u{20}' && c != '\t' && c != '\n' && c != '\r') || c == '\u{7f}'
+}
+
+fn apply_control_char_policy(mut new_message: NewMessage, policy: ControlCharPolicy) -> Result<NewMessage, ServiceError> {
+    match policy {
+        ControlCharPolicy::Strip => {
+            new_message.username.retain(|c| !is_unwanted_control(c));
+            new_message.message.retain(|c| !is_unwanted_control(c));
+            Ok(new_message)
+        }
+        ControlCharPolicy::Reject => {
+            let fields = [("username", &new_message.username), ("message", &new_message.message)];
+            for &(field, text) in fields.iter() {
+                if let Some(c) = text.chars().find(|&c| is_unwanted_control(c)) {
+                    return Err(ServiceError::BadRequest(format!(
+                        "{} contains the control character U+{:04X}",
+                        field, c as u32
+                    )));
+                }
+            }
+            Ok(new_message)
+        }
+    }
+}
+
+/// Gives every message with a zero timestamp the timestamp of the message inserted
+/// before it plus one, in id order, so imported runs keep their insertion order.
+/// Messages without a predecessor start at the oldest known timestamp, or now.
+fn backfill_timestamps(db_connection: &CountingConnection) -> QueryResult<usize> {
+    use crate::schema::messages;
+    use diesel::dsl::min;
+    db_connection.transaction(|| {
+        let zero_ids = messages::table
+            .select(messages::id)
+            .filter(messages::timestamp.eq(0))
+            .order(messages::id.asc())
+            .for_update()
+            .load::<i32>(db_connection)?;
+        if zero_ids.is_empty() {
+            return Ok(0);
+        }
+        let fallback = match messages::table
+            .select(min(messages::timestamp))
+            .filter(messages::timestamp.ne(0))
+            .first::<Option<i64>>(db_connection)? {
+            Some(oldest) => oldest,
+            None => unix_now(),
+        };
+        for id in &zero_ids {
+            // earlier rows are already filled in, so a run of zeros counts up one by one
+            let previous = messages::table
+                .select(messages::timestamp)
+                .filter(messages::id.lt(*id))
+                .order(messages::id.desc())
+                .first::<i64>(db_connection)
+                .optional()?;
+            let timestamp = previous.map(|previous| previous + 1).unwrap_or(fallback);
+            diesel::update(messages::table.filter(messages::id.eq(*id)))
+                .set(messages::timestamp.eq(timestamp))
+                .execute(db_connection)?;
+        }
+        Ok(zero_ids.len())
+    })
+}
+
+/// The `Idempotency-Key` header, if it is a non-empty string.
+fn idempotency_key(headers: &Headers) -> Option<String> {
+    let value = str::from_utf8(headers.get_raw("Idempotency-Key")?.one()?).ok()?.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(String::from(value))
+    }
+}
+
+/// `{"timestamp": ..}`, or with `plain_text` only the number; errors are always JSON.
+fn make_post_response(result: Result<InsertOutcome, ServiceError>, plain_text: bool) -> FutureResult<hyper::Response, hyperError> {
+    match result {
+        Ok(InsertOutcome::Queued) if plain_text => futureOk(
+            Response::new()
+                .with_status(StatusCode::Accepted)
+                .with_header(ContentLength("queued".len() as u64))
+                .with_header(ContentType::plaintext())
+                .with_body("queued"),
+        ),
+        Ok(InsertOutcome::Queued) => make_json_response(StatusCode::Accepted, json!({"status": "queued"}).to_string()),
+        Ok(InsertOutcome::Inserted(timestamp)) if plain_text => {
+            let body = timestamp.to_string();
+            futureOk(
+                Response::new()
+                    .with_header(ContentLength(body.len() as u64))
+                    .with_header(ContentType::plaintext())
+                    .with_body(body),
+            )
+        }
+        Ok(InsertOutcome::Inserted(timestamp)) => {
+            let payload = json!({"timestamp": timestamp}).to_string();
+            make_json_response(StatusCode::Ok, payload)
+        }
+        Err(error) => make_service_error_response(&error),
+    }
+}
+
+fn make_error_response(status: StatusCode, error_message: &str) -> FutureResult<hyper::Response, hyper::Error> {
+    let payload = json!({"error": error_message}).to_string();
+    make_json_response(status, payload)
+}
+
+/// Holds the response back until `floor` has passed since the request was dispatched, so
+/// found and not found take about as long. Slower responses are not delayed further.
+fn pad_response_time(response: ResponseFuture, floor: Duration, handle: &Handle) -> ResponseFuture {
+    let floor = match Timeout::new(floor, handle) {
+        Ok(floor) => floor,
+        Err(error) => {
+            error!("Error creating response time floor: {}", error);
+            return response;
+        }
+    };
+    // a broken timer only loses the padding, never the response
+    let floor = floor.then(|_| Ok::<(), hyper::Error>(()));
+    Box::new(response.join(floor).map(|(response, _)| response))
+}
+
+fn make_service_error_response(error: &ServiceError) -> FutureResult<hyper::Response, hyper::Error> {
+    if let ServiceError::Serialization(ref cause) = *error {
+        error!("Could not serialize response: {}", cause);
+    }
+    let payload = match error.code() {
+        Some(code) => json!({"error": error.to_string(), "code": code}),
+        None => json!({"error": error.to_string()}),
+    }.to_string();
+    let response = Response::new()
+        .with_status(error.status())
+        .with_header(ContentLength(payload.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(payload);
+    let response = match error.retry_after() {
+        Some(seconds) => response.with_header(RetryAfter::Delay(Duration::from_secs(seconds))),
+        None => response,
+    };
+    debug!("{:?}", response);
+    futures::future::ok(response)
+}
+
+fn make_json_response(status: StatusCode, payload: String) -> FutureResult<hyper::Response, hyper::Error> {
+    let response = Response::new()
+        .with_status(status)
+        .with_header(ContentLength(payload.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(payload);
+    debug!("{:?}", response);
+    futures::future::ok(response)
+}
+
+#[derive(Debug, Default)]
+struct MessageQuery {
+    before: Option<i64>,
+    after: Option<i64>,
+    /// Number of randomly selected messages to return, at most `MAX_SAMPLE_SIZE`.
+    sample: Option<i64>,
+    /// Only messages created at exactly one of these instants.
+    timestamps: Option<Vec<i64>>,
+    /// Only messages of this user.
+    username: Option<String>,
+    /// Substring the message text must contain.
+    q: Option<String>,
+    /// Bounds on the message length in characters, both inclusive.
+    min_len: Option<i32>,
+    max_len: Option<i32>,
+    /// Only messages with, or without, an emoji.
+    has_emoji: Option<bool>,
+    /// Private messages match too, for admins; not a query parameter.
+    include_private: bool,
+}
+
+impl MessageQuery {
+    /// Whether `before` and `after` rule out every message, see `is_empty_range`.
+    fn matches_nothing(&self) -> bool {
+        match (self.before, self.after) {
+            (Some(before), Some(after)) => is_empty_range(before, after),
+            _ => false,
+        }
+    }
+}
+
+/// Both bounds are exclusive, so they leave no timestamp unless `before` exceeds `after` by two.
+fn is_empty_range(before: i64, after: i64) -> bool {
+    before <= after.saturating_add(1)
+}
+
+/// `q` shorter than `MIN_SEARCH_LEN` characters is refused, such a substring matches
+/// nearly every row and the `LIKE '%q%'` scan reads the whole table for it.
+///
+/// With `STRICT_RANGE` bounds no timestamp lies between are refused too.
+fn parse_query(query: &str, config: &Config) -> Result<MessageQuery, String> {
+    let min_search_len = config.min_search_len;
+    let args = form_urlencoded::parse(&query.as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let before = parse_arg::<i64>(&args, "before")?;
+    let after = parse_arg::<i64>(&args, "after")?;
+    // `since` is a friendlier `after`; with both the later bound wins
+    let after = match args.get("since") {
+        Some(since) => {
+            let since = unix_now() - parse_relative_window(since)?;
+            Some(after.map_or(since, |after| cmp::max(after, since)))
+        }
+        None => after,
+    };
+    let sample = parse_arg::<u32>(&args, "sample")?
+        .map(|sample| cmp::min(i64::from(sample), MAX_SAMPLE_SIZE));
+    let timestamps = match args.get("timestamps") {
+        Some(timestamps) => Some(parse_timestamp_list(timestamps)?),
+        None => None,
+    };
+    let username = args.get("username").filter(|username| !username.is_empty()).cloned();
+    let q = args.get("q").filter(|q| !q.is_empty()).cloned();
+    if let Some(ref q) = q {
+        if q.chars().count() < min_search_len {
+            return Err(format!("'q' must be at least {} characters long", min_search_len));
+        }
+    }
+    let min_len = parse_length_arg(&args, "min_len")?;
+    let max_len = parse_length_arg(&args, "max_len")?;
+    let has_emoji = parse_arg::<bool>(&args, "has_emoji")?;
+    if let (Some(before), Some(after)) = (before, after) {
+        if config.strict_range && is_empty_range(before, after) {
+            return Err(format!("'before' ({}) must be more than one second after 'after' ({})", before, after));
+        }
+    }
+    if let (Some(min_len), Some(max_len)) = (min_len, max_len) {
+        if min_len > max_len {
+            return Err(format!("'min_len' ({}) must not exceed 'max_len' ({})", min_len, max_len));
+        }
+    }
+    Ok(MessageQuery {
+        before,
+        after,
+        sample,
+        timestamps,
+        username,
+        q,
+        min_len,
+        max_len,
+        has_emoji,
+        include_private: false,
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ItemRange {
+    first: i64,
+    /// Inclusive.
+    last: i64,
+}
+
+/// Reads `Range: items=first-last`. Other units and malformed ranges are ignored,
+/// as RFC 7233 asks, and answered with the whole list.
+fn parse_item_range(headers: &Headers) -> Option<ItemRange> {
+    let value = str::from_utf8(headers.get_raw("Range")?.one()?).ok()?.trim();
+    if !value.starts_with("items=") {
+        return None;
+    }
+    let mut bounds = value["items=".len()..].splitn(2, '-');
+    let first = bounds.next()?.trim().parse::<u32>().ok()?;
+    let last = bounds.next()?.trim().parse::<u32>().ok()?;
+    if last < first {
+        return None;
+    }
+    Some(ItemRange {
+        first: i64::from(first),
+        last: i64::from(last),
+    })
+}
+
+/// Keeps `items` within `MAX_OFFSET`: Postgres reads and discards every row before an
+/// `OFFSET`, so deep pages are refused, or with `CAP_OFFSET` moved back to the deepest one.
+fn limit_offset(items: ItemRange, max_offset: Option<i64>, cap_offset: bool) -> Result<ItemRange, String> {
+    let max_offset = match max_offset {
+        Some(max_offset) if items.first > max_offset => max_offset,
+        _ => return Ok(items),
+    };
+    if !cap_offset {
+        return Err(format!(
+            "Range starts at {}, past MAX_OFFSET={}; page deeper with /timeline and its next_cursor",
+            items.first, max_offset
+        ));
+    }
+    Ok(ItemRange {
+        first: max_offset,
+        last: max_offset + (items.last - items.first),
+    })
+}
+
+/// `?page=&per_page=`, numbered pages of the list for a pager.
+#[derive(Clone, Copy, Debug)]
+struct Page {
+    /// From 1.
+    page: i64,
+    per_page: i64,
+}
+
+/// Reads `page` and `per_page`, `None` without either. `per_page` defaults to
+/// `DEFAULT_PAGE_SIZE` and is capped at `MAX_PAGE_SIZE` like `Range` slices, and pages
+/// starting past `MAX_OFFSET` are refused, or with `CAP_OFFSET` moved back to the last
+/// page starting within it.
+fn parse_page(query: Option<&str>, max_offset: Option<i64>, cap_offset: bool) -> Result<Option<Page>, String> {
+    let args = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let page = parse_arg::<i64>(&args, "page")?;
+    let per_page = parse_arg::<i64>(&args, "per_page")?;
+    if page.is_none() && per_page.is_none() {
+        return Ok(None);
+    }
+    let page = page.unwrap_or(1);
+    if page < 1 {
+        return Err(format!("'page' starts at 1, got {}", page));
+    }
+    let per_page = cmp::max(1, cmp::min(per_page.unwrap_or(DEFAULT_PAGE_SIZE), MAX_PAGE_SIZE));
+    let max_page = match max_offset {
+        Some(max_offset) => max_offset / per_page + 1,
+        None => return Ok(Some(Page { page, per_page })),
+    };
+    if page <= max_page {
+        Ok(Some(Page { page, per_page }))
+    } else if cap_offset {
+        Ok(Some(Page { page: max_page, per_page }))
+    } else {
+        Err(format!(
+            "page {} starts past MAX_OFFSET={}; page deeper with /timeline and its next_cursor",
+            page,
+            max_offset.unwrap_or(0)
+        ))
+    }
+}
+
+/// Parses a window like `30s`, `15m`, `1h` or `7d` into seconds.
+fn parse_relative_window(window: &str) -> Result<i64, String> {
+    let invalid = || format!("Error parsing 'since': expected a number with unit s, m, h or d, got '{}'", window);
+    if window.len() < 2 || !window.is_char_boundary(window.len() - 1) {
+        return Err(invalid());
+    }
+    let (amount, unit) = window.split_at(window.len() - 1);
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    if !amount.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    amount
+        .parse::<i64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(unit_seconds))
+        .ok_or_else(invalid)
+}
+
+/// Parses a comma-separated list of timestamps, at most `MAX_TIMESTAMPS` of them.
+fn parse_timestamp_list(timestamps: &str) -> Result<Vec<i64>, String> {
+    let timestamps = timestamps
+        .split(',')
+        .map(str::trim)
+        .filter(|timestamp| !timestamp.is_empty())
+        .map(|timestamp| {
+            timestamp
+                .parse::<i64>()
+                .map_err(|error| format!("Error parsing timestamp '{}': {}", timestamp, error))
+        })
+        .collect::<Result<Vec<i64>, String>>()?;
+    if timestamps.len() > MAX_TIMESTAMPS {
+        return Err(format!("At most {} timestamps can be requested at once", MAX_TIMESTAMPS));
+    }
+    Ok(timestamps)
+}
+
+/// Parses `?ids=1,2,3`, at most `MAX_BATCH_IDS` of them.
+fn parse_id_list(query: Option<&str>) -> Result<Vec<i32>, String> {
+    let args = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let ids = match args.get("ids") {
+        Some(ids) => ids,
+        None => return Err(String::from("Missing parameter 'ids'")),
+    };
+    let ids = ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse::<i32>().map_err(|error| format!("Error parsing id '{}': {}", id, error)))
+        .collect::<Result<Vec<i32>, String>>()?;
+    if ids.len() > MAX_BATCH_IDS {
+        return Err(format!("At most {} ids can be requested at once", MAX_BATCH_IDS));
+    }
+    Ok(ids)
+}
+
+/// Seconds since the epoch, the unit of `messages.timestamp`.
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// `true` for `?name=true` or `?name=1`.
+fn query_flag(query: Option<&str>, name: &str) -> bool {
+    match query {
+        Some(query) => form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == name && (value == "true" || value == "1")),
+        None => false,
+    }
+}
+
+fn parse_length_arg(args: &HashMap<String, String>, name: &str) -> Result<Option<i32>, String> {
+    match parse_arg::<i32>(args, name)? {
+        Some(length) if length < 0 => Err(format!("'{}' must not be negative", name)),
+        length => Ok(length),
+    }
+}
+
+pub fn parse_arg<T>(args: &HashMap<String, String>, name: &str) -> Result<Option<T>, String>
+    where T: FromStr,
+          T::Err: fmt::Display {
+    match args.get(name) {
+        Some(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|error| format!("Error parsing '{}': {}", name, error)),
+        None => Ok(None),
+    }
+}
+
+/// How a list of messages is rendered for a particular request.
+struct RenderOptions {
+    format: ResponseFormat,
+    /// Include the stored client details, for admin requests only.
+    show_meta: bool,
+    field_case: FieldCase,
+    /// Render the allowlisted tags in message text as HTML instead of escaping them.
+    allow_safe_html: bool,
+    time_display: TimeDisplay,
+    /// For `HX-Request`s, the list URL the pager fetches more pages from; the HTML
+    /// is then only the list and its pager instead of a whole page.
+    htmx_list_url: Option<String>,
+}
+
+/// Marks an answer given without querying, because `before` and `after` leave no timestamp.
+fn with_empty_range_hint(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .set_raw("X-Query-Hint", "empty range: before must exceed after by at least 2");
+    response
+}
+
+fn make_get_response(messages: Option<Vec<Message>>, options: &RenderOptions) -> FutureResult<hyper::Response, hyper::Error> {
+    match messages {
+        Some(messages) => match render_list_response(&messages, options, None) {
+            Ok(response) => futures::future::ok(response),
+            Err(error) => make_service_error_response(&error),
+        },
+        None => futures::future::ok(Response::new().with_status(StatusCode::InternalServerError)),
+    }
+}
+
+/// The list as JSON, NDJSON, protobuf or HTML; it advertises `Accept-Ranges: items` for `Range` paging.
+/// `next_page` is the range an HTMX fragment's pager asks for, if there is more.
+fn render_list_response(
+    messages: &[Message],
+    options: &RenderOptions,
+    next_page: Option<ItemRange>,
+) -> Result<Response, ServiceError> {
+    let (content_type, body) = match (options.format, &options.htmx_list_url) {
+        (ResponseFormat::Json, _) => (ContentType::json(), render_json(messages, options)?.to_string().into_bytes()),
+        (ResponseFormat::Ndjson, _) => (ContentType(NDJSON_CONTENT_TYPE.parse().unwrap()), render_ndjson(messages, options)?),
+        (ResponseFormat::Protobuf, _) => (
+            ContentType(PROTOBUF_CONTENT_TYPE.parse().unwrap()),
+            encode_message_list(messages, options.show_meta),
+        ),
+        (_, Some(list_url)) => (
+            ContentType::html(),
+            render_html_fragment(messages, options, list_url, next_page).into_bytes(),
+        ),
+        _ => (ContentType::html(), render_html(messages, options).into_bytes()),
+    };
+    let mut response = Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(content_type)
+        .with_body(body);
+    response.headers_mut().set_raw("Accept-Ranges", "items");
+    debug!("{:?}", response);
+    Ok(response)
+}
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// The last line of an NDJSON list that failed part way.
+const NDJSON_FAILURE_LINE: &[u8] = b"{\"error\":\"stream_failed\"}\n";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ListFraming {
+    /// One JSON object per line.
+    Ndjson,
+    /// A single JSON array, the same document the buffered list sends.
+    JsonArray,
+}
+
+/// Streams the list `EXPORT_BATCH_SIZE` messages per chunk (a `sample` in one go), in
+/// the buffered list's order, framed as NDJSON or as a JSON array. Should the database
+/// fail part way the stream is aborted, an NDJSON stream after a `{"error":"stream_failed"}` line.
+fn make_streamed_list_response(
+    framing: ListFraming,
+    message_query: MessageQuery,
+    state: Arc<ServiceState>,
+    options: RenderOptions,
+    connection: ScheduledConnection,
+    handle: &Handle,
+) -> Response {
+    let batch_size = match message_query.sample {
+        Some(_) => None,
+        None => Some(EXPORT_BATCH_SIZE as usize),
+    };
+    let load_batch = move |after: Option<ListPosition>| -> Result<Vec<Message>, String> {
+        let mut query = ordered_list_query(&message_query, &state.config);
+        if message_query.sample.is_none() {
+            if let Some(position) = after {
+                query = after_position(query, position);
+            }
+            query = query.limit(EXPORT_BATCH_SIZE);
+        }
+        query
+            .load::<Message>(&*connection)
+            .and_then(|batch| resolve_contents(batch, &*connection))
+            .map_err(|error| error.to_string())
+    };
+    let (content_type, failure_chunk) = match framing {
+        ListFraming::Ndjson => (NDJSON_CONTENT_TYPE.parse().unwrap(), Some(NDJSON_FAILURE_LINE.to_vec())),
+        ListFraming::JsonArray => (mime::APPLICATION_JSON, None),
+    };
+    Response::new()
+        .with_header(ContentType(content_type))
+        .with_body(stream_body(handle, list_chunks(framing, options, batch_size, load_batch), failure_chunk))
+}
+
+/// The sort key of the list, `ordered_list_query`'s, of the last message streamed.
+#[derive(Clone, Copy, Debug)]
+struct ListPosition {
+    pinned: bool,
+    timestamp: i64,
+    id: i32,
+}
+
+impl<'a> From<&'a Message> for ListPosition {
+    fn from(message: &'a Message) -> Self {
+        ListPosition {
+            pinned: message.pinned,
+            timestamp: message.timestamp,
+            id: message.id,
+        }
+    }
+}
+
+/// The messages listed after `position`: pinned ones come first, so a pinned position
+/// is followed by the later pinned messages and then by every unpinned one.
+fn after_position<'a>(
+    query: crate::schema::messages::BoxedQuery<'a, Pg>,
+    position: ListPosition,
+) -> crate::schema::messages::BoxedQuery<'a, Pg> {
+    use crate::schema::messages;
+    let later = messages::timestamp
+        .gt(position.timestamp)
+        .or(messages::timestamp.eq(position.timestamp).and(messages::id.gt(position.id)));
+    query.filter(messages::pinned.lt(position.pinned).or(messages::pinned.eq(position.pinned).and(later)))
+}
+
+/// The chunks of a streamed list, one per batch `load_batch` returns for the position
+/// of the last message sent, `None` at first. A batch shorter than `batch_size` is the
+/// last one, without `batch_size` the first one is.
+fn list_chunks<L>(
+    framing: ListFraming,
+    options: RenderOptions,
+    batch_size: Option<usize>,
+    mut load_batch: L,
+) -> impl FnMut() -> Result<Option<Vec<u8>>, String>
+    where L: FnMut(Option<ListPosition>) -> Result<Vec<Message>, String> {
+    let mut after = None;
+    let mut items_sent = false;
+    let mut finished = false;
+    move || {
+        if finished {
+            return Ok(None);
+        }
+        let batch = load_batch(after)?;
+        after = match (batch.last(), batch_size) {
+            (Some(last), Some(batch_size)) if batch.len() == batch_size => Some(ListPosition::from(last)),
+            _ => None,
+        };
+
+        let mut chunk = String::new();
+        if framing == ListFraming::JsonArray && !items_sent {
+            chunk.push('[');
+        }
+        for message in &batch {
+            if framing == ListFraming::JsonArray && items_sent {
+                chunk.push(',');
+            }
+            let item = message_json(message, &options).map_err(|error| error.to_string())?;
+            chunk.push_str(&apply_field_case(item, options.field_case).to_string());
+            if framing == ListFraming::Ndjson {
+                chunk.push('\n');
+            }
+            items_sent = true;
+        }
+        if after.is_none() {
+            if framing == ListFraming::JsonArray {
+                chunk.push(']');
+            }
+            finished = true;
+        }
+        if chunk.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(chunk.into_bytes()))
+    }
+}
+
+/// Answers `Range: items=first-last` with 206 and the slice, or 416 when `first`
+/// lies past the end. Slices are capped at `MAX_PAGE_SIZE` items.
+fn make_range_response(
+    message_query: &MessageQuery,
+    items: ItemRange,
+    config: &Config,
+    options: &RenderOptions,
+    db_connection: &CountingConnection,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    let total = match count_db(message_query, config, db_connection) {
+        Some(total) => total,
+        None => return futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+    };
+    if items.first >= total {
+        let mut response = Response::new().with_status(StatusCode::RangeNotSatisfiable);
+        response.headers_mut().set_raw("Content-Range", format!("items */{}", total));
+        return futureOk(response);
+    }
+    let last = cmp::min(cmp::min(items.last, items.first + MAX_PAGE_SIZE - 1), total - 1);
+    let messages = match query_db_slice(message_query, config, items.first, last - items.first + 1, db_connection) {
+        Some(messages) => messages,
+        None => return futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+    };
+    let next_page = if last + 1 < total {
+        Some(ItemRange {
+            first: last + 1,
+            last: last + 1 + (last - items.first),
+        })
+    } else {
+        None
+    };
+    let mut response = match render_list_response(&messages, options, next_page) {
+        Ok(response) => response.with_status(StatusCode::PartialContent),
+        Err(error) => return make_service_error_response(&error),
+    };
+    response.headers_mut().set_raw("Content-Range", format!("items {}-{}/{}", items.first, last, total));
+    futureOk(response)
+}
+
+/// Answers `?page=` with that page and `X-Page`, `X-Per-Page`, `X-Total` and `X-Total-Pages`.
+/// The last page may be shorter; pages past it are empty, with the same headers, so a pager
+/// can still tell where the list ends.
+fn make_page_response(
+    message_query: &MessageQuery,
+    page: Page,
+    config: &Config,
+    options: &RenderOptions,
+    db_connection: &CountingConnection,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    let total = match count_db(message_query, config, db_connection) {
+        Some(total) => total,
+        None => return futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+    };
+    let total_pages = total_pages(total, page.per_page);
+    let messages = if page.page > total_pages {
+        Vec::new()
+    } else {
+        match query_db_slice(message_query, config, (page.page - 1) * page.per_page, page.per_page, db_connection) {
+            Some(messages) => messages,
+            None => return futureOk(Response::new().with_status(StatusCode::InternalServerError)),
+        }
+    };
+    let mut response = match render_list_response(&messages, options, None) {
+        Ok(response) => response,
+        Err(error) => return make_service_error_response(&error),
+    };
+    {
+        let headers = response.headers_mut();
+        headers.set_raw("X-Page", page.page.to_string());
+        headers.set_raw("X-Per-Page", page.per_page.to_string());
+        headers.set_raw("X-Total", total.to_string());
+        headers.set_raw("X-Total-Pages", total_pages.to_string());
+    }
+    futureOk(response)
+}
+
+/// Pages of `per_page` messages `total` messages fill, the last one may be shorter.
+fn total_pages(total: i64, per_page: i64) -> i64 {
+    (total + per_page - 1) / per_page
+}
+
+/// One JSON object per line, what the streamed NDJSON list sends.
+fn render_ndjson(messages: &[Message], options: &RenderOptions) -> serde_json::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    for message in messages {
+        body.extend(apply_field_case(message_json(message, options)?, options.field_case).to_string().into_bytes());
+        body.push(b'\n');
+    }
+    Ok(body)
+}
+
+fn render_json(messages: &[Message], options: &RenderOptions) -> serde_json::Result<serde_json::Value> {
+    let items = messages
+        .iter()
+        .map(|message| message_json(message, options))
+        .collect::<serde_json::Result<_>>()?;
+    Ok(apply_field_case(serde_json::Value::Array(items), options.field_case))
+}
+
+/// Lists the messages in the order their ids were requested, missing ids
+/// become `null` or are dropped according to `missing_ids`.
+fn render_batch_json(
+    ids: &[i32],
+    messages: Vec<Message>,
+    missing_ids: MissingIds,
+    options: &RenderOptions,
+) -> serde_json::Result<serde_json::Value> {
+    let by_id = messages
+        .into_iter()
+        .map(|message| (message.id, message))
+        .collect::<HashMap<i32, Message>>();
+    let items = ids
+        .iter()
+        .filter_map(|id| match (by_id.get(id), missing_ids) {
+            (Some(message), _) => Some(message_json(message, options)),
+            (None, MissingIds::Null) => Some(Ok(serde_json::Value::Null)),
+            (None, MissingIds::Omit) => None,
+        })
+        .collect::<serde_json::Result<_>>()?;
+    Ok(apply_field_case(serde_json::Value::Array(items), options.field_case))
+}
+
+/// `{"added": [..], "deleted": [..], "last_id": .., "more": ..}`, where `last_id` is
+/// what the client sends next time, its own when nothing was added.
+fn render_sync_json(diff: SyncDiff, snapshot: &SyncSnapshot, options: &RenderOptions) -> serde_json::Result<serde_json::Value> {
+    let last_id = diff.added.last().map_or(snapshot.last_id, |message| message.id);
+    let payload = json!({
+        "added": diff.added.iter().map(|message| message_json(message, options)).collect::<serde_json::Result<Vec<_>>>()?,
+        "deleted": diff.deleted,
+        "last_id": last_id,
+        "more": diff.more,
+    });
+    Ok(apply_field_case(payload, options.field_case))
+}
+
+/// `{"messages": [..], "prefetch": [..], "before_cursor": .., "next_cursor": ..}`, where
+/// `prefetch` previews the start of the next page and both cursors continue right after
+/// `messages`: `before_cursor` is the position alone, `next_cursor` the opaque whole query.
+fn render_timeline_json(
+    mut messages: Vec<Message>,
+    timeline_query: &TimelineQuery,
+    cursor_key: Option<&str>,
+    options: &RenderOptions,
+) -> serde_json::Result<serde_json::Value> {
+    let has_older = messages.len() as i64 > timeline_query.limit;
+    let mut rest = messages.split_off(cmp::min(messages.len(), timeline_query.limit as usize));
+    rest.truncate(timeline_query.prefetch as usize);
+    let (before_cursor, next_cursor) = match messages.last() {
+        Some(last) if has_older => {
+            let next_query = TimelineQuery {
+                before_cursor: Some((last.timestamp, last.id)),
+                ..timeline_query.clone()
+            };
+            (
+                json!(format!("{}:{}", last.timestamp, last.id)),
+                json!(encode_cursor(&serde_json::to_value(&next_query)?, cursor_key)),
+            )
+        }
+        _ => (serde_json::Value::Null, serde_json::Value::Null),
+    };
+    let payload = json!({
+        "messages": messages.iter().map(|message| message_json(message, options)).collect::<serde_json::Result<Vec<_>>>()?,
+        "prefetch": rest.iter().map(|message| message_json(message, options)).collect::<serde_json::Result<Vec<_>>>()?,
+        "before_cursor": before_cursor,
+        "next_cursor": next_cursor,
+    });
+    Ok(apply_field_case(payload, options.field_case))
+}
+
+/// Serialized without `json!`, which panics on values that can't be serialized.
+fn message_json(message: &Message, options: &RenderOptions) -> serde_json::Result<serde_json::Value> {
+    let mut item = serde_json::to_value(message)?;
+    if options.show_meta {
+        item["ip"] = json!(message.ip);
+        item["user_agent"] = json!(message.user_agent);
+        item["visibility"] = json!(message.visibility);
+    }
+    Ok(item)
+}
+
+/// https://maud.lambda.xyz/partials.html
+/// `show_meta` adds the stored client details, for admin requests only.
+/// Message text is escaped unless `allow_safe_html`, then it is sanitized instead.
+fn render_html(messages: &[Message], options: &RenderOptions) -> String {
+    (html! {
+        head {
+            title { "microservice" }
+            //style {"body { font-family: monospace }"}
+            meta charset="utf-8";
+        }
+        body {
+            (message_list_html(messages, options))
+        }
+    }).into_string()
+}
+
+/// The list without `<head>` and `<body>`, for HTMX to swap into the page. The pager
+/// replaces itself with the next page, sending its range like any `Range` client.
+fn render_html_fragment(messages: &[Message], options: &RenderOptions, list_url: &str, next_page: Option<ItemRange>) -> String {
+    (html! {
+        (message_list_html(messages, options))
+        @if let Some(next_page) = next_page {
+            div.pager
+                hx-get=(list_url)
+                hx-headers=(json!({"Range": format!("items={}-{}", next_page.first, next_page.last)}).to_string())
+                hx-trigger="click"
+                hx-swap="outerHTML" {
+                button { "More" }
+            }
+        }
+    }).into_string()
+}
+
+/// Timestamps are shown in `DISPLAY_TZ`, the raw value is kept in `data-timestamp` and the title.
+fn message_list_html(messages: &[Message], options: &RenderOptions) -> Markup {
+    let sanitizer = if options.allow_safe_html { Some(safe_html_sanitizer()) } else { None };
+    html! {
+        ul {
+            @for message in messages {
+                li {
+                    (message.username) " ("
+                    time title=(message.timestamp) data-timestamp=(message.timestamp) {
+                        (options.time_display.format(message.timestamp))
+                    }
+                    "): "
+                    @match sanitizer {
+                        Some(ref sanitizer) => (PreEscaped(sanitizer.clean(&message.message).to_string())),
+                        None => (message.message),
+                    }
+                    @if options.show_meta {
+                        " [ip: " (message.ip.as_ref().map(String::as_str).unwrap_or("-"))
+                        ", user agent: " (message.user_agent.as_ref().map(String::as_str).unwrap_or("-")) "]"
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::atomic::Ordering;
+
+    use hyper::Method;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use super::super::config::{HttpToHttps, RootMode};
+    use super::super::data_source::{build_pool, DbConnection};
+
+    /// A connection to `DATABASE_URL`, migrated with `diesel migration run`, whose writes
+    /// are rolled back. The tests needing one are ignored, `cargo test -- --ignored` runs them.
+    fn test_connection() -> DbConnection {
+        let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| String::from("postgresql://postgres@localhost:5432"));
+        let db_connection = checkout(&build_pool(&database_url, 1, Duration::from_secs(5), 5)).unwrap();
+        db_connection.begin_test_transaction().unwrap();
+        db_connection
+    }
+
+    fn new_message(text: &str) -> NewMessage {
+        NewMessage {
+            username: String::from("peter"),
+            message: String::from(text),
+            ip: None,
+            user_agent: None,
+            content_hash: None,
+            visibility: String::from(VISIBILITY_PUBLIC),
+        }
+    }
+
+    fn render_options(format: ResponseFormat) -> RenderOptions {
+        RenderOptions {
+            format,
+            show_meta: false,
+            field_case: FieldCase::Snake,
+            allow_safe_html: false,
+            time_display: TimeDisplay::new("UTC", "%Y-%m-%d").unwrap(),
+            htmx_list_url: None,
+        }
+    }
+
+    fn message(&(id, timestamp, pinned): &(i32, i64, bool)) -> Message {
+        Message {
+            id,
+            username: String::from("peter"),
+            message: format!("message {}", id),
+            timestamp,
+            ip: None,
+            user_agent: None,
+            views: 0,
+            content_hash: None,
+            signature: None,
+            visibility: String::from(VISIBILITY_PUBLIC),
+            pinned,
+        }
+    }
+
+    /// `(id, timestamp, pinned)` rows, inserted out of list order and with shared timestamps.
+    const ROWS: [(i32, i64, bool); 7] = [
+        (1, 300, false),
+        (2, 100, false),
+        (3, 200, true),
+        (4, 100, false),
+        (5, 400, true),
+        (6, 200, true),
+        (7, 50, false),
+    ];
+
+    /// What `after_position` asks the database for, on `rows` in `ordered_list_query`'s order.
+    fn load_after(rows: &[(i32, i64, bool)], after: Option<ListPosition>, batch_size: usize) -> Vec<Message> {
+        let mut rows = rows.to_vec();
+        rows.sort_by_key(|&(id, timestamp, pinned)| (!pinned, timestamp, id));
+        rows.iter()
+            .filter(|&&(id, timestamp, pinned)| match after {
+                Some(position) => {
+                    pinned < position.pinned
+                        || (pinned == position.pinned
+                            && (timestamp > position.timestamp || (timestamp == position.timestamp && id > position.id)))
+                }
+                None => true,
+            })
+            .take(batch_size)
+            .map(message)
+            .collect()
+    }
+
+    fn streamed<F>(mut next_chunk: F) -> String
+        where F: FnMut() -> Result<Option<Vec<u8>>, String> {
+        let mut body = Vec::new();
+        while let Some(chunk) = next_chunk().unwrap() {
+            body.extend(chunk);
+        }
+        String::from_utf8(body).unwrap()
+    }
+
+    fn streamed_array(rows: &[(i32, i64, bool)], batch_size: usize) -> serde_json::Value {
+        let rows = rows.to_vec();
+        let chunks = list_chunks(
+            ListFraming::JsonArray,
+            render_options(ResponseFormat::Json),
+            Some(batch_size),
+            move |after| Ok(load_after(&rows, after, batch_size)),
+        );
+        serde_json::from_str(&streamed(chunks)).unwrap()
+    }
+
+    #[test]
+    fn streams_the_json_array_in_the_buffered_order() {
+        let buffered = render_json(&load_after(&ROWS, None, ROWS.len()), &render_options(ResponseFormat::Json)).unwrap();
+        // the last batch is short, then full with an empty one after it
+        for &batch_size in &[3, 7] {
+            let streamed = streamed_array(&ROWS, batch_size);
+            assert_eq!(streamed.as_array().unwrap().len(), ROWS.len());
+            assert_eq!(streamed, buffered);
+        }
+        let ids = buffered
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["id"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![3, 6, 5, 7, 2, 4, 1]);
+    }
+
+    #[test]
+    fn streams_an_empty_json_array() {
+        assert_eq!(streamed_array(&[], 3), json!([]));
+    }
+
+    #[test]
+    #[ignore]
+    fn select_reads_back_the_inserted_row_not_the_newest_lookalike() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let new_message = new_message("hello");
+        // the same message under a higher id than the sequence hands out
+        diesel::insert_into(messages::table)
+            .values((
+                messages::id.eq(i32::max_value()),
+                messages::username.eq(&new_message.username),
+                messages::message.eq(&new_message.message),
+                messages::timestamp.eq(1),
+            ))
+            .execute(&*db_connection)
+            .unwrap();
+
+        let (id, timestamp) = insert_message(&new_message, InsertReturning::Select, &db_connection).unwrap();
+        assert_ne!(id, i32::max_value());
+        let stored = messages::table
+            .find(id)
+            .select(messages::timestamp)
+            .first::<i64>(&*db_connection)
+            .unwrap();
+        assert_eq!(timestamp, stored);
+    }
+
+    #[test]
+    #[ignore]
+    fn keeps_the_most_recent_messages_of_a_user() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let keep = 3;
+        let inserted = (0..=keep)
+            .map(|n| {
+                let mut new_message = new_message(&format!("message {}", n));
+                new_message.username = String::from("pruned");
+                insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap().0
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(prune_user_messages("pruned", keep, &db_connection).unwrap(), 1);
+        let remaining = messages::table
+            .filter(messages::username.eq("pruned"))
+            .select(messages::id)
+            .order(messages::id.asc())
+            .load::<i32>(&*db_connection)
+            .unwrap();
+        assert_eq!(remaining, inserted[1..].to_vec());
+    }
+
+    fn batch_ids(missing_ids: MissingIds) -> Vec<serde_json::Value> {
+        let found = vec![message(&(3, 100, false)), message(&(1, 200, false))];
+        let rendered = render_batch_json(&[1, 2, 3], found, missing_ids, &render_options(ResponseFormat::Json)).unwrap();
+        rendered.as_array().unwrap().iter().map(|item| item["id"].clone()).collect()
+    }
+
+    #[test]
+    fn lists_a_batch_in_the_requested_order_with_missing_ids_as_null() {
+        assert_eq!(batch_ids(MissingIds::Null), vec![json!(1), serde_json::Value::Null, json!(3)]);
+    }
+
+    #[test]
+    fn lists_a_batch_without_missing_ids_when_omitted() {
+        assert_eq!(batch_ids(MissingIds::Omit), vec![json!(1), json!(3)]);
+    }
+
+    #[test]
+    fn caps_the_batch_ids() {
+        assert_eq!(parse_id_list(Some("ids=3, 1,,2")).unwrap(), vec![3, 1, 2]);
+        let too_many = (0..=MAX_BATCH_IDS).map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+        assert!(parse_id_list(Some(&format!("ids={}", too_many))).is_err());
+        assert!(parse_id_list(Some("ids=1,two")).is_err());
+        assert!(parse_id_list(None).is_err());
+    }
+
+    #[test]
+    fn parses_relative_windows() {
+        assert_eq!(parse_relative_window("30s"), Ok(30));
+        assert_eq!(parse_relative_window("15m"), Ok(15 * 60));
+        assert_eq!(parse_relative_window("1h"), Ok(60 * 60));
+        assert_eq!(parse_relative_window("7d"), Ok(7 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn refuses_malformed_relative_windows() {
+        for window in &["", "h", "30", "30w", "-1h", "+1h", "1.5h", "1 h", "1é", "9223372036854775807d"] {
+            assert!(parse_relative_window(window).is_err(), "{}", window);
+        }
+    }
+
+    fn item_range(range: &str) -> Option<(i64, i64)> {
+        let mut headers = Headers::new();
+        headers.set_raw("Range", range.to_string());
+        parse_item_range(&headers).map(|items| (items.first, items.last))
+    }
+
+    #[test]
+    fn reads_item_ranges() {
+        assert_eq!(item_range("items=0-49"), Some((0, 49)));
+        assert_eq!(item_range("items= 10 - 10"), Some((10, 10)));
+    }
+
+    #[test]
+    fn ignores_other_units_and_malformed_ranges() {
+        assert_eq!(item_range("bytes=0-49"), None);
+        assert_eq!(item_range("items=49-0"), None);
+        assert_eq!(item_range("items=0-"), None);
+        assert_eq!(item_range("items=-5"), None);
+        assert_eq!(item_range("items=a-b"), None);
+        assert_eq!(parse_item_range(&Headers::new()).map(|items| items.first), None);
+    }
+
+    #[test]
+    fn ends_a_failed_ndjson_stream_with_the_error_record() {
+        let mut loads = 0;
+        let chunks = list_chunks(ListFraming::Ndjson, render_options(ResponseFormat::Ndjson), Some(2), move |after| {
+            loads += 1;
+            match loads {
+                1 => Ok(load_after(&ROWS, after, 2)),
+                _ => Err(String::from("server closed the connection unexpectedly")),
+            }
+        });
+        let mut core = Core::new().unwrap();
+        let body = stream_body(&core.handle(), chunks, Some(NDJSON_FAILURE_LINE.to_vec()));
+        let items = core.run(body.then(|item| Ok::<_, ()>(item)).collect()).unwrap();
+
+        let (last, sent) = items.split_last().unwrap();
+        // aborted, so the chunked body never ends properly
+        assert!(last.is_err());
+        let sent = sent.iter().map(|chunk| String::from_utf8(chunk.as_ref().unwrap().to_vec()).unwrap()).collect::<String>();
+        let lines = sent.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(lines[0]).unwrap()["id"], 3);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(lines[1]).unwrap()["id"], 6);
+        assert_eq!(format!("{}\n", lines[2]).as_bytes(), NDJSON_FAILURE_LINE);
+    }
+
+    #[test]
+    #[ignore]
+    fn pins_at_most_max_pinned_messages() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        diesel::update(messages::table).set(messages::pinned.eq(false)).execute(&*db_connection).unwrap();
+        let ids = (0..=MAX_PINNED_MESSAGES)
+            .map(|n| insert_message(&new_message(&format!("pin {}", n)), InsertReturning::Returning, &db_connection).unwrap().0)
+            .collect::<Vec<_>>();
+        let (last, first) = ids.split_last().unwrap();
+        for &id in first {
+            assert_eq!(set_pinned(id, true, &db_connection).unwrap(), PinOutcome::Updated);
+        }
+        assert_eq!(set_pinned(*last, true, &db_connection).unwrap(), PinOutcome::LimitReached);
+        // pinning a pinned message again doesn't need room
+        assert_eq!(set_pinned(first[0], true, &db_connection).unwrap(), PinOutcome::Updated);
+        assert_eq!(set_pinned(first[0], false, &db_connection).unwrap(), PinOutcome::Updated);
+        assert_eq!(set_pinned(*last, true, &db_connection).unwrap(), PinOutcome::Updated);
+        assert_eq!(set_pinned(-1, true, &db_connection).unwrap(), PinOutcome::NotFound);
+    }
+
+    fn limited(first: i64, last: i64, max_offset: Option<i64>, cap_offset: bool) -> Result<(i64, i64), String> {
+        limit_offset(ItemRange { first, last }, max_offset, cap_offset).map(|items| (items.first, items.last))
+    }
+
+    #[test]
+    fn passes_ranges_within_max_offset() {
+        assert_eq!(limited(10_000, 10_049, Some(10_000), false), Ok((10_000, 10_049)));
+        assert_eq!(limited(1_000_000_000, 1_000_000_049, None, false), Ok((1_000_000_000, 1_000_000_049)));
+    }
+
+    #[test]
+    fn refuses_ranges_past_max_offset() {
+        assert!(limited(10_001, 10_050, Some(10_000), false).is_err());
+    }
+
+    #[test]
+    fn caps_ranges_past_max_offset_keeping_their_length() {
+        assert_eq!(limited(1_000_000_000, 1_000_000_049, Some(10_000), true), Ok((10_000, 10_049)));
+    }
+
+    #[test]
+    fn strips_control_characters_but_not_whitespace() {
+        let mut dirty = new_message("a\u{1b}[2Jb\tc\r\nd\u{7f}\u{0}");
+        dirty.username = String::from("pe\u{8}ter");
+        let clean = apply_control_char_policy(dirty, ControlCharPolicy::Strip).unwrap();
+        assert_eq!(clean.message, "a[2Jb\tc\r\nd");
+        assert_eq!(clean.username, "peter");
+    }
+
+    #[test]
+    fn refuses_control_characters_naming_the_field() {
+        let error = apply_control_char_policy(new_message("log\u{1b}[31m"), ControlCharPolicy::Reject).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BadRequest);
+        assert_eq!(error.to_string(), "message contains the control character U+001B");
+        let clean = apply_control_char_policy(new_message("tab\tand\r\nlines, é"), ControlCharPolicy::Reject).unwrap();
+        assert_eq!(clean.message, "tab\tand\r\nlines, é");
+    }
+
+    fn page(query: &str, max_offset: Option<i64>, cap_offset: bool) -> Result<Option<(i64, i64)>, String> {
+        parse_page(Some(query), max_offset, cap_offset).map(|page| page.map(|page| (page.page, page.per_page)))
+    }
+
+    #[test]
+    fn reads_pages() {
+        assert_eq!(page("", None, false), Ok(None));
+        assert_eq!(page("page=3", None, false), Ok(Some((3, DEFAULT_PAGE_SIZE))));
+        assert_eq!(page("per_page=50", None, false), Ok(Some((1, 50))));
+        assert!(page("page=0", None, false).is_err());
+        assert!(page("page=two", None, false).is_err());
+    }
+
+    #[test]
+    fn clamps_per_page() {
+        assert_eq!(page("page=1&per_page=0", None, false), Ok(Some((1, 1))));
+        assert_eq!(page("page=1&per_page=100000", None, false), Ok(Some((1, MAX_PAGE_SIZE))));
+    }
+
+    #[test]
+    fn refuses_or_caps_pages_past_max_offset() {
+        // page 101 of 100 starts at 10000, the last within MAX_OFFSET=10000
+        assert_eq!(page("page=101&per_page=100", Some(10_000), false), Ok(Some((101, 100))));
+        assert!(page("page=102&per_page=100", Some(10_000), false).is_err());
+        assert_eq!(page("page=5000&per_page=100", Some(10_000), true), Ok(Some((101, 100))));
+    }
+
+    #[test]
+    fn counts_a_short_last_page() {
+        assert_eq!(total_pages(0, 20), 0);
+        assert_eq!(total_pages(20, 20), 1);
+        assert_eq!(total_pages(21, 20), 2);
+        assert_eq!(total_pages(1, 1), 1);
+    }
+
+    #[test]
+    #[ignore]
+    fn hides_private_messages_from_every_read_without_the_admin_token() {
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        let now = unix_now();
+        let activity_query = parse_activity_query(None).unwrap();
+        let activity = query_activity(&activity_query, now, &db_connection).unwrap();
+
+        let mut private = new_message("a private hello");
+        private.visibility = String::from(VISIBILITY_PRIVATE);
+        let (id, _) = insert_message(&private, InsertReturning::Returning, &db_connection).unwrap();
+        let ids = |messages: Vec<Message>| messages.into_iter().map(|message| message.id).collect::<Vec<_>>();
+
+        assert!(!ids(query_db(MessageQuery::default(), &config, &db_connection).unwrap()).contains(&id));
+        assert!(query_db_by_ids(&[id], false, &db_connection).unwrap().is_empty());
+        let timeline_query = parse_timeline_query(None, None).unwrap();
+        assert!(!ids(query_timeline(&timeline_query, false, &db_connection).unwrap()).contains(&id));
+        assert!(!ids(recent_messages(FEED_ITEMS, &db_connection).unwrap()).contains(&id));
+        assert!(!recent_message_texts(config.word_stats_window, &db_connection).unwrap().contains(&private.message));
+        assert_eq!(query_activity(&activity_query, now, &db_connection).unwrap(), activity);
+
+        // admins read it
+        assert_eq!(ids(query_db_by_ids(&[id], true, &db_connection).unwrap()), vec![id]);
+        assert!(ids(query_timeline(&timeline_query, true, &db_connection).unwrap()).contains(&id));
+    }
+
+    #[test]
+    #[ignore]
+    fn an_unreachable_secondary_does_not_fail_the_insert() {
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        let secondary = SecondaryStore::new("postgresql://postgres@localhost:1", 1, Duration::from_secs(1), 1);
+        let written = write_to_db(new_message("replicated"), None, &config, Some(&secondary), &db_connection).wait();
+        assert!(written.is_ok());
+    }
+
+    /// Also needs `SECONDARY_DATABASE_URL`, a second migrated database. The probe queued
+    /// after the insert reads the copy back and deletes it again.
+    #[test]
+    #[ignore]
+    fn writes_inserts_to_both_stores() {
+        use crate::schema::messages;
+        use std::sync::mpsc;
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        let secondary = SecondaryStore::new(&env::var("SECONDARY_DATABASE_URL").unwrap(), 1, Duration::from_secs(5), 5);
+        let timestamp = write_to_db(new_message("replicated"), None, &config, Some(&secondary), &db_connection)
+            .wait()
+            .unwrap();
+        let id = messages::table
+            .select(messages::id)
+            .order(messages::id.desc())
+            .first::<i32>(&*db_connection)
+            .unwrap();
+        let (sender, copies) = mpsc::channel();
+        secondary.replicate("probe", move |db_connection| {
+            let copy = messages::table
+                .find(id)
+                .select((messages::message, messages::timestamp))
+                .first::<(String, i64)>(db_connection);
+            diesel::delete(messages::table.find(id)).execute(db_connection)?;
+            sender.send(copy).unwrap();
+            Ok(())
+        });
+        let copy = copies.recv_timeout(Duration::from_secs(10)).unwrap().unwrap();
+        assert_eq!(copy, (String::from("replicated"), timestamp));
+    }
+
+    #[test]
+    fn equal_bounds_match_nothing_unless_strict() {
+        let mut config = Config::from_env().unwrap();
+        config.strict_range = false;
+        let message_query = parse_query("before=100&after=100", &config).unwrap();
+        assert!(message_query.matches_nothing());
+        assert!(!parse_query("before=102&after=100", &config).unwrap().matches_nothing());
+
+        config.strict_range = true;
+        assert_eq!(
+            parse_query("before=100&after=100", &config).err(),
+            Some(String::from("'before' (100) must be more than one second after 'after' (100)"))
+        );
+    }
+
+    #[test]
+    fn answers_an_empty_range_with_a_hint() {
+        let response = make_get_response(Some(Vec::new()), &render_options(ResponseFormat::Ndjson))
+            .map(with_empty_range_hint)
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(response.headers().get_raw("X-Query-Hint").is_some());
+    }
+
+    /// The strict budget of one query fails a second execution, so every joiner getting
+    /// the rows shows they all shared one.
+    #[test]
+    #[ignore]
+    fn concurrent_identical_lists_share_one_query() {
+        let db_connection = Rc::new(test_connection());
+        db_connection.start_request(Some(QueryBudget {
+            max_queries: 1,
+            strict: true,
+            context: String::from("single flight test"),
+        }));
+        let config = Rc::new(Config::from_env().unwrap());
+        let message_query = || MessageQuery {
+            username: Some(String::from("single flight")),
+            ..MessageQuery::default()
+        };
+        let list_flights = Rc::new(SingleFlight::new());
+        let flights = (0..10)
+            .map(|_| {
+                let db_connection = db_connection.clone();
+                let config = config.clone();
+                SingleFlight::run(&list_flights, format!("{:?}", message_query()), move || {
+                    Box::new(futures::future::lazy(move || {
+                        query_db(message_query(), &config, &db_connection)
+                            .ok_or_else(|| ServiceError::Internal(String::from("service error")))
+                    }))
+                })
+            })
+            .collect::<Vec<_>>();
+        for flight in flights {
+            assert!(flight.wait().is_ok());
+        }
+    }
+
+    #[test]
+    fn answers_a_serialization_failure_with_a_clean_500() {
+        // JSON object keys must be strings
+        let mut unserializable = HashMap::new();
+        unserializable.insert(vec![1u8], 1);
+        let error = ServiceError::from(serde_json::to_value(&unserializable).unwrap_err());
+        let response = make_service_error_response(&error).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+        let body = response.body().concat2().wait().unwrap();
+        let payload = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(payload["code"], "serialization_error");
+    }
+
+    #[test]
+    fn pads_found_and_missing_lookups_to_the_same_time() {
+        let floor = Duration::from_millis(100);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut timed = |response: ResponseFuture| {
+            let started = Instant::now();
+            let response = core.run(pad_response_time(response, floor, &handle)).unwrap();
+            (response.status(), started.elapsed())
+        };
+        let (found, found_after) = timed(Box::new(make_json_response(StatusCode::Ok, String::from("{}"))));
+        let (missing, missing_after) = timed(Box::new(make_error_response(StatusCode::NotFound, "message not found")));
+        assert_eq!((found, missing), (StatusCode::Ok, StatusCode::NotFound));
+        assert!(found_after >= floor && missing_after >= floor);
+        let difference = if found_after > missing_after { found_after - missing_after } else { missing_after - found_after };
+        assert!(difference < Duration::from_millis(50));
+    }
+
+    #[test]
+    #[ignore]
+    fn coalesces_concurrent_inserts_with_one_idempotency_key_into_one_row() {
+        use crate::schema::messages;
+        let db_connection = Rc::new(test_connection());
+        let config = Rc::new(Config::from_env().unwrap());
+        let insert_flights = Rc::new(SingleFlight::new());
+        let flights = (0..2)
+            .map(|_| {
+                let db_connection = db_connection.clone();
+                let config = config.clone();
+                SingleFlight::run(&insert_flights, String::from("Some(V4(127.0.0.1)) key"), move || {
+                    Box::new(futures::future::lazy(move || {
+                        let mut new_message = new_message("coalesced");
+                        new_message.username = String::from("coalesced");
+                        write_to_db(new_message, None, &config, None, &db_connection).map(InsertOutcome::Inserted)
+                    }))
+                })
+            })
+            .collect::<Vec<_>>();
+        for flight in flights {
+            assert!(flight.wait().is_ok());
+        }
+        let rows = messages::table
+            .filter(messages::username.eq("coalesced"))
+            .count()
+            .get_result::<i64>(&*db_connection)
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    #[test]
+    #[ignore]
+    fn orders_messages_of_the_same_timestamp_by_id() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        for n in 0..3 {
+            let mut new_message = new_message(&format!("message {}", n));
+            new_message.username = String::from("same instant");
+            insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+        }
+        diesel::update(messages::table.filter(messages::username.eq("same instant")))
+            .set(messages::timestamp.eq(1_000))
+            .execute(&*db_connection)
+            .unwrap();
+        let listed = || {
+            let message_query = MessageQuery {
+                username: Some(String::from("same instant")),
+                ..MessageQuery::default()
+            };
+            query_db(message_query, &config, &db_connection).unwrap().iter().map(|message| message.id).collect::<Vec<_>>()
+        };
+        let first = listed();
+        let mut by_id = first.clone();
+        by_id.sort();
+        assert_eq!(first, by_id);
+        for _ in 0..5 {
+            assert_eq!(listed(), first);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn scrolls_back_through_equal_timestamps_without_duplicates() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        for n in 0..7 {
+            let mut new_message = new_message(&format!("message {}", n));
+            new_message.username = String::from("scroll back");
+            insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+        }
+        diesel::update(messages::table.filter(messages::username.eq("scroll back")))
+            .set(messages::timestamp.eq(1_000))
+            .execute(&*db_connection)
+            .unwrap();
+        let ids = |list: &serde_json::Value| {
+            list.as_array().unwrap().iter().map(|message| message["id"].as_i64().unwrap() as i32).collect::<Vec<_>>()
+        };
+        let first_page = "username=scroll+back&limit=2&prefetch=1";
+        let mut query = Some(String::from(first_page));
+        let mut seen = Vec::new();
+        let mut prefetched = Vec::new();
+        while let Some(page) = query.take() {
+            let timeline_query = parse_timeline_query(Some(&page), None).unwrap();
+            let messages = query_timeline(&timeline_query, false, &db_connection).unwrap();
+            let payload = render_timeline_json(messages, &timeline_query, None, &render_options(ResponseFormat::Json)).unwrap();
+            let page_ids = ids(&payload["messages"]);
+            // the prefetched row is where the page loaded next starts
+            if let Some(&expected) = prefetched.first() {
+                assert_eq!(page_ids[0], expected);
+            }
+            prefetched = ids(&payload["prefetch"]);
+            seen.extend(page_ids);
+            query = payload["before_cursor"].as_str().map(|cursor| format!("{}&before_cursor={}", first_page, cursor));
+        }
+        let mut newest_first = messages::table
+            .filter(messages::username.eq("scroll back"))
+            .select(messages::id)
+            .load::<i32>(&*db_connection)
+            .unwrap();
+        newest_first.sort_by(|a, b| b.cmp(a));
+        assert_eq!(seen, newest_first);
+    }
+
+    #[test]
+    fn caps_the_sample_size() {
+        let config = Config::from_env().unwrap();
+        assert_eq!(parse_query("sample=5", &config).unwrap().sample, Some(5));
+        assert_eq!(parse_query("sample=100000", &config).unwrap().sample, Some(MAX_SAMPLE_SIZE));
+        assert!(parse_query("sample=-1", &config).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn samples_distinct_messages_of_the_filter() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        for n in 0..5 {
+            let mut new_message = new_message(&format!("message {}", n));
+            new_message.username = String::from("sampled");
+            insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+        }
+        let inserted = messages::table
+            .filter(messages::username.eq("sampled"))
+            .select(messages::id)
+            .load::<i32>(&*db_connection)
+            .unwrap();
+        let message_query = MessageQuery {
+            username: Some(String::from("sampled")),
+            sample: Some(3),
+            ..MessageQuery::default()
+        };
+        let mut sampled = query_db(message_query, &config, &db_connection).unwrap().iter().map(|message| message.id).collect::<Vec<_>>();
+        sampled.sort();
+        sampled.dedup();
+        assert_eq!(sampled.len(), 3);
+        assert!(sampled.iter().all(|id| inserted.contains(id)));
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(escape_like("100%_done\\"), "100\\%\\_done\\\\");
+    }
+
+    #[test]
+    #[ignore]
+    fn searches_case_insensitively_when_configured() {
+        let db_connection = test_connection();
+        let mut config = Config::from_env().unwrap();
+        let mut new_message = new_message("Hello World");
+        new_message.username = String::from("searched");
+        insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+        let found = |config: &Config, q: &str| {
+            let message_query = MessageQuery {
+                username: Some(String::from("searched")),
+                q: Some(String::from(q)),
+                ..MessageQuery::default()
+            };
+            query_db(message_query, config, &db_connection).unwrap().len()
+        };
+        config.search_case_insensitive = false;
+        assert_eq!(found(&config, "hello"), 0);
+        assert_eq!(found(&config, "Hello"), 1);
+        config.search_case_insensitive = true;
+        assert_eq!(found(&config, "hello"), 1);
+        // the wildcards stay literal
+        assert_eq!(found(&config, "h%o"), 0);
+    }
+
+    #[test]
+    fn refuses_negative_or_crossed_length_bounds() {
+        let config = Config::from_env().unwrap();
+        let message_query = parse_query("min_len=2&max_len=5", &config).unwrap();
+        assert_eq!((message_query.min_len, message_query.max_len), (Some(2), Some(5)));
+        assert_eq!(parse_query("min_len=-1", &config).err(), Some(String::from("'min_len' must not be negative")));
+        assert!(parse_query("min_len=6&max_len=5", &config).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn filters_by_length_in_characters() {
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        for text in &["hi", "héllo", "a longer message"] {
+            let mut new_message = new_message(text);
+            new_message.username = String::from("measured");
+            insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+        }
+        let message_query = MessageQuery {
+            username: Some(String::from("measured")),
+            min_len: Some(5),
+            max_len: Some(5),
+            ..MessageQuery::default()
+        };
+        let texts = query_db(message_query, &config, &db_connection)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.message)
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec![String::from("héllo")]);
+    }
+
+    #[test]
+    fn stores_request_meta_only_when_enabled() {
+        let mut config = Config::from_env().unwrap();
+        let client = "10.0.0.1".parse::<IpAddr>().ok();
+        let mut headers = Headers::new();
+        headers.set(UserAgent::new("curl/7.58.0"));
+        config.store_request_meta = false;
+        let request_meta = request_meta(&config, client, &headers);
+        assert_eq!((request_meta.ip, request_meta.user_agent), (None, None));
+        config.store_request_meta = true;
+        config.hash_stored_ip = false;
+        let request_meta = request_meta(&config, client, &headers);
+        assert_eq!(request_meta.ip, Some(String::from("10.0.0.1")));
+        assert_eq!(request_meta.user_agent, Some(String::from("curl/7.58.0")));
+        config.hash_stored_ip = true;
+        let hashed = request_meta(&config, client, &headers).ip.unwrap();
+        assert_eq!(hashed.len(), 64);
+        assert_ne!(hashed, "10.0.0.1");
+    }
+
+    #[test]
+    fn shows_request_meta_to_admins_only() {
+        let mut stored = vec![message(&(1, 100, false))];
+        stored[0].ip = Some(String::from("10.0.0.1"));
+        stored[0].user_agent = Some(String::from("curl/7.58.0"));
+        let mut options = render_options(ResponseFormat::Json);
+        let item = message_json(&stored[0], &options).unwrap();
+        assert!(item.get("ip").is_none() && item.get("user_agent").is_none());
+        assert!(!render_html(&stored, &options).contains("10.0.0.1"));
+        options.show_meta = true;
+        let item = message_json(&stored[0], &options).unwrap();
+        assert_eq!((item["ip"].as_str(), item["user_agent"].as_str()), (Some("10.0.0.1"), Some("curl/7.58.0")));
+        assert!(render_html(&stored, &options).contains("10.0.0.1"));
+    }
+
+    /// A service on `core` against an unreachable database.
+    fn unconnected_service(mut config: Config, core: &Core) -> (Arc<ServiceState>, MicroService) {
+        config.database_url = String::from("postgresql://postgres@localhost:1");
+        let state = Arc::new(ServiceState::new(config));
+        let service = MicroService::new(
+            state.clone(),
+            core.handle(),
+            Rc::new(SingleFlight::new()),
+            Rc::new(SingleFlight::new()),
+        );
+        (state, service)
+    }
+
+    /// The status and JSON body of `GET path`.
+    fn get(core: &mut Core, service: &MicroService, path: &str) -> (StatusCode, Option<serde_json::Value>) {
+        let response = core.run(service.call(Request::new(Method::Get, path.parse().unwrap()))).unwrap();
+        let status = response.status();
+        let body = core.run(response.body().concat2()).unwrap();
+        (status, serde_json::from_slice::<serde_json::Value>(&body).ok())
+    }
+
+    #[test]
+    fn answers_starting_until_ready() {
+        let mut core = Core::new().unwrap();
+        let (state, service) = unconnected_service(Config::from_env().unwrap(), &core);
+        for path in &["/ready", "/messages"] {
+            let (status, body) = get(&mut core, &service, path);
+            assert_eq!(status, StatusCode::ServiceUnavailable);
+            assert_eq!(body.unwrap()["code"], "starting");
+        }
+        state.ready.store(true, Ordering::SeqCst);
+        assert_eq!(get(&mut core, &service, "/ready").0, StatusCode::Ok);
+        assert_eq!(get(&mut core, &service, "/no-such-page").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn escapes_message_html_unless_safe_html_is_allowed() {
+        let mut messages = vec![message(&(1, 100, false))];
+        messages[0].message = String::from("<b>hi</b><script>alert(1)</script>");
+        let mut options = render_options(ResponseFormat::Html);
+        let escaped = render_html(&messages, &options);
+        assert!(escaped.contains("&lt;b&gt;hi&lt;/b&gt;&lt;script&gt;"));
+        options.allow_safe_html = true;
+        let sanitized = render_html(&messages, &options);
+        assert!(sanitized.contains("<b>hi</b>"));
+        assert!(!sanitized.contains("script"));
+    }
+
+    #[test]
+    #[ignore]
+    fn backfills_zero_timestamps_in_insertion_order() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let ids = (0..3)
+            .map(|n| insert_message(&new_message(&format!("imported {}", n)), InsertReturning::Returning, &db_connection).unwrap().0)
+            .collect::<Vec<_>>();
+        diesel::update(messages::table.filter(messages::id.eq(ids[0])))
+            .set(messages::timestamp.eq(1_000))
+            .execute(&*db_connection)
+            .unwrap();
+        diesel::update(messages::table.filter(messages::id.eq_any(&ids[1..])))
+            .set(messages::timestamp.eq(0))
+            .execute(&*db_connection)
+            .unwrap();
+        assert!(backfill_timestamps(&db_connection).unwrap() >= 2);
+        let timestamps = messages::table
+            .filter(messages::id.eq_any(&ids))
+            .select(messages::timestamp)
+            .order(messages::id.asc())
+            .load::<i64>(&*db_connection)
+            .unwrap();
+        assert_eq!(timestamps, vec![1_000, 1_001, 1_002]);
+        assert_eq!(backfill_timestamps(&db_connection).unwrap(), 0);
+    }
+
+    #[test]
+    fn times_out_routes_after_their_own_timeout() {
+        let mut core = Core::new().unwrap();
+        // every lookup fails fast and is then held for the floor
+        let floor = Duration::from_millis(300);
+        let answer = |core: &mut Core, timeout_ms: u64| {
+            let mut config = Config::from_env().unwrap();
+            config.db_pool_timeout_ms = 50;
+            config.constant_time_floor = Some(floor);
+            config.request_timeout_ms = 0;
+            config.route_timeouts.insert(Route::Message, timeout_ms);
+            let (state, service) = unconnected_service(config, core);
+            state.ready.store(true, Ordering::SeqCst);
+            let started = Instant::now();
+            let (_, body) = get(core, &service, "/messages/1");
+            (started.elapsed(), body.map(|body| body["code"].clone()))
+        };
+        let (elapsed, code) = answer(&mut core, 50);
+        assert!(elapsed < floor, "took {:?}", elapsed);
+        assert_eq!(code, Some(json!("timeout")));
+        let (elapsed, code) = answer(&mut core, 5000);
+        assert!(elapsed >= floor, "took {:?}", elapsed);
+        assert_ne!(code, Some(json!("timeout")));
+    }
+
+    #[test]
+    #[ignore]
+    fn counts_what_the_list_returns() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        for (n, text) in ["first hello", "second", "third hello"].iter().enumerate() {
+            let mut new_message = new_message(text);
+            new_message.username = String::from("counted");
+            let (id, _) = insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+            diesel::update(messages::table.filter(messages::id.eq(id)))
+                .set(messages::timestamp.eq(1_000 + n as i64))
+                .execute(&*db_connection)
+                .unwrap();
+        }
+        let query = |q: Option<&str>, after: Option<i64>| MessageQuery {
+            username: Some(String::from("counted")),
+            q: q.map(String::from),
+            after,
+            ..MessageQuery::default()
+        };
+        for &(q, after, expected) in &[(None, None, 3), (Some("hello"), None, 2), (None, Some(1_000), 2), (Some("hello"), Some(1_000), 1)] {
+            assert_eq!(count_db(&query(q, after), &config, &db_connection), Some(expected));
+            assert_eq!(query_db(query(q, after), &config, &db_connection).unwrap().len() as i64, expected);
+        }
+    }
+
+    #[test]
+    fn serves_the_favicon_and_robots_txt() {
+        let mut core = Core::new().unwrap();
+        let mut fetch = |config: Config, path: &str| {
+            let (_, service) = unconnected_service(config, &core);
+            let response = core.run(service.call(Request::new(Method::Get, path.parse().unwrap()))).unwrap();
+            let content_type = response.headers().get::<ContentType>().map(|content_type| content_type.to_string());
+            let status = response.status();
+            let body = core.run(response.body().concat2()).unwrap();
+            (status, content_type, body.to_vec())
+        };
+        let (status, content_type, body) = fetch(Config::from_env().unwrap(), "/robots.txt");
+        assert_eq!((status, content_type.as_ref().map(String::as_str)), (StatusCode::Ok, Some("text/plain; charset=utf-8")));
+        assert_eq!(body, b"User-agent: *\nDisallow: /\n".to_vec());
+        let (status, content_type, body) = fetch(Config::from_env().unwrap(), "/favicon.ico");
+        assert_eq!((status, content_type.as_ref().map(String::as_str)), (StatusCode::Ok, Some("image/x-icon")));
+        assert!(!body.is_empty());
+        let mut config = Config::from_env().unwrap();
+        config.serve_favicon = false;
+        assert_eq!(fetch(config, "/favicon.ico").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    #[ignore]
+    fn counts_views_only_when_tracked() {
+        let db_connection = test_connection();
+        let (id, _) = insert_message(&new_message("popular"), InsertReturning::Returning, &db_connection).unwrap();
+        let views = |count_view: bool| query_message(id, count_view, false, &db_connection).unwrap().unwrap().views;
+        assert_eq!(views(true), 1);
+        assert_eq!(views(true), 2);
+        assert_eq!(views(false), 2);
+        assert_eq!(views(true), 3);
+        assert!(query_message(id + 1_000_000, true, false, &db_connection).unwrap().is_none());
+    }
+
+    #[test]
+    fn answers_maintenance_on_data_endpoints_only() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::from_env().unwrap();
+        config.maintenance_mode = true;
+        config.maintenance_message = String::from("Back at noon");
+        config.maintenance_retry_after = Some(120);
+        let (state, service) = unconnected_service(config, &core);
+        state.ready.store(true, Ordering::SeqCst);
+
+        let mut request = Request::new(Method::Get, "/messages".parse().unwrap());
+        request.headers_mut().set_raw("Accept", "application/json");
+        let response = core.run(service.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(response.headers().get::<RetryAfter>(), Some(&RetryAfter::Delay(Duration::from_secs(120))));
+        let body = core.run(response.body().concat2()).unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["code"], "maintenance");
+
+        let response = core.run(service.call(Request::new(Method::Get, "/messages".parse().unwrap()))).unwrap();
+        assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+        let page = core.run(response.body().concat2()).unwrap();
+        assert!(String::from_utf8_lossy(&page).contains("Back at noon"));
+
+        assert_eq!(get(&mut core, &service, "/version").0, StatusCode::Ok);
+        let (_, health) = get(&mut core, &service, "/health");
+        assert!(health.unwrap()["status"].is_string());
+    }
+
+    #[test]
+    fn requires_a_user_agent_when_configured() {
+        let mut core = Core::new().unwrap();
+        let status = |core: &mut Core, require_user_agent: bool, path: &str, user_agent: Option<&str>| {
+            let mut config = Config::from_env().unwrap();
+            config.require_user_agent = require_user_agent;
+            let (state, service) = unconnected_service(config, core);
+            state.ready.store(true, Ordering::SeqCst);
+            let mut request = Request::new(Method::Get, path.parse().unwrap());
+            if let Some(user_agent) = user_agent {
+                request.headers_mut().set(UserAgent::new(user_agent.to_string()));
+            }
+            core.run(service.call(request)).unwrap().status()
+        };
+        assert_eq!(status(&mut core, true, "/no-such-page", None), StatusCode::BadRequest);
+        assert_eq!(status(&mut core, true, "/no-such-page", Some("curl/7.58.0")), StatusCode::NotFound);
+        assert_eq!(status(&mut core, true, "/version", None), StatusCode::Ok);
+        assert_eq!(status(&mut core, false, "/no-such-page", None), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn caps_the_timestamp_list() {
+        assert_eq!(parse_timestamp_list("1000, 2000,,3000"), Ok(vec![1000, 2000, 3000]));
+        assert!(parse_timestamp_list("1000,soon").is_err());
+        let at_cap = (0..MAX_TIMESTAMPS).map(|n| n.to_string()).collect::<Vec<_>>().join(",");
+        assert_eq!(parse_timestamp_list(&at_cap).map(|timestamps| timestamps.len()), Ok(MAX_TIMESTAMPS));
+        assert_eq!(
+            parse_timestamp_list(&format!("{},1", at_cap)),
+            Err(format!("At most {} timestamps can be requested at once", MAX_TIMESTAMPS))
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn lists_messages_at_the_given_timestamps() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        for timestamp in &[1_000, 2_000, 3_000] {
+            let mut new_message = new_message(&format!("at {}", timestamp));
+            new_message.username = String::from("instants");
+            let (id, _) = insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+            diesel::update(messages::table.filter(messages::id.eq(id)))
+                .set(messages::timestamp.eq(timestamp))
+                .execute(&*db_connection)
+                .unwrap();
+        }
+        let message_query = MessageQuery {
+            username: Some(String::from("instants")),
+            timestamps: Some(vec![1_000, 3_000, 4_000]),
+            ..MessageQuery::default()
+        };
+        let mut timestamps = query_db(message_query, &config, &db_connection)
+            .unwrap()
+            .iter()
+            .map(|message| message.timestamp)
+            .collect::<Vec<_>>();
+        timestamps.sort();
+        assert_eq!(timestamps, vec![1_000, 3_000]);
+    }
+
+    #[test]
+    #[ignore]
+    fn refuses_usernames_longer_than_the_column() {
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        let mut too_long = new_message("too long a name");
+        too_long.username = "x".repeat(MAX_USERNAME_LEN + 1);
+        match write_to_db(too_long, None, &config, None, &db_connection).wait() {
+            Err(error) => {
+                assert_eq!(error.status(), StatusCode::BadRequest);
+                assert_eq!(error.to_string(), format!("username must be at most {} characters", MAX_USERNAME_LEN));
+            }
+            Ok(timestamp) => panic!("over-length username stored at {}", timestamp),
+        }
+        let mut longest = new_message("long enough a name");
+        longest.username = "x".repeat(MAX_USERNAME_LEN);
+        assert!(write_to_db(longest, None, &config, None, &db_connection).wait().is_ok());
+    }
+
+    #[test]
+    fn answers_htmx_with_a_fragment_and_its_pager() {
+        let messages = vec![message(&(1, 100, false)), message(&(2, 200, false))];
+        let options = render_options(ResponseFormat::Html);
+        let fragment = render_html_fragment(&messages, &options, "/messages", Some(ItemRange { first: 2, last: 3 }));
+        assert!(fragment.starts_with("<ul>"));
+        assert!(!fragment.contains("<head>") && !fragment.contains("<body>"));
+        assert!(fragment.contains("message 1") && fragment.contains("message 2"));
+        assert!(fragment.contains(r#"hx-get="/messages""#));
+        assert!(fragment.contains("items=2-3"));
+        let last_page = render_html_fragment(&messages, &options, "/messages", None);
+        assert!(!last_page.contains("hx-get"));
+        assert!(render_html(&messages, &options).contains("<head>"));
+
+        let mut headers = Headers::new();
+        assert!(!is_htmx_request(&headers));
+        headers.set_raw("HX-Request", "true");
+        assert!(is_htmx_request(&headers));
+    }
+
+    #[test]
+    #[ignore]
+    fn stores_identical_texts_once() {
+        use crate::schema::{message_contents, messages};
+        use diesel::dsl::count_star;
+        let db_connection = test_connection();
+        let mut config = Config::from_env().unwrap();
+        config.dedup_content_storage = true;
+        for _ in 0..2 {
+            let mut new_message = new_message("+1 from the dedup test");
+            new_message.username = String::from("deduplicated");
+            write_to_db(new_message, None, &config, None, &db_connection).wait().unwrap();
+        }
+        let contents = message_contents::table
+            .filter(message_contents::message.eq("+1 from the dedup test"))
+            .select(count_star())
+            .first::<i64>(&*db_connection)
+            .unwrap();
+        assert_eq!(contents, 1);
+        let stored_texts = messages::table
+            .filter(messages::username.eq("deduplicated"))
+            .select(messages::message)
+            .load::<String>(&*db_connection)
+            .unwrap();
+        assert_eq!(stored_texts, vec![String::new(), String::new()]);
+        let message_query = MessageQuery {
+            username: Some(String::from("deduplicated")),
+            q: Some(String::from("dedup test")),
+            ..MessageQuery::default()
+        };
+        let texts = query_db(message_query, &config, &db_connection)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.message)
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec![String::from("+1 from the dedup test"); 2]);
+    }
+
+    #[test]
+    fn shows_local_times_and_keeps_the_raw_timestamp() {
+        let messages = vec![message(&(1, 1_600_000_000, false))];
+        let mut options = render_options(ResponseFormat::Html);
+        options.time_display = TimeDisplay::new("Asia/Tokyo", "%Y-%m-%d %H:%M").unwrap();
+        let html = render_html(&messages, &options);
+        assert!(html.contains(r#"<time title="1600000000" data-timestamp="1600000000">2020-09-13 21:26</time>"#));
+    }
+
+    #[test]
+    fn answers_options_with_the_allowed_methods() {
+        let mut core = Core::new().unwrap();
+        let (_, service) = unconnected_service(Config::from_env().unwrap(), &core);
+        let response = core.run(service.call(Request::new(Method::Options, "/".parse().unwrap()))).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get::<Allow>(), Some(&Allow(vec![Method::Options, Method::Post, Method::Get])));
+        let body = core.run(response.body().concat2()).unwrap();
+        let index = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(index["methods"], json!(["OPTIONS", "POST", "GET"]));
+        assert!(index["endpoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|endpoint| endpoint["method"] == "GET" && endpoint["path"] == "/messages"));
+    }
+
+    #[test]
+    fn answers_inserts_with_a_bare_timestamp_for_plain_text() {
+        let body_of = |result: Result<InsertOutcome, ServiceError>, plain_text: bool| {
+            let response = make_post_response(result, plain_text).wait().unwrap();
+            let content_type = response.headers().get::<ContentType>().map(|content_type| content_type.to_string());
+            let body = response.body().concat2().wait().unwrap();
+            (content_type, String::from_utf8(body.to_vec()).unwrap())
+        };
+        assert_eq!(
+            body_of(Ok(InsertOutcome::Inserted(1_600_000_000)), true),
+            (Some(String::from("text/plain; charset=utf-8")), String::from("1600000000"))
+        );
+        let (content_type, body) = body_of(Ok(InsertOutcome::Inserted(1_600_000_000)), false);
+        assert_eq!(content_type, Some(String::from("application/json")));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["timestamp"], 1_600_000_000);
+        let (content_type, _) = body_of(Err(ServiceError::BadRequest(String::from("empty message"))), true);
+        assert_eq!(content_type, Some(String::from("application/json")));
+
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", "text/plain");
+        assert!(prefers_plain_text(&headers));
+        headers.set_raw("Accept", "text/plain, application/json");
+        assert!(!prefers_plain_text(&headers));
+    }
+
+    #[test]
+    #[ignore]
+    fn records_writes_in_the_audit_log() {
+        use crate::schema::messages;
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        let audit = AuditContext {
+            client: Some(String::from("10.0.0.1")),
+            operation: Route::Insert,
+        };
+        write_to_db(new_message("audited"), Some(audit), &config, None, &db_connection).wait().unwrap();
+        let id = messages::table
+            .filter(messages::message.eq("audited"))
+            .select(messages::id)
+            .order(messages::id.desc())
+            .first::<i32>(&*db_connection)
+            .unwrap();
+        let entries = recent_audit_entries(1, &db_connection).unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(
+            (entry.client.as_ref().map(String::as_str), entry.operation.as_str(), entry.affected_id, entry.outcome.as_str()),
+            (Some("10.0.0.1"), "insert", Some(id), "ok")
+        );
+    }
+
+    #[test]
+    fn reads_the_audit_log_for_admins_only() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::from_env().unwrap();
+        config.audit_log = true;
+        let (state, service) = unconnected_service(config, &core);
+        state.ready.store(true, Ordering::SeqCst);
+        assert_eq!(get(&mut core, &service, "/admin/audit").0, StatusCode::Unauthorized);
+    }
+
+    #[test]
+    fn serves_the_landing_page_at_the_root_in_landing_mode() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::from_env().unwrap();
+        config.root_mode = RootMode::Landing;
+        config.landing_page = None;
+        let (_, service) = unconnected_service(config, &core);
+        let response = core.run(service.call(Request::new(Method::Get, "/".parse().unwrap()))).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get::<ContentType>(), Some(&ContentType::html()));
+        let page = core.run(response.body().concat2()).unwrap();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.contains("<h1>Welcome</h1>"));
+        assert!(page.contains(r#"<a href="/messages">"#));
+    }
+
+    #[test]
+    fn refuses_explain_without_the_admin_token() {
+        let mut core = Core::new().unwrap();
+        let (state, service) = unconnected_service(Config::from_env().unwrap(), &core);
+        state.ready.store(true, Ordering::SeqCst);
+        assert_eq!(get(&mut core, &service, "/?explain=true").0, StatusCode::Unauthorized);
+    }
+
+    #[test]
+    #[ignore]
+    fn explains_the_list_query() {
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        let message_query = MessageQuery {
+            username: Some(String::from("peter")),
+            ..MessageQuery::default()
+        };
+        let response = make_explain_response(&message_query, &config, &db_connection).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get::<ContentType>(), Some(&ContentType::plaintext()));
+        let plan = String::from_utf8(response.body().concat2().wait().unwrap().to_vec()).unwrap();
+        assert!(plan.contains("messages"));
+        assert!(plan.contains("Execution Time"));
+    }
+
+    #[test]
+    fn composes_decomposed_text_to_nfc() {
+        let mut decomposed = new_message("cafe\u{301} cre\u{300}me");
+        decomposed.username = String::from("Zoe\u{308}");
+        let normalized = normalize_nfc(decomposed);
+        assert_eq!(normalized.message, "caf\u{e9} cr\u{e8}me");
+        assert_eq!(normalized.username, "Zo\u{eb}");
+        assert_eq!(normalize_nfc(new_message("caf\u{e9}")).message, "caf\u{e9}");
+    }
+
+    #[test]
+    #[ignore]
+    fn filters_by_emoji_presence() {
+        let db_connection = test_connection();
+        let config = Config::from_env().unwrap();
+        for text in &["party \u{1F389}", "sunny \u{2600}", "plain text", "punctuation :-)"] {
+            let mut new_message = new_message(text);
+            new_message.username = String::from("emoji");
+            insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap();
+        }
+        let texts = |has_emoji: bool| {
+            let message_query = MessageQuery {
+                username: Some(String::from("emoji")),
+                has_emoji: Some(has_emoji),
+                ..MessageQuery::default()
+            };
+            let mut texts = query_db(message_query, &config, &db_connection)
+                .unwrap()
+                .into_iter()
+                .map(|message| message.message)
+                .collect::<Vec<_>>();
+            texts.sort();
+            texts
+        };
+        assert_eq!(texts(true), vec![String::from("party \u{1F389}"), String::from("sunny \u{2600}")]);
+        assert_eq!(texts(false), vec![String::from("plain text"), String::from("punctuation :-)")]);
+    }
+
+    #[test]
+    fn refuses_searches_shorter_than_the_minimum() {
+        let mut config = Config::from_env().unwrap();
+        config.min_search_len = 3;
+        assert_eq!(parse_query("q=ab", &config).err(), Some(String::from("'q' must be at least 3 characters long")));
+        assert_eq!(parse_query("q=abc", &config).unwrap().q, Some(String::from("abc")));
+        // counted in characters, not bytes
+        assert!(parse_query("q=%C3%A9%C3%A9", &config).is_err());
+        assert!(parse_query("username=peter", &config).is_ok());
+        config.min_search_len = 0;
+        assert!(parse_query("q=a", &config).is_ok());
+    }
+
+    #[test]
+    fn refuses_untyped_bodies_unless_lenient() {
+        let mut core = Core::new().unwrap();
+        let post = |core: &mut Core, lenient_content_type: bool| {
+            let mut config = Config::from_env().unwrap();
+            config.lenient_content_type = lenient_content_type;
+            config.db_pool_timeout_ms = 50;
+            let (state, service) = unconnected_service(config, core);
+            state.ready.store(true, Ordering::SeqCst);
+            let mut request = Request::new(Method::Post, "/".parse().unwrap());
+            request.set_body("username=peter&message=untyped");
+            let response = core.run(service.call(request)).unwrap();
+            let status = response.status();
+            let body = core.run(response.body().concat2()).unwrap();
+            let code = serde_json::from_slice::<serde_json::Value>(&body).ok().map(|body| body["code"].clone());
+            (status, code)
+        };
+        assert_eq!(post(&mut core, false), (StatusCode::BadRequest, Some(json!("missing_content_type"))));
+        // parsed as a form, and then refused by the unreachable database instead
+        let (status, code) = post(&mut core, true);
+        assert_ne!(status, StatusCode::BadRequest);
+        assert_ne!(code, Some(json!("missing_content_type")));
+    }
+
+    #[test]
+    fn parses_sync_snapshots() {
+        let snapshot = parse_sync_snapshot(b"{}".to_vec()).wait().unwrap();
+        assert_eq!((snapshot.last_id, snapshot.known_ids), (0, vec![]));
+        let snapshot = parse_sync_snapshot(br#"{"last_id": 7, "known_ids": [3, 5]}"#.to_vec()).wait().unwrap();
+        assert_eq!((snapshot.last_id, snapshot.known_ids), (7, vec![3, 5]));
+        assert!(parse_sync_snapshot(b"[1, 2]".to_vec()).wait().is_err());
+        let too_many = json!({ "known_ids": (0..=MAX_SYNC_KNOWN_IDS as i32).collect::<Vec<_>>() });
+        assert!(parse_sync_snapshot(too_many.to_string().into_bytes()).wait().is_err());
+    }
+
+    #[test]
+    fn continues_the_sync_after_the_last_added_message() {
+        let snapshot = SyncSnapshot { last_id: 4, known_ids: vec![2, 3] };
+        let options = render_options(ResponseFormat::Json);
+        let unchanged = SyncDiff { added: vec![], more: false, deleted: vec![] };
+        assert_eq!(render_sync_json(unchanged, &snapshot, &options).unwrap()["last_id"], json!(4));
+        let diff = SyncDiff {
+            added: vec![message(&(5, 1, false)), message(&(6, 2, false))],
+            more: true,
+            deleted: vec![3],
+        };
+        let payload = render_sync_json(diff, &snapshot, &options).unwrap();
+        assert_eq!(payload["last_id"], json!(6));
+        assert_eq!(payload["more"], json!(true));
+        assert_eq!(payload["deleted"], json!([3]));
+        assert_eq!(payload["added"].as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    #[ignore]
+    fn reports_added_and_deleted_messages() {
+        let db_connection = test_connection();
+        let insert = |visibility: &str| {
+            let mut new_message = new_message("synced");
+            new_message.visibility = String::from(visibility);
+            insert_message(&new_message, InsertReturning::Returning, &db_connection).unwrap().0
+        };
+        let seen = insert(VISIBILITY_PUBLIC);
+        let private = insert(VISIBILITY_PRIVATE);
+        let added = insert(VISIBILITY_PUBLIC);
+        let gone = added + 1000;
+        let snapshot = SyncSnapshot { last_id: seen, known_ids: vec![seen, private, gone, gone] };
+        let diff = sync_messages(&snapshot, false, &db_connection).unwrap();
+        assert_eq!(diff.added.iter().map(|message| message.id).collect::<Vec<_>>(), vec![added]);
+        assert!(!diff.more);
+        assert_eq!(diff.deleted, vec![private, gone]);
+        let diff = sync_messages(&snapshot, true, &db_connection).unwrap();
+        assert_eq!(diff.added.iter().map(|message| message.id).collect::<Vec<_>>(), vec![private, added]);
+        assert_eq!(diff.deleted, vec![gone]);
+    }
+
+    #[test]
+    fn serves_pool_metrics_only_when_enabled() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::from_env().unwrap();
+        config.metrics = false;
+        let (_, service) = unconnected_service(config.clone(), &core);
+        assert_eq!(get(&mut core, &service, "/metrics").0, StatusCode::NotFound);
+        config.metrics = true;
+        let (_, service) = unconnected_service(config, &core);
+        let response = core.run(service.call(Request::new(Method::Get, "/metrics".parse().unwrap()))).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get::<ContentType>(), Some(&ContentType(METRICS_CONTENT_TYPE.parse().unwrap())));
+        let text = String::from_utf8(core.run(response.body().concat2()).unwrap().to_vec()).unwrap();
+        assert!(text.contains("# TYPE db_pool_connections_in_use gauge"));
+        assert!(text.contains("db_pool_checkout_wait_seconds_count "));
+    }
+
+    #[test]
+    fn refuses_plaintext_requests_except_probes() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::from_env().unwrap();
+        config.http_to_https = Some(HttpToHttps::Reject);
+        let (state, service) = unconnected_service(config, &core);
+        state.ready.store(true, Ordering::SeqCst);
+        assert_eq!(get(&mut core, &service, "/messages").0, StatusCode::UpgradeRequired);
+        assert_ne!(get(&mut core, &service, "/health").0, StatusCode::UpgradeRequired);
+    }
+}
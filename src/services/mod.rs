@@ -1,6 +1,58 @@
 
+pub mod config;
 pub mod data_source;
 
+mod access_log;
+mod activity;
+mod allowed_hosts;
+mod audit;
+mod auth;
+mod circuit_breaker;
+mod client_ip;
+mod compression;
+mod content_dedup;
+mod csv_export;
+mod cursor;
+mod dual_write;
+mod error;
+mod exports;
+mod explain;
+mod health;
+mod https_redirect;
+mod index_advisory;
+mod json_case;
+mod json_feed;
+mod landing;
+mod leak_detection;
+mod limits;
+mod maintenance;
+mod metrics;
 mod micro_service;
+mod moderation;
+mod negotiation;
+mod openapi;
+mod protobuf;
+mod queue;
+mod rate_limit;
+mod request_body;
+mod routes;
+mod safe_html;
+mod scheduler;
+mod signing;
+mod single_flight;
+mod sql_export;
+mod startup;
+mod state;
+mod static_files;
+mod streaming;
+mod time_display;
+mod timeline;
+mod trace_headers;
+mod word_stats;
+mod write_buffer;
 
-pub use self::micro_service::MicroService;
\ No newline at end of file
+pub use self::leak_detection::watch_for_leaks;
+pub use self::micro_service::{replay_write_buffer, MicroService};
+pub use self::single_flight::SingleFlight;
+pub use self::startup::initialize;
+pub use self::state::ServiceState;
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+use futures::future::{Either, Future};
+use futures::Stream;
+use hyper::{Client, Method, Request, StatusCode, Uri};
+use hyper::header::{ContentLength, ContentType};
+use serde_json::Value;
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::data_source::models::NewMessage;
+use super::error::ServiceError;
+
+/// Settings of the optional pre-insert moderation call.
+#[derive(Clone, Debug)]
+pub struct ModerationConfig {
+    /// Plain `http://` endpoint, there is no TLS client in this crate.
+    pub url: Uri,
+    pub timeout: Duration,
+    /// Insert anyway when the moderation service can't be asked.
+    pub fail_open: bool,
+}
+
+enum Verdict {
+    Approved,
+    Rejected(String),
+}
+
+/// Posts `{"username": .., "message": ..}` to the moderation service, which answers
+/// `{"approved": true}` or `{"approved": false, "reason": ".."}`. A rejection fails
+/// with `ModerationRejected`; an error or timeout lets the message through only when
+/// the moderation is configured to fail open.
+pub fn moderate(
+    new_message: NewMessage,
+    moderation: &ModerationConfig,
+    handle: &Handle,
+) -> Box<dyn Future<Item=NewMessage, Error=ServiceError>> {
+    let payload = json!({
+        "username": new_message.username,
+        "message": new_message.message,
+    }).to_string();
+    let mut request = Request::new(Method::Post, moderation.url.clone());
+    request.headers_mut().set(ContentType::json());
+    request.headers_mut().set(ContentLength(payload.len() as u64));
+    request.set_body(payload);
+
+    let call = Client::new(handle)
+        .request(request)
+        .and_then(|response| {
+            let status = response.status();
+            response.body().concat2().map(move |body| (status, body.to_vec()))
+        })
+        .map_err(|error| error.to_string());
+    let verdict: Box<dyn Future<Item=Verdict, Error=String>> = match Timeout::new(moderation.timeout, handle) {
+        Ok(timeout) => Box::new(call.select2(timeout).then(|result| match result {
+            Ok(Either::A(((status, body), _))) => parse_verdict(status, &body),
+            Ok(Either::B(_)) => Err(String::from("timed out")),
+            Err(Either::A((error, _))) => Err(error),
+            Err(Either::B((error, _))) => Err(error.to_string()),
+        })),
+        Err(error) => Box::new(futures::future::err(error.to_string())),
+    };
+
+    let fail_open = moderation.fail_open;
+    Box::new(verdict.then(move |verdict| match verdict {
+        Ok(Verdict::Approved) => Ok(new_message),
+        Ok(Verdict::Rejected(reason)) => Err(ServiceError::ModerationRejected(reason)),
+        Err(error) if fail_open => {
+            warn!("Moderation failed, accepting message: {}", error);
+            Ok(new_message)
+        }
+        Err(error) => {
+            error!("Moderation failed, rejecting message: {}", error);
+            Err(ServiceError::ModerationUnavailable)
+        }
+    }))
+}
+
+fn parse_verdict(status: StatusCode, body: &[u8]) -> Result<Verdict, String> {
+    if !status.is_success() {
+        return Err(format!("moderation service answered {}", status));
+    }
+    let verdict = serde_json::from_slice::<Value>(body).map_err(|error| error.to_string())?;
+    match verdict["approved"].as_bool() {
+        Some(true) => Ok(Verdict::Approved),
+        Some(false) => {
+            let reason = verdict["reason"].as_str().unwrap_or("rejected by moderation");
+            Ok(Verdict::Rejected(reason.to_string()))
+        }
+        None => Err(String::from("moderation response lacks 'approved'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use super::super::micro_service::VISIBILITY_PUBLIC;
+
+    /// A moderation service answering a single request with `verdict`.
+    fn mock_moderation(verdict: &'static str) -> Uri {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            // the payload is a single JSON object, it ends with the request
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                verdict.len(),
+                verdict
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}/moderate", address).parse().unwrap()
+    }
+
+    fn new_message() -> NewMessage {
+        NewMessage {
+            username: String::from("peter"),
+            message: String::from("hello"),
+            ip: None,
+            user_agent: None,
+            content_hash: None,
+            visibility: String::from(VISIBILITY_PUBLIC),
+        }
+    }
+
+    fn moderation(url: Uri, fail_open: bool) -> ModerationConfig {
+        ModerationConfig {
+            url,
+            timeout: Duration::from_secs(5),
+            fail_open,
+        }
+    }
+
+    #[test]
+    fn inserts_approved_messages() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let moderation = moderation(mock_moderation(r#"{"approved": true}"#), false);
+        let approved = core.run(moderate(new_message(), &moderation, &handle)).unwrap();
+        assert_eq!(approved.message, "hello");
+    }
+
+    #[test]
+    fn refuses_rejected_messages_with_the_reason() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let moderation = moderation(mock_moderation(r#"{"approved": false, "reason": "spam"}"#), true);
+        match core.run(moderate(new_message(), &moderation, &handle)) {
+            Err(ServiceError::ModerationRejected(reason)) => assert_eq!(reason, "spam"),
+            other => panic!("expected a rejection, got {:?}", other.map(|new_message| new_message.message)),
+        }
+    }
+
+    #[test]
+    fn fails_open_or_closed_without_a_moderation_service() {
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/moderate", listener.local_addr().unwrap()).parse::<Uri>().unwrap()
+        };
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        assert!(core.run(moderate(new_message(), &moderation(unreachable.clone(), true), &handle)).is_ok());
+        match core.run(moderate(new_message(), &moderation(unreachable, false), &handle)) {
+            Err(ServiceError::ModerationUnavailable) => {}
+            other => panic!("expected the moderation to fail closed, got {:?}", other.map(|new_message| new_message.message)),
+        }
+    }
+
+    #[test]
+    fn requires_an_approved_field() {
+        assert!(parse_verdict(StatusCode::Ok, br#"{"status": "fine"}"#).is_err());
+        assert!(parse_verdict(StatusCode::InternalServerError, br#"{"approved": true}"#).is_err());
+    }
+}
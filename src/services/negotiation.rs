@@ -0,0 +1,58 @@
+use hyper::header::{Accept, Headers};
+use hyper::mime;
+
+/// Representation chosen for a response from the request's `Accept` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseFormat {
+    Html,
+    Json,
+    /// One JSON object per line, streamed; only the list offers it.
+    Ndjson,
+    /// A `MessageList` of `proto/messages.proto`; only the list offers it.
+    Protobuf,
+}
+
+/// JSON only when asked for explicitly, browsers and `curl` keep getting HTML.
+pub fn response_format(headers: &Headers) -> ResponseFormat {
+    let accept = match headers.get::<Accept>() {
+        Some(accept) => accept,
+        None => return ResponseFormat::Html,
+    };
+    let accepts = |subtype: &str| {
+        accept
+            .iter()
+            .any(|item| item.item.type_() == mime::APPLICATION && item.item.subtype() == subtype)
+    };
+    if accepts("x-ndjson") {
+        ResponseFormat::Ndjson
+    } else if accepts("x-protobuf") {
+        ResponseFormat::Protobuf
+    } else if accepts("json") {
+        ResponseFormat::Json
+    } else {
+        ResponseFormat::Html
+    }
+}
+
+/// `text/plain` asked for explicitly, and JSON not; the insert then answers with the bare timestamp.
+pub fn prefers_plain_text(headers: &Headers) -> bool {
+    match headers.get::<Accept>() {
+        Some(accept) => {
+            let accepts = |type_, subtype: &str| {
+                accept
+                    .iter()
+                    .any(|item| item.item.type_() == type_ && item.item.subtype() == subtype)
+            };
+            accepts(mime::TEXT, "plain") && !accepts(mime::APPLICATION, "json")
+        }
+        None => false,
+    }
+}
+
+/// Sent by HTMX with every request it makes, those want a fragment to swap in.
+pub fn is_htmx_request(headers: &Headers) -> bool {
+    match headers.get_raw("HX-Request").and_then(|value| value.one()) {
+        Some(value) => value == b"true",
+        None => false,
+    }
+}
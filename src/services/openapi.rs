@@ -0,0 +1,337 @@
+use hyper::Method;
+use serde_json::{Map, Value};
+
+use super::routes::{Route, ROUTES};
+
+/// The OpenAPI 3.0 description of every route in `ROUTES`, served as `/openapi.json`.
+///
+/// Paths and methods come from the dispatch table itself; what each route takes and
+/// returns is described in `operation`, whose match makes a new route fail to compile
+/// until it is documented.
+pub fn openapi_document() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES.iter() {
+        let (method, path) = match route.endpoint() {
+            Some(endpoint) => endpoint,
+            None => continue,
+        };
+        let item = paths.entry(path.to_string()).or_insert_with(|| json!({}));
+        item[method.to_string().to_ascii_lowercase()] = operation(*route);
+    }
+    json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": "rust_web_server_demo",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "Message": {
+                    "type": "object",
+                    "required": ["id", "username", "message", "timestamp"],
+                    "properties": {
+                        "id": {"type": "integer", "format": "int32"},
+                        "username": {"type": "string", "maxLength": 128},
+                        "message": {"type": "string"},
+                        "timestamp": {"type": "integer", "format": "int64", "description": "Seconds since the epoch"},
+                        "ip": {"type": "string", "description": "Admins only"},
+                        "user_agent": {"type": "string", "description": "Admins only"},
+                        "visibility": {"type": "string", "enum": ["public", "private"], "description": "Admins only"},
+                        "signature": {"type": "string", "description": "HMAC-SHA256, for messages signed with MESSAGE_SIGNING_KEY"},
+                        "pinned": {"type": "boolean", "description": "Present and true for pinned messages, listed first"},
+                    },
+                },
+            },
+            "securitySchemes": {
+                "adminToken": {"type": "http", "scheme": "bearer"},
+            },
+        },
+    })
+}
+
+/// The answer to `OPTIONS /`: what `/` allows and a one line summary of every endpoint.
+pub fn api_index() -> Value {
+    let endpoints = ROUTES
+        .iter()
+        .filter_map(|route| {
+            let (method, path) = route.endpoint()?;
+            Some(json!({
+                "method": method.to_string(),
+                "path": path,
+                "summary": operation(*route)["summary"],
+            }))
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "description": "Stores short messages and lists them as HTML, JSON, NDJSON or protobuf",
+        "methods": root_methods().iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "endpoints": endpoints,
+        "openapi": "/openapi.json",
+    })
+}
+
+/// The methods `/` answers, `OPTIONS` included, for the `Allow` header of `OPTIONS /`.
+pub fn root_methods() -> Vec<Method> {
+    ROUTES
+        .iter()
+        .filter_map(|route| route.endpoint())
+        .filter(|&(_, path)| path == "/")
+        .map(|(method, _)| method)
+        .collect()
+}
+
+fn operation(route: Route) -> Value {
+    let mut operation = match route {
+        Route::Ready => describe("Whether startup has finished", vec![], json_response("Ready")),
+        Route::Health => describe(
+            "Service and database health, 503 when down",
+            vec![
+                query_param("verbose", "boolean", "Include component details"),
+                query_param("deep", "boolean", "Also write to the database, at most every HEALTH_WRITE_INTERVAL_SECS"),
+            ],
+            json_response("Health report"),
+        ),
+        Route::Metrics => {
+            let mut metrics = describe(
+                "Connection pool gauges and checkout waits for Prometheus, with METRICS=1",
+                vec![],
+                text_response("text/plain"),
+            );
+            metrics["responses"]["404"] = json!({"description": "Metrics are off"});
+            metrics
+        }
+        Route::Version => describe("The running version", vec![], json_response("Version")),
+        Route::OpenApi => describe("This document", vec![], json_response("OpenAPI document")),
+        Route::Index => describe("Methods of / and a summary of every endpoint", vec![], json_response("API index")),
+        Route::Insert => {
+            let mut insert = describe("Store a message", vec![], json_response("Timestamp of the new message"));
+            insert["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/x-www-form-urlencoded": {
+                        "schema": {
+                            "type": "object",
+                            "required": ["username", "message"],
+                            "properties": {
+                                "username": {"type": "string"},
+                                "message": {"type": "string"},
+                                "visibility": {"type": "string", "enum": ["public", "private"], "default": "public"},
+                            },
+                        },
+                    },
+                },
+            });
+            insert["responses"]["202"] = json!({"description": "Queued in WRITE_BUFFER_PATH while the database is unreachable"});
+            insert
+        }
+        Route::List => {
+            let mut parameters = list_params();
+            parameters.push(query_param("page", "integer", "Page number from 1, answered with X-Page, X-Per-Page, X-Total and X-Total-Pages"));
+            parameters.push(query_param("per_page", "integer", "Messages per page, at most 100"));
+            parameters.push(query_param("explain", "boolean", "Admins only: the EXPLAIN ANALYZE plan as text instead"));
+            describe("List messages as HTML, JSON, NDJSON or protobuf", parameters, message_list_response())
+        }
+        Route::Messages => describe(
+            "Messages by id, in the requested order",
+            vec![query_param("ids", "string", "Comma separated ids, at most 100")],
+            message_list_response(),
+        ),
+        Route::MessageCount => describe("Number of messages the list would return", list_params(), json_response("Count")),
+        Route::Message => {
+            let mut message = describe("One message", vec![path_param("id", "integer")], message_response());
+            message["responses"]["404"] = json!({"description": "No such message"});
+            message
+        }
+        Route::VerifyMessage => {
+            let mut verify = describe(
+                "Recompute a message's signature, with MESSAGE_SIGNING_KEY",
+                vec![path_param("id", "integer")],
+                json_response("Whether the message is signed and unaltered"),
+            );
+            verify["responses"]["404"] = json!({"description": "No such message, or signing is off"});
+            verify
+        }
+        Route::PinMessage => {
+            let mut pin = admin(describe(
+                "Pin a message to the top of the list, or unpin it",
+                vec![path_param("id", "integer"), query_param("pinned", "boolean", "false unpins, defaults to true")],
+                json_response("The message's new state"),
+            ));
+            pin["responses"]["404"] = json!({"description": "No such message"});
+            pin["responses"]["409"] = json!({"description": "Too many pinned messages"});
+            pin
+        }
+        Route::Sync => {
+            let mut sync = describe(
+                "Messages added after, and known ids deleted since, a client's snapshot",
+                vec![],
+                json_response("Added messages, deleted ids and the next last_id"),
+            );
+            sync["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/json": {
+                        "schema": {
+                            "type": "object",
+                            "properties": {
+                                "last_id": {"type": "integer", "description": "Highest id the client has"},
+                                "known_ids": {"type": "array", "items": {"type": "integer"}, "maxItems": 1000},
+                            },
+                        },
+                    },
+                },
+            });
+            sync
+        }
+        Route::Timeline => describe(
+            "Newest messages first, with cursor paging",
+            vec![
+                query_param("limit", "integer", "Page size, at most 100"),
+                query_param("prefetch", "integer", "Messages of the next page to include"),
+                query_param("username", "string", "Only messages of this user"),
+                query_param("before_cursor", "string", "Cursor returned by the previous page"),
+                query_param("cursor", "string", "Opaque next_cursor of the previous page, replacing all other parameters"),
+            ],
+            json_response("A page of the timeline"),
+        ),
+        Route::Activity => describe(
+            "Message counts per time bucket",
+            vec![
+                query_param("buckets", "integer", "Number of buckets, at most 366"),
+                query_param("interval", "string", "minute, hour or day"),
+            ],
+            json_response("Counts per bucket"),
+        ),
+        Route::WordStats => describe(
+            "Most frequent words of the newest messages",
+            vec![query_param("limit", "integer", "Number of words, at most 100")],
+            json_response("Words with their counts"),
+        ),
+        Route::Feed => describe("The newest messages as a JSON Feed 1.1", vec![], text_response("application/feed+json")),
+        Route::BackfillTimestamps => admin(describe("Fill in zero timestamps", vec![], json_response("Rows updated"))),
+        Route::DbMaintenance => admin(describe("Run VACUUM ANALYZE", vec![], json_response("Timing"))),
+        Route::AuditLog => admin(describe(
+            "Newest audit entries first",
+            vec![query_param("limit", "integer", "Number of entries, at most 500")],
+            json_response("Audit entries"),
+        )),
+        Route::ExportSql => admin(describe("Every message as SQL INSERT statements", vec![], text_response("application/sql"))),
+        Route::ExportCsv => admin(describe("Every message as CSV", vec![], text_response("text/csv"))),
+        Route::Favicon => describe("The site icon", vec![], text_response("image/x-icon")),
+        Route::RobotsTxt => describe("Crawler rules", vec![], text_response("text/plain")),
+        Route::Landing | Route::NotFound => Value::Null,
+    };
+    operation["operationId"] = json!(route.name());
+    operation
+}
+
+fn describe(summary: &str, parameters: Vec<Value>, responses: Value) -> Value {
+    json!({
+        "summary": summary,
+        "parameters": parameters,
+        "responses": responses,
+    })
+}
+
+fn admin(mut operation: Value) -> Value {
+    operation["security"] = json!([{"adminToken": []}]);
+    operation["responses"]["401"] = json!({"description": "Missing or wrong admin token"});
+    operation
+}
+
+fn list_params() -> Vec<Value> {
+    vec![
+        query_param("before", "integer", "Only messages older than this timestamp"),
+        query_param("after", "integer", "Only messages newer than this timestamp"),
+        query_param("since", "string", "Relative window like 30m, 1h or 7d"),
+        query_param("timestamps", "string", "Comma separated exact timestamps, at most 100"),
+        query_param("username", "string", "Only messages of this user"),
+        query_param("q", "string", "Substring the message must contain"),
+        query_param("min_len", "integer", "Shortest message length in characters"),
+        query_param("max_len", "integer", "Longest message length in characters"),
+        query_param("has_emoji", "boolean", "Only messages with, or with false without, an emoji"),
+        query_param("sample", "integer", "Random messages to return, at most 100"),
+    ]
+}
+
+fn query_param(name: &str, schema_type: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": {"type": schema_type},
+    })
+}
+
+fn path_param(name: &str, schema_type: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": {"type": schema_type},
+    })
+}
+
+fn json_response(description: &str) -> Value {
+    json!({"200": {"description": description, "content": {"application/json": {}}}})
+}
+
+fn text_response(content_type: &str) -> Value {
+    let mut content = Map::new();
+    content.insert(content_type.to_string(), json!({}));
+    json!({"200": {"description": "OK", "content": content}})
+}
+
+fn message_response() -> Value {
+    json!({"200": {
+        "description": "The message",
+        "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Message"}}},
+    }})
+}
+
+fn message_list_response() -> Value {
+    json!({"200": {
+        "description": "The messages",
+        "content": {"application/json": {"schema": {
+            "type": "array",
+            "items": {"$ref": "#/components/schemas/Message"},
+        }}},
+    }})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_well_formed_json() {
+        let document = openapi_document();
+        let reparsed = serde_json::from_str::<Value>(&document.to_string()).unwrap();
+        assert_eq!(reparsed, document);
+        assert_eq!(document["openapi"], "3.0.0");
+        assert!(document["components"]["schemas"]["Message"]["properties"]["timestamp"].is_object());
+    }
+
+    #[test]
+    fn lists_every_route() {
+        let document = openapi_document();
+        let paths = document["paths"].as_object().unwrap();
+        for &(method, path) in &[("get", "/messages"), ("post", "/"), ("get", "/messages/{id}"), ("get", "/openapi.json")] {
+            assert!(paths[path][method]["summary"].is_string(), "{} {} undocumented", method, path);
+        }
+        let operations = paths.values().map(|item| item.as_object().unwrap().len()).sum::<usize>();
+        assert_eq!(operations, ROUTES.iter().filter(|route| route.endpoint().is_some()).count());
+        for (path, item) in paths {
+            for operation in item.as_object().unwrap().values() {
+                assert!(operation["responses"].is_object(), "{} lacks responses", path);
+            }
+        }
+    }
+
+    #[test]
+    fn lists_the_root_methods() {
+        assert_eq!(root_methods(), vec![Method::Options, Method::Post, Method::Get]);
+    }
+}
@@ -0,0 +1,95 @@
+use prost::Message as ProstMessage;
+
+use super::data_source::models::Message;
+
+/// `Message` of `proto/messages.proto`; keep the two in sync.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProtoMessage {
+    #[prost(int32, tag = "1")]
+    pub id: i32,
+    #[prost(string, tag = "2")]
+    pub username: String,
+    #[prost(string, tag = "3")]
+    pub message: String,
+    #[prost(int64, tag = "4")]
+    pub timestamp: i64,
+    #[prost(string, tag = "5")]
+    pub ip: String,
+    #[prost(string, tag = "6")]
+    pub user_agent: String,
+}
+
+/// `MessageList` of `proto/messages.proto`.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProtoMessageList {
+    #[prost(message, repeated, tag = "1")]
+    pub messages: Vec<ProtoMessage>,
+}
+
+/// The list as an encoded `MessageList`; `show_meta` fills in the client details.
+pub fn encode_message_list(messages: &[Message], show_meta: bool) -> Vec<u8> {
+    let list = ProtoMessageList {
+        messages: messages
+            .iter()
+            .map(|message| ProtoMessage {
+                id: message.id,
+                username: message.username.clone(),
+                message: message.message.clone(),
+                timestamp: message.timestamp,
+                ip: meta_field(show_meta, &message.ip),
+                user_agent: meta_field(show_meta, &message.user_agent),
+            })
+            .collect(),
+    };
+    let mut buffer = Vec::with_capacity(list.encoded_len());
+    // only fails when the buffer is too small, and a Vec grows
+    list.encode(&mut buffer).unwrap();
+    buffer
+}
+
+fn meta_field(show_meta: bool, value: &Option<String>) -> String {
+    match *value {
+        Some(ref value) if show_meta => value.clone(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i32) -> Message {
+        Message {
+            id,
+            username: String::from("peter"),
+            message: format!("message {}", id),
+            timestamp: 1_000 + i64::from(id),
+            ip: Some(String::from("10.0.0.1")),
+            user_agent: Some(String::from("curl/7.58.0")),
+            views: 0,
+            content_hash: None,
+            signature: None,
+            visibility: String::from("public"),
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn decodes_to_the_listed_messages() {
+        let encoded = encode_message_list(&[message(1), message(2)], false);
+        let decoded = ProtoMessageList::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded.messages.len(), 2);
+        let first = &decoded.messages[0];
+        assert_eq!((first.id, first.username.as_str(), first.message.as_str(), first.timestamp), (1, "peter", "message 1", 1_001));
+        assert_eq!((first.ip.as_str(), first.user_agent.as_str()), ("", ""));
+        assert_eq!(decoded.messages[1].id, 2);
+    }
+
+    #[test]
+    fn includes_the_client_details_for_admins() {
+        let encoded = encode_message_list(&[message(1)], true);
+        let decoded = ProtoMessageList::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded.messages[0].ip, "10.0.0.1");
+        assert_eq!(decoded.messages[0].user_agent, "curl/7.58.0");
+    }
+}
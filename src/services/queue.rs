@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{err as futureErr, Either, Future, ok as futureOk};
+use futures::sync::oneshot;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Global limit on requests being served, with a bounded queue for the overflow.
+///
+/// A request over the limit waits up to `max_wait` for a slot; slots are handed
+/// directly from a finishing request to the oldest waiter.
+pub struct RequestQueue {
+    max_concurrent: usize,
+    max_queued: usize,
+    max_wait: Duration,
+    inner: Mutex<QueueInner>,
+}
+
+struct QueueInner {
+    active: usize,
+    waiting: VecDeque<oneshot::Sender<QueuePermit>>,
+}
+
+/// One of the `max_concurrent` slots; dropping it hands the slot on.
+pub struct QueuePermit {
+    queue: Option<Arc<RequestQueue>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueRejection {
+    Full,
+    TimedOut,
+}
+
+impl RequestQueue {
+    /// `max_concurrent == 0` disables the limit (and with it the queue).
+    pub fn new(max_concurrent: usize, max_queued: usize, max_wait: Duration) -> Self {
+        RequestQueue {
+            max_concurrent,
+            max_queued,
+            max_wait,
+            inner: Mutex::new(QueueInner {
+                active: 0,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn acquire(queue: &Arc<RequestQueue>, handle: &Handle) -> Box<dyn Future<Item=QueuePermit, Error=QueueRejection>> {
+        if queue.max_concurrent == 0 {
+            return Box::new(futureOk(QueuePermit { queue: None }));
+        }
+
+        let receiver = {
+            let mut inner = queue.inner.lock().unwrap();
+            if inner.active < queue.max_concurrent {
+                inner.active += 1;
+                return Box::new(futureOk(QueuePermit { queue: Some(queue.clone()) }));
+            }
+            // waiters that already timed out no longer count against the queue size
+            inner.waiting.retain(|waiter| !waiter.is_canceled());
+            if inner.waiting.len() >= queue.max_queued {
+                return Box::new(futureErr(QueueRejection::Full));
+            }
+            let (sender, receiver) = oneshot::channel();
+            inner.waiting.push_back(sender);
+            receiver
+        };
+
+        let timeout = match Timeout::new(queue.max_wait, handle) {
+            Ok(timeout) => timeout,
+            Err(error) => {
+                error!("Error creating queue timeout: {}", error);
+                return Box::new(futureErr(QueueRejection::TimedOut));
+            }
+        };
+        Box::new(receiver.select2(timeout).then(|result| match result {
+            Ok(Either::A((permit, _))) => Ok(permit),
+            _ => Err(QueueRejection::TimedOut),
+        }))
+    }
+
+    fn release(queue: &Arc<RequestQueue>) {
+        let mut inner = queue.inner.lock().unwrap();
+        while let Some(waiter) = inner.waiting.pop_front() {
+            match waiter.send(QueuePermit { queue: Some(queue.clone()) }) {
+                Ok(()) => return,
+                // the waiter gave up; disarm the permit so dropping it doesn't re-enter `release`
+                Err(mut permit) => {
+                    permit.queue.take();
+                }
+            }
+        }
+        inner.active -= 1;
+    }
+}
+
+impl Drop for QueuePermit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            RequestQueue::release(&queue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    #[test]
+    fn hands_a_finished_slot_to_the_waiter() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let queue = Arc::new(RequestQueue::new(1, 1, Duration::from_secs(5)));
+        let first = RequestQueue::acquire(&queue, &handle).wait().unwrap();
+        let waiter = RequestQueue::acquire(&queue, &handle);
+        drop(first);
+        assert!(core.run(waiter).is_ok());
+    }
+
+    #[test]
+    fn refuses_requests_beyond_the_queue() {
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let queue = Arc::new(RequestQueue::new(1, 1, Duration::from_secs(5)));
+        let _first = RequestQueue::acquire(&queue, &handle).wait().unwrap();
+        let _waiter = RequestQueue::acquire(&queue, &handle);
+        assert_eq!(RequestQueue::acquire(&queue, &handle).wait().err(), Some(QueueRejection::Full));
+    }
+
+    #[test]
+    fn gives_up_after_max_wait() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let queue = Arc::new(RequestQueue::new(1, 1, Duration::from_millis(20)));
+        let _first = RequestQueue::acquire(&queue, &handle).wait().unwrap();
+        let waiter = RequestQueue::acquire(&queue, &handle);
+        assert_eq!(core.run(waiter).err(), Some(QueueRejection::TimedOut));
+    }
+}
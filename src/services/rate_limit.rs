@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Methods that can get their own limit with `RATE_LIMIT_<METHOD>`.
+pub const RATE_LIMITED_METHODS: [&str; 6] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+
+/// Tracked buckets beyond which idle ones are dropped.
+const MAX_TRACKED_BUCKETS: usize = 10000;
+
+/// `requests` per `per`, refilled continuously.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+/// Parses a rate like `10/min`; the period is one of `s`/`sec`, `m`/`min`, `h`/`hour`.
+pub fn parse_rate(name: &str, value: &str) -> Result<Rate, String> {
+    let invalid = || format!("{} must look like 10/min, got '{}'", name, value);
+    let mut parts = value.splitn(2, '/');
+    let requests = parts
+        .next()
+        .and_then(|requests| requests.trim().parse::<u32>().ok())
+        .filter(|&requests| requests > 0)
+        .ok_or_else(invalid)?;
+    let per = match parts.next().map(str::trim) {
+        Some("s") | Some("sec") => Duration::from_secs(1),
+        Some("m") | Some("min") => Duration::from_secs(60),
+        Some("h") | Some("hour") => Duration::from_secs(60 * 60),
+        _ => return Err(invalid()),
+    };
+    Ok(Rate { requests, per })
+}
+
+/// Token buckets per client address and method.
+pub struct RateLimiter {
+    default_rate: Option<Rate>,
+    method_rates: HashMap<String, Rate>,
+    buckets: Mutex<HashMap<(IpAddr, String), Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(default_rate: Option<Rate>, method_rates: HashMap<String, Rate>) -> Self {
+        RateLimiter {
+            default_rate,
+            method_rates,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for a `method` request from `ip`. `Err` carries the seconds until
+    /// the next token, for `Retry-After`. Methods without a limit always pass.
+    pub fn check(&self, ip: IpAddr, method: &str) -> Result<(), u64> {
+        let (key, rate) = match self.method_rates.get(method) {
+            Some(rate) => (method, *rate),
+            None => match self.default_rate {
+                // methods without a limit of their own share the default bucket
+                Some(rate) => ("*", rate),
+                None => return Ok(()),
+            },
+        };
+        let capacity = f64::from(rate.requests);
+        let refill_per_sec = capacity / duration_secs(rate.per);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            // a bucket idle for a whole period is full again, forgetting it changes nothing
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < rate.per);
+        }
+        let bucket = buckets.entry((ip, key.to_string())).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = duration_secs(now.duration_since(bucket.updated));
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / refill_per_sec).ceil() as u64)
+        }
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(requests: u32) -> Rate {
+        Rate {
+            requests,
+            per: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("RATE_LIMIT", "10/min"), Ok(rate(10)));
+        assert_eq!(parse_rate("RATE_LIMIT", " 5 / s ").map(|rate| rate.per), Ok(Duration::from_secs(1)));
+        assert!(parse_rate("RATE_LIMIT", "0/min").is_err());
+        assert!(parse_rate("RATE_LIMIT", "10/day").is_err());
+        assert!(parse_rate("RATE_LIMIT", "10").is_err());
+    }
+
+    #[test]
+    fn limits_posts_independently_of_gets() {
+        let mut method_rates = HashMap::new();
+        method_rates.insert(String::from("POST"), rate(2));
+        method_rates.insert(String::from("GET"), rate(5));
+        let limiter = RateLimiter::new(None, method_rates);
+        let ip = "10.0.0.1".parse::<IpAddr>().unwrap();
+        assert!(limiter.check(ip, "POST").is_ok());
+        assert!(limiter.check(ip, "POST").is_ok());
+        assert_eq!(limiter.check(ip, "POST"), Err(30));
+        for _ in 0..5 {
+            assert!(limiter.check(ip, "GET").is_ok());
+        }
+        assert!(limiter.check(ip, "GET").is_err());
+        assert!(limiter.check("10.0.0.2".parse().unwrap(), "POST").is_ok());
+        // without a limit of its own or a default, DELETE is not limited
+        assert!(limiter.check(ip, "DELETE").is_ok());
+    }
+
+    #[test]
+    fn shares_the_default_between_other_methods() {
+        let mut method_rates = HashMap::new();
+        method_rates.insert(String::from("POST"), rate(1));
+        let limiter = RateLimiter::new(Some(rate(2)), method_rates);
+        let ip = "10.0.0.1".parse::<IpAddr>().unwrap();
+        assert!(limiter.check(ip, "GET").is_ok());
+        assert!(limiter.check(ip, "DELETE").is_ok());
+        assert!(limiter.check(ip, "GET").is_err());
+        assert!(limiter.check(ip, "POST").is_ok());
+    }
+}
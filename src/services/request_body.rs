@@ -0,0 +1,203 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use futures::future::{err as futureErr, FutureResult, ok as futureOk};
+use hyper::Chunk;
+use hyper::header::{ContentEncoding, ContentLength, ContentType, Encoding, Headers};
+
+use super::error::ServiceError;
+
+/// Upper bound on a decompressed request body, guarding against zip bombs.
+pub const MAX_DECOMPRESSED_BODY_SIZE: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyEncoding {
+    Identity,
+    Gzip,
+}
+
+/// Reads the `Content-Encoding` header of a request.
+/// Only `gzip` (and the no-op `identity`) are accepted.
+pub fn body_encoding(headers: &Headers) -> Result<BodyEncoding, ServiceError> {
+    let encodings = match headers.get::<ContentEncoding>() {
+        Some(&ContentEncoding(ref encodings)) => encodings,
+        None => return Ok(BodyEncoding::Identity),
+    };
+    let mut result = BodyEncoding::Identity;
+    for encoding in encodings {
+        match *encoding {
+            Encoding::Identity => {}
+            Encoding::Gzip if result == BodyEncoding::Identity => result = BodyEncoding::Gzip,
+            ref other => {
+                return Err(ServiceError::UnsupportedMediaType(format!(
+                    "Unsupported content encoding: {}",
+                    other
+                )));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// The `Content-Length` of a request. hyper answers a malformed or repeated one with
+/// 400 itself, before the service sees the request.
+pub fn declared_length(headers: &Headers) -> Option<u64> {
+    headers.get::<ContentLength>().map(|&ContentLength(length)| length)
+}
+
+/// Rejects a body whose size differs from its `Content-Length`. hyper reads a
+/// `Transfer-Encoding: chunked` body by its chunks and passes a `Content-Length` sent
+/// along with it through, so the two can disagree.
+pub fn check_content_length(body: Chunk, declared: Option<u64>) -> FutureResult<Chunk, ServiceError> {
+    match declared {
+        Some(declared) if body.len() as u64 != declared => futureErr(ServiceError::InvalidContentLength(format!(
+            "Body of {} bytes doesn't match Content-Length {}",
+            body.len(),
+            declared
+        ))),
+        _ => futureOk(body),
+    }
+}
+
+/// Refuses a non-empty body sent without `Content-Type`, which would otherwise be
+/// parsed as a form whatever it holds. An empty body needs no type.
+pub fn require_content_type(body: Chunk, headers_had_type: bool) -> FutureResult<Chunk, ServiceError> {
+    if body.is_empty() || headers_had_type {
+        futureOk(body)
+    } else {
+        futureErr(ServiceError::MissingContentType)
+    }
+}
+
+pub fn has_content_type(headers: &Headers) -> bool {
+    headers.has::<ContentType>()
+}
+
+pub fn decode_body(body: Chunk, encoding: BodyEncoding) -> FutureResult<Vec<u8>, ServiceError> {
+    match encoding {
+        BodyEncoding::Identity => futureOk(body.to_vec()),
+        BodyEncoding::Gzip => match gunzip(body.as_ref(), MAX_DECOMPRESSED_BODY_SIZE) {
+            Ok(decoded) => futureOk(decoded),
+            Err(error) => futureErr(error),
+        },
+    }
+}
+
+fn gunzip(compressed: &[u8], limit: u64) -> Result<Vec<u8>, ServiceError> {
+    let mut decoded = Vec::new();
+    // read one byte past the limit so an oversized body can be told apart from one that fits exactly
+    let mut decoder = GzDecoder::new(compressed).take(limit + 1);
+    match decoder.read_to_end(&mut decoded) {
+        Ok(_) if decoded.len() as u64 > limit => Err(ServiceError::PayloadTooLarge(format!(
+            "Decompressed body exceeds {} bytes",
+            limit
+        ))),
+        Ok(_) => Ok(decoded),
+        Err(error) => Err(ServiceError::BadRequest(format!(
+            "Corrupt gzip body: {}",
+            error
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use futures::{stream, Future, Stream};
+    use hyper::StatusCode;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decodes_a_gzip_body() {
+        let body = Chunk::from(gzip(b"username=peter&message=hello"));
+        assert_eq!(decode_body(body, BodyEncoding::Gzip).wait().unwrap(), b"username=peter&message=hello".to_vec());
+    }
+
+    #[test]
+    fn decodes_a_gzip_body_of_exactly_the_limit() {
+        let body = vec![b'a'; MAX_DECOMPRESSED_BODY_SIZE as usize];
+        assert_eq!(gunzip(&gzip(&body), MAX_DECOMPRESSED_BODY_SIZE).unwrap().len(), body.len());
+    }
+
+    #[test]
+    fn refuses_a_zip_bomb() {
+        // 16 MiB of zeros compress to a few KiB
+        let bomb = Chunk::from(gzip(&vec![0; 16 * MAX_DECOMPRESSED_BODY_SIZE as usize]));
+        assert!(bomb.len() < 64 * 1024);
+        let error = decode_body(bomb, BodyEncoding::Gzip).wait().unwrap_err();
+        assert_eq!(error.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[test]
+    fn refuses_a_corrupt_gzip_body() {
+        let error = decode_body(Chunk::from("not gzip"), BodyEncoding::Gzip).wait().unwrap_err();
+        assert_eq!(error.status(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn never_decodes_a_gzip_body_cut_off_by_a_disconnect() {
+        let compressed = gzip(b"username=peter&message=hello");
+        let received = compressed[..compressed.len() / 2].to_vec();
+        // what hyper yields when the client goes away mid-upload
+        let body = stream::iter_result(vec![Ok(Chunk::from(received)), Err(hyper::Error::Incomplete)]);
+        let mut decoded = false;
+        let result = body
+            .concat2()
+            .map_err(ServiceError::from)
+            .and_then(|body| {
+                decoded = true;
+                decode_body(body, BodyEncoding::Gzip)
+            })
+            .wait();
+        assert!(!decoded);
+        match result {
+            Err(ServiceError::Hyper(hyper::Error::Incomplete)) => {}
+            other => panic!("expected the disconnect, got {:?}", other.map(|body| body.len())),
+        }
+    }
+
+    #[test]
+    fn accepts_a_body_of_the_declared_length() {
+        assert!(check_content_length(Chunk::from("hello"), Some(5)).wait().is_ok());
+        assert!(check_content_length(Chunk::from("hello"), None).wait().is_ok());
+    }
+
+    #[test]
+    fn rejects_a_chunked_body_longer_than_declared() {
+        let error = check_content_length(Chunk::from("hello world"), Some(5)).wait().unwrap_err();
+        assert_eq!(error.code(), Some("invalid_content_length"));
+    }
+
+    #[test]
+    fn rejects_a_body_shorter_than_declared() {
+        let error = check_content_length(Chunk::from("hi"), Some(5)).wait().unwrap_err();
+        assert_eq!(error.status(), StatusCode::BadRequest);
+        assert_eq!(error.code(), Some("invalid_content_length"));
+    }
+
+    #[test]
+    fn reads_the_declared_length() {
+        let mut headers = Headers::new();
+        assert_eq!(declared_length(&headers), None);
+        headers.set(ContentLength(5));
+        assert_eq!(declared_length(&headers), Some(5));
+    }
+
+    #[test]
+    fn requires_a_type_for_non_empty_bodies_only() {
+        let missing = require_content_type(Chunk::from("username=peter&message=hi"), false).wait();
+        assert_eq!(missing.err().and_then(|error| error.code()), Some("missing_content_type"));
+        assert!(require_content_type(Chunk::from("username=peter&message=hi"), true).wait().is_ok());
+        assert!(require_content_type(Chunk::from(""), false).wait().is_ok());
+    }
+}
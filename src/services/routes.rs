@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::str;
+
+use hyper::Method;
+
+use super::config::RootMode;
+
+/// The endpoints the service answers, as matched from method and path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Route {
+    Ready,
+    Health,
+    Metrics,
+    Version,
+    OpenApi,
+    Index,
+    Insert,
+    List,
+    Messages,
+    MessageCount,
+    Message,
+    VerifyMessage,
+    PinMessage,
+    Sync,
+    Timeline,
+    Activity,
+    WordStats,
+    Feed,
+    BackfillTimestamps,
+    DbMaintenance,
+    AuditLog,
+    ExportSql,
+    ExportCsv,
+    Favicon,
+    RobotsTxt,
+    /// `/` with `ROOT_MODE=landing`, never matched by path alone.
+    Landing,
+    NotFound,
+}
+
+/// Every route in matching order: `/messages/count` has to be tried before `/messages/{id}`.
+pub const ROUTES: [Route; 26] = [
+    Route::Ready,
+    Route::Health,
+    Route::Metrics,
+    Route::Version,
+    Route::OpenApi,
+    Route::Index,
+    Route::Insert,
+    Route::List,
+    Route::Messages,
+    Route::MessageCount,
+    Route::Message,
+    Route::VerifyMessage,
+    Route::PinMessage,
+    Route::Sync,
+    Route::Timeline,
+    Route::Activity,
+    Route::WordStats,
+    Route::Feed,
+    Route::BackfillTimestamps,
+    Route::DbMaintenance,
+    Route::AuditLog,
+    Route::ExportSql,
+    Route::ExportCsv,
+    Route::Favicon,
+    Route::RobotsTxt,
+    Route::NotFound,
+];
+
+impl Route {
+    pub fn of(method: &Method, path: &str) -> Route {
+        ROUTES
+            .iter()
+            .cloned()
+            .find(|route| route.matches(method, path))
+            .unwrap_or(Route::NotFound)
+    }
+
+    /// With `ROOT_MODE=landing` `/` is the landing page and the list moves to
+    /// `/messages`, which keeps answering `?ids=` batches.
+    pub fn with_root_mode(self, root_mode: RootMode, query: Option<&str>) -> Route {
+        let has_ids = query.map_or(false, |query| {
+            query.split('&').any(|pair| pair.starts_with("ids="))
+        });
+        match (root_mode, self) {
+            (RootMode::Landing, Route::List) => Route::Landing,
+            (RootMode::Landing, Route::Messages) if !has_ids => Route::List,
+            (_, route) => route,
+        }
+    }
+
+    /// Method and path of the route, `{id}` standing for one path segment.
+    /// This is the dispatch table, `/openapi.json` is generated from it too.
+    pub fn endpoint(&self) -> Option<(Method, &'static str)> {
+        let endpoint = match *self {
+            Route::Ready => (Method::Get, "/ready"),
+            Route::Health => (Method::Get, "/health"),
+            Route::Metrics => (Method::Get, "/metrics"),
+            Route::Version => (Method::Get, "/version"),
+            Route::OpenApi => (Method::Get, "/openapi.json"),
+            Route::Index => (Method::Options, "/"),
+            Route::Insert => (Method::Post, "/"),
+            Route::List => (Method::Get, "/"),
+            Route::Messages => (Method::Get, "/messages"),
+            Route::MessageCount => (Method::Get, "/messages/count"),
+            Route::Message => (Method::Get, "/messages/{id}"),
+            Route::VerifyMessage => (Method::Get, "/messages/{id}/verify"),
+            Route::PinMessage => (Method::Post, "/messages/{id}/pin"),
+            Route::Sync => (Method::Post, "/messages/sync"),
+            Route::Timeline => (Method::Get, "/timeline"),
+            Route::Activity => (Method::Get, "/stats/activity"),
+            Route::WordStats => (Method::Get, "/stats/words"),
+            Route::Feed => (Method::Get, "/feed.json"),
+            Route::BackfillTimestamps => (Method::Post, "/admin/backfill-timestamps"),
+            Route::DbMaintenance => (Method::Post, "/admin/maintenance"),
+            Route::AuditLog => (Method::Get, "/admin/audit"),
+            Route::ExportSql => (Method::Get, "/export.sql"),
+            Route::ExportCsv => (Method::Get, "/export.csv"),
+            Route::Favicon => (Method::Get, "/favicon.ico"),
+            Route::RobotsTxt => (Method::Get, "/robots.txt"),
+            Route::Landing | Route::NotFound => return None,
+        };
+        Some(endpoint)
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let (route_method, template) = match self.endpoint() {
+            Some(endpoint) => endpoint,
+            None => return false,
+        };
+        if route_method != *method {
+            return false;
+        }
+        // a placeholder matches one non-empty segment
+        let mut segments = path.split('/');
+        let matched = template.split('/').all(|expected| match segments.next() {
+            Some(segment) if expected.starts_with('{') => !segment.is_empty(),
+            Some(segment) => segment == expected,
+            None => false,
+        });
+        matched && segments.next().is_none()
+    }
+
+    /// The name used for the route in configuration, e.g. `ROUTE_TIMEOUTS`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Route::Ready => "ready",
+            Route::Health => "health",
+            Route::Metrics => "metrics",
+            Route::Version => "version",
+            Route::OpenApi => "openapi",
+            Route::Index => "index",
+            Route::Insert => "insert",
+            Route::List => "list",
+            Route::Messages => "messages",
+            Route::MessageCount => "count",
+            Route::Message => "message",
+            Route::VerifyMessage => "verify_message",
+            Route::PinMessage => "pin_message",
+            Route::Sync => "sync",
+            Route::Timeline => "timeline",
+            Route::Activity => "activity",
+            Route::WordStats => "words",
+            Route::Feed => "feed",
+            Route::BackfillTimestamps => "backfill_timestamps",
+            Route::DbMaintenance => "db_maintenance",
+            Route::AuditLog => "audit_log",
+            Route::ExportSql => "export_sql",
+            Route::ExportCsv => "export_csv",
+            Route::Favicon => "favicon",
+            Route::RobotsTxt => "robots",
+            Route::Landing => "landing",
+            Route::NotFound => "not_found",
+        }
+    }
+
+    /// Routes answering during startup and maintenance too, none of them needs the database.
+    pub fn always_available(&self) -> bool {
+        match *self {
+            Route::Health
+            | Route::Ready
+            | Route::Metrics
+            | Route::Version
+            | Route::OpenApi
+            | Route::Index
+            | Route::Favicon
+            | Route::RobotsTxt
+            | Route::Landing => true,
+            _ => false,
+        }
+    }
+
+    /// Lookups whose answer time could tell whether a message exists, padded with `CONSTANT_TIME_LOOKUPS`.
+    pub fn is_sensitive_lookup(&self) -> bool {
+        match *self {
+            Route::Messages | Route::Message | Route::VerifyMessage => true,
+            _ => false,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Route> {
+        ROUTES.iter().cloned().find(|route| route.name() == name)
+    }
+}
+
+/// Parses `ROUTE_TIMEOUTS`, a comma separated list of `route=milliseconds`.
+pub fn parse_route_timeouts(value: &str) -> Result<HashMap<Route, u64>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let route = Route::from_name(name).ok_or_else(|| format!("Unknown route '{}' in ROUTE_TIMEOUTS", name))?;
+            let timeout_ms = parts
+                .next()
+                .and_then(|timeout_ms| timeout_ms.trim().parse::<u64>().ok())
+                .ok_or_else(|| format!("ROUTE_TIMEOUTS entries must look like health=500, got '{}'", entry))?;
+            Ok((route, timeout_ms))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_route_timeouts() {
+        let route_timeouts = parse_route_timeouts("health=500, export_sql=60000,").unwrap();
+        assert_eq!(route_timeouts.len(), 2);
+        assert_eq!(route_timeouts[&Route::Health], 500);
+        assert!(parse_route_timeouts("no_such_route=500").is_err());
+        assert!(parse_route_timeouts("health").is_err());
+        assert!(parse_route_timeouts("health=soon").is_err());
+    }
+
+    #[test]
+    fn routes_every_endpoint_to_its_route() {
+        for route in ROUTES.iter() {
+            if let Some((method, path)) = route.endpoint() {
+                assert_eq!(Route::of(&method, &path.replace("{id}", "42")), *route);
+            }
+        }
+        assert_eq!(Route::of(&Method::Get, "/messages/42/pin"), Route::NotFound);
+        assert_eq!(Route::of(&Method::Delete, "/messages"), Route::NotFound);
+    }
+
+    #[test]
+    fn moves_the_list_to_messages_in_landing_mode() {
+        let route = |root_mode, path, query| Route::of(&Method::Get, path).with_root_mode(root_mode, query);
+        assert_eq!(route(RootMode::List, "/", None), Route::List);
+        assert_eq!(route(RootMode::List, "/messages", None), Route::Messages);
+        assert_eq!(route(RootMode::Landing, "/", None), Route::Landing);
+        assert_eq!(route(RootMode::Landing, "/messages", None), Route::List);
+        assert_eq!(route(RootMode::Landing, "/messages", Some("ids=1,2")), Route::Messages);
+    }
+}
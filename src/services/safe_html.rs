@@ -0,0 +1,44 @@
+use std::collections::{HashMap, HashSet};
+
+use ammonia::Builder;
+
+/// The allowlist applied to message text with `ALLOW_SAFE_HTML`: basic inline
+/// formatting and links. Everything else is stripped, `script` and `style` together
+/// with their content, and so are all event handler and style attributes.
+pub fn safe_html_sanitizer() -> Builder<'static> {
+    let tags = ["a", "b", "i", "em", "strong", "code", "br"].iter().cloned().collect::<HashSet<_>>();
+    let mut tag_attributes = HashMap::new();
+    tag_attributes.insert("a", ["href", "title"].iter().cloned().collect::<HashSet<_>>());
+    let mut sanitizer = Builder::default();
+    sanitizer
+        .tags(tags)
+        .tag_attributes(tag_attributes)
+        .generic_attributes(HashSet::new())
+        .link_rel(Some("noopener noreferrer nofollow"));
+    sanitizer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(html: &str) -> String {
+        safe_html_sanitizer().clean(html).to_string()
+    }
+
+    #[test]
+    fn keeps_the_allowed_tags() {
+        assert_eq!(clean("<b>bold</b> and <i>italic</i>"), "<b>bold</b> and <i>italic</i>");
+        let link = clean(r#"<a href="https://example.com" title="example" onclick="alert(1)">link</a>"#);
+        assert!(link.starts_with(r#"<a href="https://example.com" title="example""#));
+        assert!(link.contains(r#"rel="noopener noreferrer nofollow""#));
+        assert!(!link.contains("onclick"));
+    }
+
+    #[test]
+    fn strips_scripts_and_event_handlers() {
+        assert_eq!(clean("hi<script>alert(1)</script>"), "hi");
+        assert_eq!(clean(r#"<b onclick="alert(1)" style="color: red">bold</b>"#), "<b>bold</b>");
+        assert_eq!(clean(r#"<img src="x" onerror="alert(1)">"#), "");
+    }
+}
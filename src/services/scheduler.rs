@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{err as futureErr, Either, Future, ok as futureOk};
+use futures::sync::oneshot;
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::data_source::{checkout, DbConnection, DbPool};
+use super::error::ServiceError;
+use super::leak_detection::LeakGuard;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Hands out the pool's connections to readers and writers in a weighted round robin.
+///
+/// While both kinds are waiting, each round serves `read_weight` readers and
+/// `write_weight` writers, so a burst of one kind can't starve the other. Without
+/// contention slots are handed out first come, first served.
+pub struct CheckoutScheduler {
+    slots: usize,
+    read_weight: usize,
+    write_weight: usize,
+    max_wait: Duration,
+    inner: Mutex<SchedulerInner>,
+}
+
+struct SchedulerInner {
+    active: usize,
+    readers: VecDeque<oneshot::Sender<CheckoutPermit>>,
+    writers: VecDeque<oneshot::Sender<CheckoutPermit>>,
+    round_reads: usize,
+    round_writes: usize,
+}
+
+/// The right to check out one pooled connection; dropping it passes the slot on.
+pub struct CheckoutPermit {
+    scheduler: Option<Arc<CheckoutScheduler>>,
+}
+
+impl CheckoutScheduler {
+    pub fn new(slots: usize, read_weight: usize, write_weight: usize, max_wait: Duration) -> Self {
+        CheckoutScheduler {
+            slots,
+            read_weight,
+            write_weight,
+            max_wait,
+            inner: Mutex::new(SchedulerInner {
+                active: 0,
+                readers: VecDeque::new(),
+                writers: VecDeque::new(),
+                round_reads: 0,
+                round_writes: 0,
+            }),
+        }
+    }
+
+    /// Waits for a slot, failing with `PoolExhausted` after `max_wait`.
+    pub fn acquire(
+        scheduler: &Arc<CheckoutScheduler>,
+        access: Access,
+        handle: &Handle,
+    ) -> Box<dyn Future<Item=CheckoutPermit, Error=ServiceError>> {
+        let receiver = match CheckoutScheduler::enqueue(scheduler, access) {
+            Ok(permit) => return Box::new(futureOk(permit)),
+            Err(receiver) => receiver,
+        };
+        let timeout = match Timeout::new(scheduler.max_wait, handle) {
+            Ok(timeout) => timeout,
+            Err(error) => return Box::new(futureErr(ServiceError::Internal(error.to_string()))),
+        };
+        Box::new(receiver.select2(timeout).then(|result| match result {
+            Ok(Either::A((permit, _))) => Ok(permit),
+            _ => Err(ServiceError::PoolExhausted),
+        }))
+    }
+
+    /// Waits for a slot as long as it takes, blocking the calling thread. For the work
+    /// running on threads of its own, off the reactor.
+    pub fn acquire_blocking(scheduler: &Arc<CheckoutScheduler>, access: Access) -> Result<CheckoutPermit, ServiceError> {
+        match CheckoutScheduler::enqueue(scheduler, access) {
+            Ok(permit) => Ok(permit),
+            Err(receiver) => receiver.wait().map_err(|_| ServiceError::PoolExhausted),
+        }
+    }
+
+    /// Takes a free slot right away, or queues for one and returns what it arrives on.
+    fn enqueue(
+        scheduler: &Arc<CheckoutScheduler>,
+        access: Access,
+    ) -> Result<CheckoutPermit, oneshot::Receiver<CheckoutPermit>> {
+        let mut inner = scheduler.inner.lock().unwrap();
+        inner.readers.retain(|waiter| !waiter.is_canceled());
+        inner.writers.retain(|waiter| !waiter.is_canceled());
+        let nobody_waiting = inner.readers.is_empty() && inner.writers.is_empty();
+        if nobody_waiting && inner.active < scheduler.slots {
+            inner.active += 1;
+            return Ok(CheckoutPermit { scheduler: Some(scheduler.clone()) });
+        }
+        let (sender, receiver) = oneshot::channel();
+        match access {
+            Access::Read => inner.readers.push_back(sender),
+            Access::Write => inner.writers.push_back(sender),
+        }
+        Err(receiver)
+    }
+
+    fn release(scheduler: &Arc<CheckoutScheduler>) {
+        let mut inner = scheduler.inner.lock().unwrap();
+        while let Some(waiter) = scheduler.next_waiter(&mut inner) {
+            match waiter.send(CheckoutPermit { scheduler: Some(scheduler.clone()) }) {
+                Ok(()) => return,
+                // the waiter timed out; disarm the permit so dropping it doesn't re-enter `release`
+                Err(mut permit) => {
+                    permit.scheduler.take();
+                }
+            }
+        }
+        inner.active -= 1;
+    }
+
+    fn next_waiter(&self, inner: &mut SchedulerInner) -> Option<oneshot::Sender<CheckoutPermit>> {
+        match (inner.readers.is_empty(), inner.writers.is_empty()) {
+            (true, true) => None,
+            (false, true) => inner.readers.pop_front(),
+            (true, false) => inner.writers.pop_front(),
+            (false, false) => {
+                if inner.round_reads >= self.read_weight && inner.round_writes >= self.write_weight {
+                    inner.round_reads = 0;
+                    inner.round_writes = 0;
+                }
+                if inner.round_reads < self.read_weight {
+                    inner.round_reads += 1;
+                    inner.readers.pop_front()
+                } else {
+                    inner.round_writes += 1;
+                    inner.writers.pop_front()
+                }
+            }
+        }
+    }
+}
+
+/// A pooled connection together with its scheduler slot.
+pub struct ScheduledConnection {
+    // declared first so the connection is back in the pool before the slot is handed on
+    connection: DbConnection,
+    _permit: CheckoutPermit,
+    _leak_guard: Option<LeakGuard>,
+}
+
+impl ScheduledConnection {
+    pub fn new(connection: DbConnection, permit: CheckoutPermit, leak_guard: Option<LeakGuard>) -> Self {
+        ScheduledConnection {
+            connection,
+            _permit: permit,
+            _leak_guard: leak_guard,
+        }
+    }
+}
+
+/// Checks a connection out of `pool` once the scheduler grants `access` a slot, blocking
+/// the calling thread meanwhile; see `CheckoutScheduler::acquire_blocking`.
+pub fn checkout_blocking(
+    scheduler: &Arc<CheckoutScheduler>,
+    pool: &DbPool,
+    access: Access,
+) -> Result<ScheduledConnection, ServiceError> {
+    let permit = CheckoutScheduler::acquire_blocking(scheduler, access)?;
+    let connection = checkout(pool)?;
+    Ok(ScheduledConnection::new(connection, permit, None))
+}
+
+impl Drop for ScheduledConnection {
+    fn drop(&mut self) {
+        self.connection.finish_request();
+    }
+}
+
+impl Deref for ScheduledConnection {
+    type Target = DbConnection;
+
+    fn deref(&self) -> &DbConnection {
+        &self.connection
+    }
+}
+
+impl Drop for CheckoutPermit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            CheckoutScheduler::release(&scheduler);
+        }
+    }
+}
+
+/// Parses `READ_WRITE_RATIO` as `reads:writes`, e.g. `4:1`.
+pub fn parse_read_write_ratio(value: &str) -> Result<(usize, usize), String> {
+    let mut parts = value.splitn(2, ':');
+    let reads = parts.next().and_then(|reads| reads.trim().parse::<usize>().ok());
+    let writes = parts.next().and_then(|writes| writes.trim().parse::<usize>().ok());
+    match (reads, writes) {
+        (Some(reads), Some(writes)) if reads > 0 && writes > 0 => Ok((reads, writes)),
+        _ => Err(format!("READ_WRITE_RATIO must look like 4:1, got '{}'", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    #[test]
+    fn serves_waiting_reads_and_writes_at_the_configured_ratio() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let scheduler = Arc::new(CheckoutScheduler::new(1, 3, 1, Duration::from_secs(5)));
+        let held = CheckoutScheduler::acquire(&scheduler, Access::Write, &handle).wait().unwrap();
+        let served = Rc::new(RefCell::new(Vec::new()));
+        let waiters = (0..8)
+            .flat_map(|_| vec![Access::Read, Access::Write])
+            .map(|access| {
+                let served = served.clone();
+                CheckoutScheduler::acquire(&scheduler, access, &handle).map(move |permit| {
+                    served.borrow_mut().push(access);
+                    drop(permit);
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(held);
+        core.run(future::join_all(waiters)).unwrap();
+
+        let order = served
+            .borrow()
+            .iter()
+            .map(|access| match *access {
+                Access::Read => 'r',
+                Access::Write => 'w',
+            })
+            .collect::<String>();
+        // three reads per write while both wait, then the writes left over
+        assert_eq!(order, "rrrwrrrwrrwwwwww");
+    }
+
+    #[test]
+    fn parses_the_read_write_ratio() {
+        assert_eq!(parse_read_write_ratio("4:1"), Ok((4, 1)));
+        assert_eq!(parse_read_write_ratio(" 2 : 3 "), Ok((2, 3)));
+        assert!(parse_read_write_ratio("4").is_err());
+        assert!(parse_read_write_ratio("0:1").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::auth::constant_time_eq;
+use super::data_source::models::Message;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The hex HMAC-SHA256 of a message's id, timestamp, username and text under `key`.
+/// Every field is length prefixed, so no two messages share the signed bytes.
+pub fn sign(key: &str, id: i32, timestamp: i64, username: &str, message: &str) -> String {
+    // HMAC takes keys of any length
+    let mut mac = HmacSha256::new_varkey(key.as_bytes()).unwrap();
+    mac.input(&id.to_be_bytes());
+    mac.input(&timestamp.to_be_bytes());
+    for field in &[username, message] {
+        mac.input(&(field.len() as u64).to_be_bytes());
+        mac.input(field.as_bytes());
+    }
+    mac.result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The raw HMAC-SHA256 of `data` under `key`.
+pub fn hmac_sha256(key: &str, data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_varkey(key.as_bytes()).unwrap();
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// Whether the stored signature still matches the row; unsigned messages don't.
+pub fn verify(key: &str, message: &Message) -> bool {
+    match message.signature {
+        Some(ref signature) => {
+            let expected = sign(key, message.id, message.timestamp, &message.username, &message.message);
+            constant_time_eq(signature.as_bytes(), expected.as_bytes())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "signing key";
+
+    fn signed_message() -> Message {
+        Message {
+            id: 7,
+            username: String::from("peter"),
+            message: String::from("hello"),
+            timestamp: 1_000,
+            ip: None,
+            user_agent: None,
+            views: 0,
+            content_hash: None,
+            signature: Some(sign(KEY, 7, 1_000, "peter", "hello")),
+            visibility: String::from("public"),
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn verifies_what_it_signed() {
+        assert!(verify(KEY, &signed_message()));
+    }
+
+    #[test]
+    fn detects_a_tampered_row() {
+        let mut edited = signed_message();
+        edited.message = String::from("goodbye");
+        assert!(!verify(KEY, &edited));
+        let mut backdated = signed_message();
+        backdated.timestamp -= 1;
+        assert!(!verify(KEY, &backdated));
+        assert!(!verify("another key", &signed_message()));
+    }
+
+    #[test]
+    fn keeps_fields_apart() {
+        assert_ne!(sign(KEY, 1, 1, "ab", "c"), sign(KEY, 1, 1, "a", "bc"));
+    }
+
+    #[test]
+    fn does_not_verify_an_unsigned_message() {
+        let mut unsigned = signed_message();
+        unsigned.signature = None;
+        assert!(!verify(KEY, &unsigned));
+    }
+}
@@ -0,0 +1,44 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::future::{Future, Shared};
+
+use super::error::ServiceError;
+
+type Flight<T> = Shared<Box<dyn Future<Item=T, Error=ServiceError>>>;
+
+/// Lets identical concurrent queries share one execution: whoever asks for a key
+/// while its query is still running gets the running query's result. Inserts with
+/// the same `Idempotency-Key` are shared the same way.
+///
+/// Lives on the event loop thread, shared by the connections' `MicroService`s.
+pub struct SingleFlight<T> {
+    in_flight: RefCell<HashMap<String, Flight<T>>>,
+}
+
+impl<T: 'static> SingleFlight<T> {
+    pub fn new() -> Self {
+        SingleFlight {
+            in_flight: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Joins the query running for `key`, or starts it with `start`.
+    pub fn run<F>(flights: &Rc<SingleFlight<T>>, key: String, start: F) -> Flight<T>
+        where F: FnOnce() -> Box<dyn Future<Item=T, Error=ServiceError>> {
+        if let Some(flight) = flights.in_flight.borrow().get(&key) {
+            debug!("Joining in-flight query {}", key);
+            return flight.clone();
+        }
+        let finished = flights.clone();
+        let finished_key = key.clone();
+        let query: Box<dyn Future<Item=T, Error=ServiceError>> = Box::new(start().then(move |result| {
+            finished.in_flight.borrow_mut().remove(&finished_key);
+            result
+        }));
+        let flight = query.shared();
+        flights.in_flight.borrow_mut().insert(key, flight.clone());
+        flight
+    }
+}
@@ -0,0 +1,108 @@
+use super::data_source::models::Message;
+
+/// Sent before the statements: with it, backslashes in the literals below are plain
+/// characters whatever the importing server's default is.
+pub const SQL_EXPORT_HEADER: &str = "SET standard_conforming_strings = on;\nBEGIN;\n";
+
+/// Sent after the statements, moves the id sequence past the imported ids.
+pub const SQL_EXPORT_FOOTER: &str =
+    "SELECT setval(pg_get_serial_sequence('messages', 'id'), COALESCE((SELECT MAX(id) FROM messages), 1));\nCOMMIT;\n";
+
+/// One `INSERT` statement per line, restoring every column of `message`.
+pub fn insert_statement(message: &Message) -> String {
+    format!(
+        "INSERT INTO messages (id, username, message, timestamp, ip, user_agent, views, signature, visibility, pinned) \
+         VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {}, {});\n",
+        message.id,
+        quote_literal(&message.username),
+        quote_literal(&message.message),
+        message.timestamp,
+        quote_optional(&message.ip),
+        quote_optional(&message.user_agent),
+        message.views,
+        quote_optional(&message.signature),
+        quote_literal(&message.visibility),
+        message.pinned,
+    )
+}
+
+/// A standard SQL string literal: quotes are doubled and nothing else is special.
+/// Line breaks are spliced in with `chr()` to keep each statement on its own line.
+fn quote_literal(value: &str) -> String {
+    let escaped = value
+        .replace('\'', "''")
+        .replace('\r', "' || chr(13) || '")
+        .replace('\n', "' || chr(10) || '");
+    format!("'{}'", escaped)
+}
+
+fn quote_optional(value: &Option<String>) -> String {
+    match *value {
+        Some(ref value) => quote_literal(value),
+        None => String::from("NULL"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+
+    use diesel::prelude::*;
+
+    use super::*;
+    use super::super::data_source::{build_pool, checkout};
+
+    fn message() -> Message {
+        Message {
+            id: 42,
+            username: String::from("o'brien"),
+            message: String::from("it's\r\nfine'); DROP TABLE messages; --\\"),
+            timestamp: 1_000,
+            ip: None,
+            user_agent: Some(String::from("curl/7.58.0")),
+            views: 3,
+            content_hash: None,
+            signature: None,
+            visibility: String::from("private"),
+            pinned: true,
+        }
+    }
+
+    #[test]
+    fn quotes_string_literals() {
+        assert_eq!(quote_literal("it's"), "'it''s'");
+        assert_eq!(quote_literal("a\\b"), "'a\\b'");
+        assert_eq!(quote_literal("one\ntwo"), "'one' || chr(10) || 'two'");
+        assert_eq!(quote_optional(&None), "NULL");
+    }
+
+    #[test]
+    fn writes_one_statement_per_line() {
+        let statement = insert_statement(&message());
+        assert!(statement.ends_with(");\n"));
+        assert_eq!(statement.lines().count(), 1);
+    }
+
+    #[test]
+    #[ignore]
+    fn reimports_into_a_fresh_table() {
+        use crate::schema::messages;
+        let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| String::from("postgresql://postgres@localhost:5432"));
+        let db_connection = checkout(&build_pool(&database_url, 1, Duration::from_secs(5), 5)).unwrap();
+        db_connection.begin_test_transaction().unwrap();
+        // shadows the real table for the rest of the transaction
+        diesel::sql_query("CREATE TEMPORARY TABLE messages (LIKE public.messages INCLUDING ALL) ON COMMIT DROP")
+            .execute(&*db_connection)
+            .unwrap();
+        let exported = message();
+        diesel::sql_query(insert_statement(&exported)).execute(&*db_connection).unwrap();
+        let imported = messages::table.first::<Message>(&*db_connection).unwrap();
+        assert_eq!(
+            (imported.id, imported.username, imported.message, imported.timestamp),
+            (exported.id, exported.username, exported.message, exported.timestamp)
+        );
+        assert_eq!((imported.ip, imported.user_agent, imported.views), (exported.ip, exported.user_agent, exported.views));
+        assert_eq!((imported.visibility, imported.pinned), (exported.visibility, exported.pinned));
+    }
+}
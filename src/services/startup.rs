@@ -0,0 +1,99 @@
+use std::cmp;
+use std::fmt;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use super::index_advisory::advise_indexes;
+use super::scheduler::{checkout_blocking, Access};
+use super::state::ServiceState;
+
+/// Longest pause between two connection attempts.
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Runs the startup work and then marks the service ready.
+/// Until then `/ready` and the data endpoints answer 503 with code `starting`.
+pub fn initialize(state: &ServiceState) {
+    wait_for_database(state);
+    if state.config.index_advisory {
+        advise_indexes(&state.pool);
+    }
+    state.ready.store(true, Ordering::SeqCst);
+    info!("Microservice ready");
+}
+
+/// Opens the first connection, which also warms the pool. A database that is still
+/// coming up gets `DB_CONNECT_ATTEMPTS` tries with a doubling delay in between; after
+/// that the process exits so the supervisor can restart it.
+fn wait_for_database(state: &ServiceState) {
+    let attempts = state.config.db_connect_attempts;
+    let delay = Duration::from_millis(state.config.db_connect_retry_ms);
+    match retry_with_backoff(attempts, delay, || checkout_blocking(&state.scheduler, &state.pool, Access::Read)) {
+        Ok(_) => info!("Database connection pool ready"),
+        Err(error) => {
+            error!("Database connection attempt {} of {} failed: {}, giving up", attempts, attempts, error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Calls `attempt` until it succeeds, at most `attempts` times, sleeping `delay` after
+/// the first failure and twice as long after each one after that. The last error is
+/// returned, the earlier ones are logged.
+fn retry_with_backoff<T, E, F>(attempts: u32, mut delay: Duration, mut attempt: F) -> Result<T, E>
+    where E: fmt::Display, F: FnMut() -> Result<T, E> {
+    let mut tried = 1;
+    loop {
+        match attempt() {
+            Err(error) if tried < attempts => {
+                warn!(
+                    "Database connection attempt {} of {} failed: {}, retrying in {}ms",
+                    tried,
+                    attempts,
+                    error,
+                    delay.as_secs() * 1000 + u64::from(delay.subsec_millis())
+                );
+                thread::sleep(delay);
+                delay = cmp::min(delay * 2, MAX_CONNECT_RETRY_DELAY);
+                tried += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn connects_once_the_database_is_up() {
+        let mut calls = 0;
+        let started = Instant::now();
+        let connected = retry_with_backoff(5, Duration::from_millis(10), || {
+            calls += 1;
+            if calls < 3 {
+                Err("connection refused")
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(connected, Ok(3));
+        // 10ms after the first failure, 20ms after the second
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn gives_up_after_the_configured_attempts() {
+        let mut calls = 0;
+        let connected = retry_with_backoff(3, Duration::from_millis(1), || -> Result<(), String> {
+            calls += 1;
+            Err(format!("attempt {} refused", calls))
+        });
+        assert_eq!(connected, Err(String::from("attempt 3 refused")));
+        assert_eq!(calls, 3);
+    }
+}
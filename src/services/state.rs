@@ -0,0 +1,128 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use super::circuit_breaker::CircuitBreaker;
+use super::config::Config;
+use super::data_source::{build_pool, DbPool};
+use super::dual_write::SecondaryStore;
+use super::health::WriteCheck;
+use super::leak_detection::LeakDetector;
+use super::limits::{ExportLimiter, PerIpLimiter};
+use super::metrics::PoolMetrics;
+use super::queue::RequestQueue;
+use super::rate_limit::RateLimiter;
+use super::scheduler::CheckoutScheduler;
+use super::write_buffer::WriteBuffer;
+
+/// State shared by every connection's `MicroService`.
+pub struct ServiceState {
+    pub config: Config,
+    pub pool: DbPool,
+    /// Set with `DUAL_WRITE=1`.
+    pub secondary: Option<SecondaryStore>,
+    /// Set with `WRITE_BUFFER_PATH`.
+    pub write_buffer: Option<WriteBuffer>,
+    pub scheduler: Arc<CheckoutScheduler>,
+    /// Set with `CB_FAILURE_THRESHOLD`.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Set with `POOL_LEAK_THRESHOLD_MS`.
+    pub leak_detector: Option<Arc<LeakDetector>>,
+    /// Set with `METRICS=1`.
+    pub pool_metrics: Option<PoolMetrics>,
+    pub per_ip_limiter: Arc<PerIpLimiter>,
+    pub export_limiter: Arc<ExportLimiter>,
+    pub rate_limiter: RateLimiter,
+    pub request_queue: Arc<RequestQueue>,
+    pub started_at: Instant,
+    /// Set once `startup::initialize` has finished.
+    pub ready: AtomicBool,
+    /// The last write of `/health?deep=true`.
+    pub last_write_check: Mutex<Option<WriteCheck>>,
+    /// When `POST /admin/maintenance` last ran.
+    last_db_maintenance: Mutex<Option<Instant>>,
+}
+
+/// Shortest pause between two runs of `POST /admin/maintenance`.
+const DB_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+impl ServiceState {
+    pub fn new(config: Config) -> Self {
+        let per_ip_limiter = Arc::new(PerIpLimiter::new(config.max_concurrent_per_ip));
+        let export_limiter = Arc::new(ExportLimiter::new(config.max_concurrent_exports));
+        let rate_limiter = RateLimiter::new(config.rate_limit, config.method_rate_limits.clone());
+        let request_queue = Arc::new(RequestQueue::new(
+            config.max_concurrent_requests,
+            config.queue_max,
+            Duration::from_millis(config.queue_wait_ms),
+        ));
+        let pool = build_pool(
+            &config.database_url,
+            config.db_pool_size,
+            Duration::from_millis(config.db_pool_timeout_ms),
+            config.db_connect_timeout_secs,
+        );
+        let secondary = config.secondary_database_url.as_ref().map(|url| {
+            SecondaryStore::new(
+                url,
+                config.db_pool_size,
+                Duration::from_millis(config.db_pool_timeout_ms),
+                config.db_connect_timeout_secs,
+            )
+        });
+        let write_buffer = config
+            .write_buffer_path
+            .as_ref()
+            .map(|path| WriteBuffer::new(path, config.write_buffer_max_bytes));
+        let (read_weight, write_weight) = config.read_write_ratio;
+        let scheduler = Arc::new(CheckoutScheduler::new(
+            config.db_pool_size as usize,
+            read_weight,
+            write_weight,
+            Duration::from_millis(config.db_pool_timeout_ms),
+        ));
+        let circuit_breaker = config
+            .cb_failure_threshold
+            .map(|threshold| CircuitBreaker::new(threshold, Duration::from_secs(config.cb_open_secs)));
+        let leak_detector = config
+            .pool_leak_threshold_ms
+            .map(|threshold_ms| Arc::new(LeakDetector::new(Duration::from_millis(threshold_ms))));
+        let pool_metrics = if config.metrics { Some(PoolMetrics::default()) } else { None };
+        ServiceState {
+            config,
+            pool,
+            secondary,
+            write_buffer,
+            scheduler,
+            circuit_breaker,
+            leak_detector,
+            pool_metrics,
+            per_ip_limiter,
+            export_limiter,
+            rate_limiter,
+            request_queue,
+            started_at: Instant::now(),
+            ready: AtomicBool::new(false),
+            last_write_check: Mutex::new(None),
+            last_db_maintenance: Mutex::new(None),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Records a database maintenance run, or returns the seconds until the next
+    /// one is allowed.
+    pub fn claim_db_maintenance(&self) -> Result<(), u64> {
+        let mut last_run = self.last_db_maintenance.lock().unwrap();
+        if let Some(last_run) = *last_run {
+            let elapsed = last_run.elapsed();
+            if elapsed < DB_MAINTENANCE_INTERVAL {
+                return Err((DB_MAINTENANCE_INTERVAL - elapsed).as_secs() + 1);
+            }
+        }
+        *last_run = Some(Instant::now());
+        Ok(())
+    }
+}
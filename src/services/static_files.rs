@@ -0,0 +1,34 @@
+use futures::future::{ok as futureOk, FutureResult};
+use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentType};
+use hyper::server::Response;
+use hyper::StatusCode;
+
+const FAVICON: &[u8] = include_bytes!("../../static/favicon.ico");
+
+/// Served as `/robots.txt` unless `ROBOTS_TXT_FILE` is set: keep every crawler out.
+pub const DEFAULT_ROBOTS_TXT: &str = "User-agent: *\nDisallow: /\n";
+
+/// How long browsers and crawlers may cache both files.
+const MAX_AGE_SECS: u32 = 24 * 60 * 60;
+
+pub fn favicon_response() -> FutureResult<Response, hyper::Error> {
+    futureOk(
+        Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentType("image/x-icon".parse().unwrap()))
+            .with_header(ContentLength(FAVICON.len() as u64))
+            .with_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(MAX_AGE_SECS)]))
+            .with_body(FAVICON),
+    )
+}
+
+pub fn robots_txt_response(robots_txt: &str) -> FutureResult<Response, hyper::Error> {
+    futureOk(
+        Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentType::plaintext())
+            .with_header(ContentLength(robots_txt.len() as u64))
+            .with_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(MAX_AGE_SECS)]))
+            .with_body(robots_txt.to_string()),
+    )
+}
@@ -0,0 +1,96 @@
+use futures::{Async, Future, Sink, Stream};
+use futures::stream;
+use futures::sync::mpsc::SendError;
+use hyper::{Body, Chunk};
+use tokio_core::reactor::Handle;
+
+type BodyItem = Result<Chunk, hyper::Error>;
+
+enum StreamState {
+    Producing,
+    /// The producer failed, the body is aborted with the next item.
+    Aborting,
+    Done,
+}
+
+/// A response body filled by `next_chunk`, one call per chunk, until it returns `Ok(None)`.
+///
+/// Each chunk is only produced once the previous one was taken, so a slow client slows
+/// the producer down instead of piling up chunks in memory. Production stops when the
+/// client goes away. On an error, which is logged, `failure_chunk` is sent if given and
+/// the body is then aborted, so the chunked response never ends properly and clients
+/// can tell it is incomplete.
+pub fn stream_body<F>(handle: &Handle, mut next_chunk: F, mut failure_chunk: Option<Vec<u8>>) -> Body
+    where F: FnMut() -> Result<Option<Vec<u8>>, String> + 'static {
+    let (sender, body) = Body::pair();
+    let mut state = StreamState::Producing;
+    let chunks = stream::poll_fn(move || -> Result<Async<Option<BodyItem>>, SendError<BodyItem>> {
+        let item = match state {
+            StreamState::Producing => match next_chunk() {
+                Ok(Some(chunk)) => Some(Ok(Chunk::from(chunk))),
+                Ok(None) => {
+                    state = StreamState::Done;
+                    None
+                }
+                Err(error) => {
+                    error!("Error streaming response: {}", error);
+                    state = StreamState::Aborting;
+                    match failure_chunk.take() {
+                        Some(chunk) => Some(Ok(Chunk::from(chunk))),
+                        None => {
+                            state = StreamState::Done;
+                            Some(Err(hyper::Error::Incomplete))
+                        }
+                    }
+                }
+            },
+            StreamState::Aborting => {
+                state = StreamState::Done;
+                Some(Err(hyper::Error::Incomplete))
+            }
+            StreamState::Done => None,
+        };
+        Ok(Async::Ready(item))
+    });
+    handle.spawn(
+        sender
+            .send_all(chunks)
+            .map(|_| ())
+            .map_err(|_| debug!("Client went away, stopped streaming")),
+    );
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    #[test]
+    fn stops_producing_once_the_client_is_gone() {
+        let mut core = Core::new().unwrap();
+        let produced = Rc::new(Cell::new(0));
+        let counter = produced.clone();
+        let body = stream_body(&core.handle(), move || {
+            counter.set(counter.get() + 1);
+            Ok(Some(b"chunk".to_vec()))
+        }, None);
+        let (first, body) = core.run(body.into_future()).map_err(|(error, _)| error).unwrap();
+        assert!(first.is_some());
+        drop(body);
+        for _ in 0..3 {
+            core.turn(Some(Duration::from_millis(10)));
+        }
+        let after_disconnect = produced.get();
+        for _ in 0..10 {
+            core.turn(Some(Duration::from_millis(10)));
+        }
+        assert_eq!(produced.get(), after_disconnect);
+        assert!(after_disconnect < 5);
+    }
+}
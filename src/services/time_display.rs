@@ -0,0 +1,66 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::TimeZone;
+use chrono_tz::Tz;
+
+pub const DEFAULT_DISPLAY_TZ: &str = "UTC";
+pub const DEFAULT_DISPLAY_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+
+/// How the HTML shows message timestamps, from `DISPLAY_TZ` and `DISPLAY_TIME_FORMAT`.
+#[derive(Clone, Debug)]
+pub struct TimeDisplay {
+    tz: Tz,
+    format: String,
+}
+
+impl TimeDisplay {
+    /// Checks the format up front, chrono only finds bad specifiers while formatting.
+    pub fn new(tz_name: &str, format: &str) -> Result<Self, String> {
+        let tz = tz_name
+            .parse::<Tz>()
+            .map_err(|error| format!("Invalid DISPLAY_TZ '{}': {}", tz_name, error))?;
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(format!("Invalid DISPLAY_TIME_FORMAT '{}'", format));
+        }
+        Ok(TimeDisplay {
+            tz,
+            format: String::from(format),
+        })
+    }
+
+    /// The local time of `timestamp`, or the number itself when it is out of range.
+    pub fn format(&self, timestamp: i64) -> String {
+        match self.tz.timestamp_opt(timestamp, 0).single() {
+            Some(time) => time.format(&self.format).to_string(),
+            None => timestamp.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_in_the_configured_timezone() {
+        let utc = TimeDisplay::new(DEFAULT_DISPLAY_TZ, DEFAULT_DISPLAY_TIME_FORMAT).unwrap();
+        assert_eq!(utc.format(0), "1970-01-01 00:00:00 UTC");
+        let tokyo = TimeDisplay::new("Asia/Tokyo", "%d.%m.%Y %H:%M").unwrap();
+        assert_eq!(tokyo.format(1_600_000_000), "13.09.2020 21:26");
+        let berlin = TimeDisplay::new("Europe/Berlin", "%H:%M %Z").unwrap();
+        // summer and winter time
+        assert_eq!(berlin.format(1_593_561_600), "02:00 CEST");
+        assert_eq!(berlin.format(1_577_836_800), "01:00 CET");
+    }
+
+    #[test]
+    fn refuses_unknown_timezones_and_formats() {
+        assert!(TimeDisplay::new("Mars/Olympus_Mons", DEFAULT_DISPLAY_TIME_FORMAT).is_err());
+        assert!(TimeDisplay::new(DEFAULT_DISPLAY_TZ, "%Y-%Q").is_err());
+    }
+
+    #[test]
+    fn shows_out_of_range_timestamps_as_numbers() {
+        let utc = TimeDisplay::new(DEFAULT_DISPLAY_TZ, DEFAULT_DISPLAY_TIME_FORMAT).unwrap();
+        assert_eq!(utc.format(i64::max_value()), i64::max_value().to_string());
+    }
+}
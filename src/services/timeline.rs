@@ -0,0 +1,114 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use url::form_urlencoded;
+
+use super::content_dedup::resolve_contents;
+use super::cursor::decode_cursor;
+use super::data_source::CountingConnection;
+use super::data_source::models::Message;
+use super::micro_service::{parse_arg, shown_visibilities, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+/// Everything a timeline page depends on, which is what an opaque `cursor` carries.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TimelineQuery {
+    pub limit: i64,
+    /// Extra rows of the next page to send along, as a loading hint.
+    pub prefetch: i64,
+    /// Only messages of this user.
+    pub username: Option<String>,
+    /// `(timestamp, id)` of the last message already shown.
+    pub before_cursor: Option<(i64, i32)>,
+}
+
+/// With `cursor` the whole query comes from it and the other parameters are ignored,
+/// so every page of a listing has the filters of the first.
+pub fn parse_timeline_query(query: Option<&str>, cursor_key: Option<&str>) -> Result<TimelineQuery, String> {
+    let args = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    if let Some(cursor) = args.get("cursor") {
+        let state = decode_cursor(cursor, cursor_key)?;
+        let timeline_query = serde_json::from_value::<TimelineQuery>(state).map_err(|_| String::from("Invalid 'cursor'"))?;
+        // also what a forged unsigned cursor could get around
+        if timeline_query.limit < 1 || timeline_query.limit > MAX_PAGE_SIZE
+            || timeline_query.prefetch < 0 || timeline_query.prefetch > timeline_query.limit
+            || timeline_query.before_cursor.is_none() {
+            return Err(String::from("Invalid 'cursor'"));
+        }
+        return Ok(timeline_query);
+    }
+    let limit = parse_arg::<u32>(&args, "limit")?
+        .map(|limit| cmp::max(1, cmp::min(i64::from(limit), MAX_PAGE_SIZE)))
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    let prefetch = parse_arg::<u32>(&args, "prefetch")?
+        .map(|prefetch| cmp::min(i64::from(prefetch), limit))
+        .unwrap_or(0);
+    let before_cursor = match args.get("before_cursor") {
+        Some(cursor) => Some(parse_timeline_cursor(cursor)?),
+        None => None,
+    };
+    let username = args.get("username").filter(|username| !username.is_empty()).cloned();
+    Ok(TimelineQuery {
+        limit,
+        prefetch,
+        username,
+        before_cursor,
+    })
+}
+
+fn parse_timeline_cursor(cursor: &str) -> Result<(i64, i32), String> {
+    let mut parts = cursor.splitn(2, ':');
+    let timestamp = parts.next().and_then(|timestamp| timestamp.parse::<i64>().ok());
+    let id = parts.next().and_then(|id| id.parse::<i32>().ok());
+    match (timestamp, id) {
+        (Some(timestamp), Some(id)) => Ok((timestamp, id)),
+        _ => Err(format!("Invalid 'before_cursor': {}", cursor)),
+    }
+}
+
+/// Loads a page of the newest-first timeline plus the prefetched rows, and one row
+/// more to tell whether anything older is left.
+pub fn query_timeline(timeline_query: &TimelineQuery, include_private: bool, db_connection: &CountingConnection) -> Option<Vec<Message>> {
+    use crate::schema::messages;
+    let mut query = messages::table
+        .filter(messages::visibility.eq_any(shown_visibilities(include_private)))
+        .order((messages::timestamp.desc(), messages::id.desc()))
+        .limit(timeline_query.limit + timeline_query.prefetch + 1)
+        .into_boxed();
+    if let Some(ref username) = timeline_query.username {
+        query = query.filter(messages::username.eq(username.clone()));
+    }
+    if let Some((timestamp, id)) = timeline_query.before_cursor {
+        query = query.filter(
+            messages::timestamp.lt(timestamp)
+                .or(messages::timestamp.eq(timestamp).and(messages::id.lt(id))),
+        );
+    }
+    match query.load::<Message>(db_connection).and_then(|messages| resolve_contents(messages, db_connection)) {
+        Ok(result) => Some(result),
+        Err(error) => {
+            error!("Error query Db: {}", error);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_cursor_and_caps_prefetch_at_the_limit() {
+        let timeline_query = parse_timeline_query(Some("limit=5&prefetch=9&before_cursor=1000:42"), None).unwrap();
+        assert_eq!((timeline_query.limit, timeline_query.prefetch), (5, 5));
+        assert_eq!(timeline_query.before_cursor, Some((1000, 42)));
+    }
+
+    #[test]
+    fn refuses_a_malformed_before_cursor() {
+        assert!(parse_timeline_query(Some("before_cursor=1000"), None).is_err());
+        assert!(parse_timeline_query(Some("before_cursor=soon:42"), None).is_err());
+    }
+}
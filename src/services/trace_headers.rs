@@ -0,0 +1,120 @@
+use std::fmt;
+use std::str;
+
+use hyper::header::Headers;
+
+/// Trace headers copied from the request onto the response.
+const TRACE_HEADERS: [&str; 3] = ["X-Request-Id", "X-B3-TraceId", "traceparent"];
+
+/// The trace headers of one request, with a `traceparent` generated when it came without.
+pub struct TraceHeaders {
+    values: Vec<(&'static str, String)>,
+}
+
+impl TraceHeaders {
+    pub fn from_request(headers: &Headers) -> Self {
+        let mut values = TRACE_HEADERS
+            .iter()
+            .filter_map(|&name| {
+                let value = headers.get_raw(name)?.one()?;
+                let value = str::from_utf8(value).ok()?.trim();
+                if value.is_empty() {
+                    None
+                } else {
+                    Some((name, value.to_string()))
+                }
+            })
+            .collect::<Vec<_>>();
+        if !values.iter().any(|&(name, _)| name == "traceparent") {
+            let b3_trace_id = values
+                .iter()
+                .find(|&&(name, _)| name == "X-B3-TraceId")
+                .map(|&(_, ref value)| value.as_str());
+            values.push(("traceparent", new_traceparent(b3_trace_id)));
+        }
+        TraceHeaders { values }
+    }
+
+    pub fn apply(&self, headers: &mut Headers) {
+        for &(name, ref value) in &self.values {
+            headers.set_raw(name, value.clone());
+        }
+    }
+}
+
+impl fmt::Display for TraceHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, &(name, ref value)) in self.values.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A W3C Trace Context `traceparent` with a fresh parent id, continuing a 128-bit
+/// B3 trace when there is one so both header styles name the same trace.
+fn new_traceparent(b3_trace_id: Option<&str>) -> String {
+    let trace_id = match b3_trace_id {
+        Some(trace_id) if is_trace_id(trace_id) => trace_id.to_ascii_lowercase(),
+        _ => format!("{:032x}", rand::random::<u128>() | 1),
+    };
+    format!("00-{}-{:016x}-01", trace_id, rand::random::<u64>() | 1)
+}
+
+/// 32 hex digits, not all zero.
+fn is_trace_id(value: &str) -> bool {
+    value.len() == 32 && value.bytes().all(|byte| byte.is_ascii_hexdigit()) && value.bytes().any(|byte| byte != b'0')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echoed(request: &Headers) -> Headers {
+        let mut response = Headers::new();
+        TraceHeaders::from_request(request).apply(&mut response);
+        response
+    }
+
+    fn raw<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+        headers.get_raw(name).and_then(|value| value.one()).and_then(|value| str::from_utf8(value).ok())
+    }
+
+    #[test]
+    fn echoes_incoming_trace_headers() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut request = Headers::new();
+        request.set_raw("X-Request-Id", "abc-123");
+        request.set_raw("traceparent", traceparent);
+        let response = echoed(&request);
+        assert_eq!(raw(&response, "X-Request-Id"), Some("abc-123"));
+        assert_eq!(raw(&response, "traceparent"), Some(traceparent));
+        assert_eq!(raw(&response, "X-B3-TraceId"), None);
+    }
+
+    #[test]
+    fn generates_a_traceparent_when_missing() {
+        let response = echoed(&Headers::new());
+        let traceparent = raw(&response, "traceparent").unwrap();
+        let parts = traceparent.split('-').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 4);
+        assert_eq!((parts[0], parts[3]), ("00", "01"));
+        assert!(is_trace_id(parts[1]));
+        assert_eq!(parts[2].len(), 16);
+        assert_ne!(raw(&echoed(&Headers::new()), "traceparent"), Some(traceparent));
+    }
+
+    #[test]
+    fn continues_a_b3_trace() {
+        let mut request = Headers::new();
+        request.set_raw("X-B3-TraceId", "4BF92F3577B34DA6A3CE929D0E0E4736");
+        let response = echoed(&request);
+        assert!(raw(&response, "traceparent").unwrap().starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        // 64-bit B3 ids don't fit a traceparent, a new trace is started
+        request.set_raw("X-B3-TraceId", "a3ce929d0e0e4736");
+        assert!(!raw(&echoed(&request), "traceparent").unwrap().contains("a3ce929d0e0e4736"));
+    }
+}
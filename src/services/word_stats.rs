@@ -0,0 +1,76 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// Words left out of `/stats/words` unless `STOPWORDS` replaces them.
+const DEFAULT_STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "has", "have", "i", "in",
+    "is", "it", "its", "me", "my", "not", "of", "on", "or", "so", "that", "the", "this", "to", "was",
+    "we", "were", "with", "you", "your",
+];
+
+pub fn default_stopwords() -> HashSet<String> {
+    DEFAULT_STOPWORDS.iter().map(|word| word.to_string()).collect()
+}
+
+/// Parses `STOPWORDS`, a comma separated list of words compared case insensitively.
+pub fn parse_stopwords(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(|word| word.trim().to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// The `limit` most frequent words of `texts` with their counts, most frequent first and
+/// ties in alphabetical order. Words are runs of letters, digits and apostrophes, lowercased.
+pub fn top_words<'a, I>(texts: I, stopwords: &HashSet<String>, limit: usize) -> Vec<(String, usize)>
+    where I: IntoIterator<Item=&'a str> {
+    let mut counts = HashMap::new();
+    for text in texts {
+        let words = text
+            .split(|c: char| !c.is_alphanumeric() && c != '\'')
+            .map(|word| word.trim_matches('\'').to_lowercase())
+            .filter(|word| !word.is_empty() && !stopwords.contains(word));
+        for word in words {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<(String, usize)>>();
+    counts.sort_by(|a, b| match b.1.cmp(&a.1) {
+        Ordering::Equal => a.0.cmp(&b.0),
+        ordering => ordering,
+    });
+    counts.truncate(limit);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGES: [&str; 4] = [
+        "The cat sat on the mat",
+        "A cat and a dog",
+        "Dogs aren't cats, the CAT said",
+        "'Mat' is a word",
+    ];
+
+    #[test]
+    fn counts_the_most_frequent_words() {
+        let top = top_words(MESSAGES.iter().cloned(), &default_stopwords(), 3);
+        assert_eq!(
+            top,
+            vec![(String::from("cat"), 3), (String::from("mat"), 2), (String::from("aren't"), 1)]
+        );
+    }
+
+    #[test]
+    fn leaves_out_the_stopwords() {
+        let top = top_words(MESSAGES.iter().cloned(), &default_stopwords(), 100);
+        assert!(top.iter().all(|&(ref word, _)| word != "the" && word != "a" && word != "and"));
+        let stopwords = parse_stopwords(" Cat, MAT ,,");
+        assert_eq!(stopwords.len(), 2);
+        let top = top_words(MESSAGES.iter().cloned(), &stopwords, 1);
+        assert_eq!(top, vec![(String::from("a"), 3)]);
+    }
+}
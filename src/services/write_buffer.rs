@@ -0,0 +1,220 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use super::data_source::models::NewMessage;
+
+/// One queued insert, a JSON line of the buffer file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BufferedInsert {
+    pub new_message: NewMessage,
+    /// The audit entry's client as `audit_context` stored it, with `AUDIT_LOG=1` only;
+    /// the replay records the insert under it.
+    pub audit_client: Option<Option<String>>,
+}
+
+/// Inserts that failed because the database was unreachable, one JSON line each, kept
+/// in a file until they can be written.
+///
+/// A replay first renames the file aside, so inserts queued meanwhile go to a fresh one;
+/// what could not be written is put back in front of those. A replay cut short by a
+/// crash is resumed at startup, which may write its first entries twice.
+pub struct WriteBuffer {
+    path: PathBuf,
+    replay_path: PathBuf,
+    max_bytes: u64,
+    /// Held while the files are appended to, renamed or rewritten.
+    files: Mutex<()>,
+}
+
+impl WriteBuffer {
+    pub fn new(path: &str, max_bytes: u64) -> Self {
+        WriteBuffer {
+            path: PathBuf::from(path),
+            replay_path: PathBuf::from(format!("{}.replay", path)),
+            max_bytes,
+            files: Mutex::new(()),
+        }
+    }
+
+    /// Appends `insert`, and syncs it to disk before the client is told it's queued.
+    /// Returns `false` when the file would grow past `max_bytes`.
+    pub fn enqueue(&self, insert: &BufferedInsert) -> io::Result<bool> {
+        let mut line = serde_json::to_string(insert)?;
+        line.push('\n');
+        let _files = self.files.lock().unwrap();
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error),
+        };
+        if size + line.len() as u64 > self.max_bytes {
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(true)
+    }
+
+    /// Hands the queued inserts, oldest first, to `write` until it fails, and returns
+    /// how many were written. Lines that can't be parsed are logged and dropped.
+    pub fn replay<F>(&self, mut write: F) -> io::Result<usize>
+        where F: FnMut(BufferedInsert) -> Result<(), String> {
+        {
+            let _files = self.files.lock().unwrap();
+            // a replay file left over from a crash is finished first
+            if !self.replay_path.exists() {
+                if !self.path.exists() {
+                    return Ok(0);
+                }
+                fs::rename(&self.path, &self.replay_path)?;
+            }
+        }
+        let lines = BufReader::new(File::open(&self.replay_path)?)
+            .lines()
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut written = 0;
+        let mut consumed = 0;
+        for line in &lines {
+            match serde_json::from_str::<BufferedInsert>(line) {
+                Ok(insert) => {
+                    if let Err(error) = write(insert) {
+                        warn!("Replaying buffered writes stopped, {} left: {}", lines.len() - consumed, error);
+                        break;
+                    }
+                    written += 1;
+                }
+                Err(error) => error!("Dropping unreadable buffered write: {}", error),
+            }
+            consumed += 1;
+        }
+        let _files = self.files.lock().unwrap();
+        if consumed < lines.len() {
+            self.put_back(&lines[consumed..])?;
+        }
+        fs::remove_file(&self.replay_path)?;
+        Ok(written)
+    }
+
+    /// Rewrites the buffer as `lines` followed by whatever was queued during the replay.
+    fn put_back(&self, lines: &[String]) -> io::Result<()> {
+        let queued_meanwhile = match fs::read(&self.path) {
+            Ok(queued) => queued,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        let temporary_path = PathBuf::from(format!("{}.tmp", self.path.display()));
+        let mut file = File::create(&temporary_path)?;
+        for line in lines {
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        file.write_all(&queued_meanwhile)?;
+        file.sync_data()?;
+        fs::rename(&temporary_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn buffer(name: &str, max_bytes: u64) -> WriteBuffer {
+        let path = env::temp_dir().join(format!("write_buffer_{}_{}", name, process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.replay", path));
+        WriteBuffer::new(path, max_bytes)
+    }
+
+    fn insert(message: &str) -> BufferedInsert {
+        BufferedInsert {
+            new_message: NewMessage {
+                username: String::from("peter"),
+                message: String::from(message),
+                ip: None,
+                user_agent: None,
+                content_hash: None,
+                visibility: String::from("public"),
+            },
+            audit_client: Some(Some(String::from("127.0.0.1"))),
+        }
+    }
+
+    fn replay_all(buffer: &WriteBuffer) -> Vec<String> {
+        let mut written = Vec::new();
+        buffer
+            .replay(|insert| {
+                written.push(insert.new_message.message);
+                Ok(())
+            })
+            .unwrap();
+        written
+    }
+
+    #[test]
+    fn replays_queued_inserts_in_order_once_the_database_is_back() {
+        let buffer = buffer("in_order", 1024 * 1024);
+        assert!(buffer.enqueue(&insert("first")).unwrap());
+        assert!(buffer.enqueue(&insert("second")).unwrap());
+
+        // the outage goes on: the first write fails and everything stays queued
+        let mut attempts = 0;
+        let written = buffer
+            .replay(|_| {
+                attempts += 1;
+                Err(String::from("database unavailable"))
+            })
+            .unwrap();
+        assert_eq!((written, attempts), (0, 1));
+
+        assert_eq!(replay_all(&buffer), vec!["first", "second"]);
+        assert!(replay_all(&buffer).is_empty());
+    }
+
+    #[test]
+    fn keeps_what_failed_ahead_of_inserts_queued_during_the_replay() {
+        let buffer = buffer("put_back", 1024 * 1024);
+        buffer.enqueue(&insert("first")).unwrap();
+        buffer.enqueue(&insert("second")).unwrap();
+        let written = buffer
+            .replay(|insert| {
+                if insert.new_message.message == "second" {
+                    return Err(String::from("database unavailable"));
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(written, 1);
+        buffer.enqueue(&insert("third")).unwrap();
+        assert_eq!(replay_all(&buffer), vec!["second", "third"]);
+    }
+
+    #[test]
+    fn keeps_the_audit_client() {
+        let buffer = buffer("audit", 1024 * 1024);
+        buffer.enqueue(&insert("audited")).unwrap();
+        let mut clients = Vec::new();
+        buffer
+            .replay(|insert| {
+                clients.push(insert.audit_client);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(clients, vec![Some(Some(String::from("127.0.0.1")))]);
+    }
+
+    #[test]
+    fn refuses_inserts_beyond_max_bytes() {
+        let line_len = serde_json::to_string(&insert("first")).unwrap().len() as u64 + 1;
+        let buffer = buffer("bounded", line_len);
+        assert!(buffer.enqueue(&insert("first")).unwrap());
+        assert!(!buffer.enqueue(&insert("other")).unwrap());
+        assert_eq!(replay_all(&buffer), vec!["first"]);
+    }
+}